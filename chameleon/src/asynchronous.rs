@@ -0,0 +1,157 @@
+use crate::{
+    to_countries, to_country, to_currencies, to_currency_codes, to_exchange_rates,
+    to_sats_per_unit, Country, Currency, ExchangeRate,
+};
+use futures_util::stream::{self, StreamExt};
+use graphql::asynchronous::{BackendClient, Subscription};
+use graphql::errors::*;
+use graphql::schema::*;
+use graphql::{parse_from_rfc3339, ToRfc3339};
+use log::warn;
+use std::time::{Duration, SystemTime};
+
+/// Maximum number of exchange rates the backend streams at once.
+const STREAM_BATCH_SIZE: i64 = 10;
+/// Backoff before resubscribing to exchange rates, doubled for every failed attempt.
+const RECONNECT_INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const RECONNECT_MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+pub struct ExchangeRateProvider {
+    client: BackendClient,
+}
+
+impl ExchangeRateProvider {
+    pub fn with_client(client: BackendClient) -> Self {
+        Self { client }
+    }
+
+    pub async fn list_currency_codes(&self) -> Result<Vec<String>> {
+        let variables = list_currency_codes::Variables {};
+        let data = self.client.post::<ListCurrencyCodes>(variables).await?;
+        Ok(to_currency_codes(data))
+    }
+
+    pub async fn list_countries(&self) -> Result<Vec<Country>> {
+        let variables = list_countries::Variables {};
+        let data = self.client.post::<ListCountries>(variables).await?;
+        Ok(to_countries(data))
+    }
+
+    /// Returns `None` if the backend does not know the country.
+    pub async fn query_country(&self, country_code: String) -> Result<Option<Country>> {
+        let variables = get_country::Variables { country_code };
+        let data = self.client.post::<GetCountry>(variables).await?;
+        Ok(to_country(data))
+    }
+
+    /// Lists the currencies of all countries, sorted by currency code.
+    pub async fn list_currencies(&self) -> Result<Vec<Currency>> {
+        Ok(to_currencies(self.list_countries().await?))
+    }
+
+    pub async fn query_exchange_rate(&self, code: String) -> Result<u32> {
+        let variables = get_exchange_rate::Variables { code };
+        let data = self.client.post::<GetExchangeRate>(variables).await?;
+        to_sats_per_unit(data)
+    }
+
+    pub async fn query_all_exchange_rates(&self) -> Result<Vec<ExchangeRate>> {
+        let variables = get_all_exchange_rates::Variables {};
+        let data = self.client.post::<GetAllExchangeRates>(variables).await?;
+        to_exchange_rates(data)
+    }
+
+    /// Streams exchange rates as they get updated, starting with all current ones.
+    ///
+    /// Transient errors like a dropped connection or a rejected access token are passed on,
+    /// then the provider resubscribes with an exponential backoff and streams all current
+    /// exchange rates again. The stream ends on any other error.
+    pub async fn subscribe_to_exchange_rates(&self) -> Result<Subscription<ExchangeRate>> {
+        let subscription = subscribe_to_currency_stream(&self.client).await?;
+        let state = SubscriptionState::Subscribed(subscription, RECONNECT_INITIAL_BACKOFF);
+        let updates = stream::unfold((self.client.clone(), state), |(client, state)| async move {
+            let (mut subscription, backoff) = match state {
+                SubscriptionState::Subscribed(subscription, backoff) => (subscription, backoff),
+                SubscriptionState::Resubscribing(backoff) => {
+                    tokio::time::sleep(backoff).await;
+                    let backoff = (backoff * 2).min(RECONNECT_MAX_BACKOFF);
+                    match subscribe_to_currency_stream(&client).await {
+                        Ok(subscription) => (subscription, backoff),
+                        Err(e) => {
+                            let state = SubscriptionState::after_error(&e, backoff);
+                            return Some((Err(e), (client, state)));
+                        }
+                    }
+                }
+                SubscriptionState::Ended => return None,
+            };
+            let (update, state) = match subscription.next().await? {
+                Ok(data) => (
+                    Ok(data),
+                    SubscriptionState::Subscribed(subscription, RECONNECT_INITIAL_BACKOFF),
+                ),
+                Err(e) => {
+                    let state = SubscriptionState::after_error(&e, backoff);
+                    (Err(e), state)
+                }
+            };
+            Some((update, (client, state)))
+        });
+        let exchange_rates = updates.flat_map(|data| {
+            let exchange_rates = match data.and_then(to_streamed_exchange_rates) {
+                Ok(exchange_rates) => exchange_rates.into_iter().map(Ok).collect(),
+                Err(e) => vec![Err(e)],
+            };
+            stream::iter(exchange_rates)
+        });
+        Ok(exchange_rates.boxed())
+    }
+}
+
+enum SubscriptionState {
+    /// With the backoff to wait before resubscribing if the subscription fails.
+    Subscribed(Subscription<currency_stream::ResponseData>, Duration),
+    Resubscribing(Duration),
+    Ended,
+}
+
+impl SubscriptionState {
+    fn after_error(error: &Error, backoff: Duration) -> Self {
+        match error {
+            Error::RuntimeError {
+                code:
+                    GraphQlRuntimeErrorCode::NetworkError
+                    | GraphQlRuntimeErrorCode::RemoteServiceUnavailable
+                    | GraphQlRuntimeErrorCode::AuthServiceError,
+                ..
+            } => {
+                warn!("Exchange rate subscription failed, resubscribing in {backoff:?}: {error}");
+                Self::Resubscribing(backoff)
+            }
+            _ => Self::Ended,
+        }
+    }
+}
+
+async fn subscribe_to_currency_stream(
+    client: &BackendClient,
+) -> Result<Subscription<currency_stream::ResponseData>> {
+    let variables = currency_stream::Variables {
+        updated_after: SystemTime::UNIX_EPOCH.to_rfc3339(),
+        batch_size: STREAM_BATCH_SIZE,
+    };
+    client.subscribe::<CurrencyStream>(variables).await
+}
+
+fn to_streamed_exchange_rates(data: currency_stream::ResponseData) -> Result<Vec<ExchangeRate>> {
+    data.currency_stream
+        .into_iter()
+        .map(|c| {
+            Ok(ExchangeRate {
+                currency_code: c.currency_code,
+                sats_per_unit: c.sats_per_unit as u32,
+                updated_at: parse_from_rfc3339(&c.conversion_rate_updated_at)?,
+            })
+        })
+        .collect()
+}
@@ -0,0 +1,216 @@
+use crate::{ExchangeRate, ExchangeRateProvider};
+use graphql::errors::*;
+use graphql::perro::{invalid_input, OptionToError};
+use log::{debug, warn};
+use std::sync::mpsc::{channel, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, SystemTime};
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct StalenessPolicy {
+    /// Exchange rates updated longer ago than that are stale.
+    pub max_age: Duration,
+    /// Refreshing stale rates on demand is not attempted more often than that.
+    pub min_refresh_interval: Duration,
+}
+
+impl Default for StalenessPolicy {
+    fn default() -> Self {
+        Self {
+            max_age: Duration::from_secs(30 * 60),
+            min_refresh_interval: Duration::from_secs(30),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CachedExchangeRate {
+    pub exchange_rate: ExchangeRate,
+    /// The rate was updated longer ago than the max age of the staleness policy,
+    /// because refreshing failed or the backend has no newer rate.
+    pub is_stale: bool,
+}
+
+struct Snapshot {
+    exchange_rates: Vec<ExchangeRate>,
+    refresh_attempted_at: SystemTime,
+}
+
+struct Cache {
+    provider: ExchangeRateProvider,
+    policy: StalenessPolicy,
+    snapshot: Mutex<Option<Snapshot>>,
+    /// Held while refreshing on demand, so concurrent readers of stale rates refresh only once.
+    on_demand_refresh: Mutex<()>,
+}
+
+/// Serves exchange rates from the last snapshot of all exchange rates.
+///
+/// Stale rates are refreshed on demand, or in the background when
+/// [`CachingExchangeRateProvider::start_background_refresh`] was called.
+/// If refreshing fails, the last known rates are served.
+pub struct CachingExchangeRateProvider {
+    cache: Arc<Cache>,
+}
+
+impl CachingExchangeRateProvider {
+    pub fn new(provider: ExchangeRateProvider, policy: StalenessPolicy) -> Self {
+        Self {
+            cache: Arc::new(Cache {
+                provider,
+                policy,
+                snapshot: Mutex::new(None),
+                on_demand_refresh: Mutex::new(()),
+            }),
+        }
+    }
+
+    /// Replaces the snapshot with the current exchange rates of the backend.
+    pub fn refresh(&self) -> Result<()> {
+        self.cache.refresh()
+    }
+
+    pub fn get_exchange_rate(&self, currency_code: &str) -> Result<CachedExchangeRate> {
+        self.cache.refresh_if_stale(Some(currency_code))?;
+        let snapshot = self.cache.snapshot.lock().unwrap();
+        let exchange_rate = snapshot
+            .as_ref()
+            .ok_or_permanent_failure("No exchange rates after refreshing")?
+            .exchange_rates
+            .iter()
+            .find(|r| r.currency_code == currency_code);
+        match exchange_rate {
+            Some(exchange_rate) => Ok(self.cache.to_cached(exchange_rate)),
+            None => invalid_input!("Unknown currency: {currency_code}"),
+        }
+    }
+
+    pub fn get_all_exchange_rates(&self) -> Result<Vec<CachedExchangeRate>> {
+        self.cache.refresh_if_stale(None)?;
+        let snapshot = self.cache.snapshot.lock().unwrap();
+        let exchange_rates = &snapshot
+            .as_ref()
+            .ok_or_permanent_failure("No exchange rates after refreshing")?
+            .exchange_rates;
+        Ok(exchange_rates
+            .iter()
+            .map(|r| self.cache.to_cached(r))
+            .collect())
+    }
+
+    /// Refreshes the snapshot every `interval` until the returned handle is dropped.
+    pub fn start_background_refresh(&self, interval: Duration) -> BackgroundRefresh {
+        let cache = Arc::clone(&self.cache);
+        let (stop, stopped) = channel::<()>();
+        let thread = thread::spawn(move || loop {
+            if let Err(e) = cache.refresh() {
+                warn!("Failed to refresh exchange rates in the background: {e}");
+            }
+            match stopped.recv_timeout(interval) {
+                Err(RecvTimeoutError::Timeout) => {}
+                Ok(()) | Err(RecvTimeoutError::Disconnected) => break,
+            }
+        });
+        BackgroundRefresh {
+            stop,
+            thread: Some(thread),
+        }
+    }
+}
+
+/// Stops the background refresh when dropped, waiting for a running refresh to finish.
+pub struct BackgroundRefresh {
+    stop: Sender<()>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Drop for BackgroundRefresh {
+    fn drop(&mut self) {
+        // Fails only if the thread is already gone.
+        let _ = self.stop.send(());
+        if let Some(thread) = self.thread.take() {
+            if thread.join().is_err() {
+                warn!("Background refresh of exchange rates panicked");
+            }
+        }
+    }
+}
+
+impl Cache {
+    fn refresh(&self) -> Result<()> {
+        let result = self.provider.query_all_exchange_rates();
+        let mut snapshot = self.snapshot.lock().unwrap();
+        match result {
+            Ok(exchange_rates) => {
+                debug!("Refreshed {} exchange rates", exchange_rates.len());
+                *snapshot = Some(Snapshot {
+                    exchange_rates,
+                    refresh_attempted_at: SystemTime::now(),
+                });
+                Ok(())
+            }
+            Err(e) => {
+                if let Some(snapshot) = snapshot.as_mut() {
+                    snapshot.refresh_attempted_at = SystemTime::now();
+                }
+                Err(e)
+            }
+        }
+    }
+
+    /// Refreshes the snapshot if there is none or if the rate of the currency
+    /// (any rate if `None`) is stale.
+    ///
+    /// Fails only if there is no snapshot to fall back to.
+    fn refresh_if_stale(&self, currency_code: Option<&str>) -> Result<()> {
+        if self.should_refresh(currency_code) == Some(false) {
+            return Ok(());
+        }
+
+        let _refreshing = self.on_demand_refresh.lock().unwrap();
+        // Anyone else refreshed the snapshot in the meantime?
+        match self.should_refresh(currency_code) {
+            // Nothing to fall back to.
+            None => self.refresh(),
+            Some(true) => {
+                if let Err(e) = self.refresh() {
+                    warn!("Failed to refresh stale exchange rates, serving the stale ones: {e}");
+                }
+                Ok(())
+            }
+            Some(false) => Ok(()),
+        }
+    }
+
+    /// Returns `None` if there is no snapshot yet.
+    fn should_refresh(&self, currency_code: Option<&str>) -> Option<bool> {
+        let snapshot = self.snapshot.lock().unwrap();
+        let snapshot = snapshot.as_ref()?;
+        let is_stale = snapshot
+            .exchange_rates
+            .iter()
+            .filter(|r| currency_code.is_none() || currency_code == Some(r.currency_code.as_str()))
+            .any(|r| self.is_stale(r));
+        let may_refresh =
+            elapsed(snapshot.refresh_attempted_at) >= self.policy.min_refresh_interval;
+        Some(is_stale && may_refresh)
+    }
+
+    fn is_stale(&self, exchange_rate: &ExchangeRate) -> bool {
+        elapsed(exchange_rate.updated_at) > self.policy.max_age
+    }
+
+    fn to_cached(&self, exchange_rate: &ExchangeRate) -> CachedExchangeRate {
+        CachedExchangeRate {
+            exchange_rate: exchange_rate.clone(),
+            is_stale: self.is_stale(exchange_rate),
+        }
+    }
+}
+
+fn elapsed(since: SystemTime) -> Duration {
+    SystemTime::now()
+        .duration_since(since)
+        .unwrap_or(Duration::ZERO)
+}
@@ -0,0 +1,299 @@
+use crate::ExchangeRate;
+use graphql::errors::*;
+use graphql::perro::{ensure, invalid_input};
+use std::time::SystemTime;
+
+const MSATS_PER_SAT: u128 = 1_000;
+const SATS_PER_BTC: f64 = 100_000_000_f64;
+
+/// ISO 4217 currencies with no minor unit.
+const ZERO_DECIMAL_CURRENCIES: [&str; 17] = [
+    "BIF", "CLP", "DJF", "GNF", "ISK", "JPY", "KMF", "KRW", "PYG", "RWF", "UGX", "UYI", "VND",
+    "VUV", "XAF", "XOF", "XPF",
+];
+
+/// ISO 4217 currencies with 1/1000 as minor unit.
+const THREE_DECIMAL_CURRENCIES: [&str; 7] = ["BHD", "IQD", "JOD", "KWD", "LYD", "OMR", "TND"];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Rounding {
+    /// Towards zero.
+    Down,
+    /// Away from zero.
+    Up,
+    /// To the nearest value, ties away from zero.
+    HalfUp,
+    /// To the nearest value, ties to the even value.
+    HalfEven,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FiatValue {
+    pub minor_units: u64,
+    pub currency_code: String,
+    /// When the exchange rate used for the conversion was updated.
+    pub rate_updated_at: SystemTime,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BitcoinValue {
+    pub msats: u64,
+    /// When the exchange rate used for the conversion was updated.
+    pub rate_updated_at: SystemTime,
+}
+
+/// Number of decimals of the minor unit of the currency, e.g. 2 for EUR (cents).
+///
+/// Currencies not known to have 0 or 3 decimals are assumed to have 2.
+pub fn minor_unit_exponent(currency_code: &str) -> u32 {
+    let currency_code = currency_code.to_uppercase();
+    if ZERO_DECIMAL_CURRENCIES.contains(&currency_code.as_str()) {
+        0
+    } else if THREE_DECIMAL_CURRENCIES.contains(&currency_code.as_str()) {
+        3
+    } else {
+        2
+    }
+}
+
+pub fn msats_to_fiat(
+    exchange_rate: &ExchangeRate,
+    msats: u64,
+    rounding: Rounding,
+) -> Result<FiatValue> {
+    ensure_valid_rate(exchange_rate)?;
+    let numerator = msats as u128 * minor_units_per_unit(&exchange_rate.currency_code);
+    let denominator = exchange_rate.sats_per_unit as u128 * MSATS_PER_SAT;
+    Ok(FiatValue {
+        minor_units: to_u64(divide(numerator, denominator, rounding))?,
+        currency_code: exchange_rate.currency_code.clone(),
+        rate_updated_at: exchange_rate.updated_at,
+    })
+}
+
+pub fn sats_to_fiat(
+    exchange_rate: &ExchangeRate,
+    sats: u64,
+    rounding: Rounding,
+) -> Result<FiatValue> {
+    let msats = sats
+        .checked_mul(MSATS_PER_SAT as u64)
+        .ok_or_else(|| invalid_input(format!("Amount of {sats} sats is too large")))?;
+    msats_to_fiat(exchange_rate, msats, rounding)
+}
+
+pub fn fiat_to_msats(
+    exchange_rate: &ExchangeRate,
+    minor_units: u64,
+    rounding: Rounding,
+) -> Result<BitcoinValue> {
+    ensure_valid_rate(exchange_rate)?;
+    let numerator = minor_units as u128 * exchange_rate.sats_per_unit as u128 * MSATS_PER_SAT;
+    let denominator = minor_units_per_unit(&exchange_rate.currency_code);
+    Ok(BitcoinValue {
+        msats: to_u64(divide(numerator, denominator, rounding))?,
+        rate_updated_at: exchange_rate.updated_at,
+    })
+}
+
+/// Same as [`fiat_to_msats`], but rounded to whole sats.
+pub fn fiat_to_sats(
+    exchange_rate: &ExchangeRate,
+    minor_units: u64,
+    rounding: Rounding,
+) -> Result<BitcoinValue> {
+    ensure_valid_rate(exchange_rate)?;
+    let numerator = minor_units as u128 * exchange_rate.sats_per_unit as u128;
+    let denominator = minor_units_per_unit(&exchange_rate.currency_code);
+    let sats = divide(numerator, denominator, rounding);
+    Ok(BitcoinValue {
+        msats: to_u64(sats * MSATS_PER_SAT)?,
+        rate_updated_at: exchange_rate.updated_at,
+    })
+}
+
+/// Converts a price of one bitcoin in fiat (major units) as reported by the backend
+/// into sats per fiat unit, rounded to the nearest sat.
+pub fn sats_per_unit_from_price(price: f64) -> Result<u32> {
+    ensure!(
+        price.is_finite() && price > 0_f64,
+        invalid_input(format!("Invalid bitcoin price: {price}"))
+    );
+    let sats_per_unit = (SATS_PER_BTC / price).round();
+    ensure!(
+        sats_per_unit <= u32::MAX as f64,
+        invalid_input(format!("Bitcoin price too low: {price}"))
+    );
+    Ok(sats_per_unit as u32)
+}
+
+/// Converts a fiat amount in major units as reported by the backend
+/// into minor units of the currency, rounded to the nearest minor unit.
+pub fn major_to_minor_units(major_units: f64, currency_code: &str) -> Result<u64> {
+    ensure!(
+        major_units.is_finite() && major_units >= 0_f64,
+        invalid_input(format!("Invalid fiat amount: {major_units}"))
+    );
+    let minor_units = (major_units * minor_units_per_unit(currency_code) as f64).round();
+    ensure!(
+        minor_units <= u64::MAX as f64,
+        invalid_input(format!("Fiat amount too large: {major_units}"))
+    );
+    Ok(minor_units as u64)
+}
+
+fn minor_units_per_unit(currency_code: &str) -> u128 {
+    10_u128.pow(minor_unit_exponent(currency_code))
+}
+
+fn ensure_valid_rate(exchange_rate: &ExchangeRate) -> Result<()> {
+    ensure!(
+        exchange_rate.sats_per_unit > 0,
+        invalid_input(format!(
+            "Invalid exchange rate of 0 sats per {}",
+            exchange_rate.currency_code
+        ))
+    );
+    Ok(())
+}
+
+fn divide(numerator: u128, denominator: u128, rounding: Rounding) -> u128 {
+    let quotient = numerator / denominator;
+    let remainder = numerator % denominator;
+    let round_up = match rounding {
+        Rounding::Down => false,
+        Rounding::Up => remainder > 0,
+        Rounding::HalfUp => remainder * 2 >= denominator,
+        Rounding::HalfEven => {
+            remainder * 2 > denominator || (remainder * 2 == denominator && quotient % 2 == 1)
+        }
+    };
+    if round_up {
+        quotient + 1
+    } else {
+        quotient
+    }
+}
+
+fn to_u64(value: u128) -> Result<u64> {
+    match u64::try_from(value) {
+        Ok(value) => Ok(value),
+        Err(_) => invalid_input!("Converted amount is too large: {value}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_minor_unit_exponent() {
+        assert_eq!(minor_unit_exponent("EUR"), 2);
+        assert_eq!(minor_unit_exponent("chf"), 2);
+        assert_eq!(minor_unit_exponent("JPY"), 0);
+        assert_eq!(minor_unit_exponent("KWD"), 3);
+    }
+
+    #[test]
+    fn test_divide() {
+        assert_eq!(divide(14, 10, Rounding::Down), 1);
+        assert_eq!(divide(14, 10, Rounding::Up), 2);
+        assert_eq!(divide(10, 10, Rounding::Up), 1);
+        assert_eq!(divide(14, 10, Rounding::HalfUp), 1);
+        assert_eq!(divide(15, 10, Rounding::HalfUp), 2);
+        assert_eq!(divide(25, 10, Rounding::HalfUp), 3);
+        assert_eq!(divide(15, 10, Rounding::HalfEven), 2);
+        assert_eq!(divide(25, 10, Rounding::HalfEven), 2);
+        assert_eq!(divide(26, 10, Rounding::HalfEven), 3);
+    }
+
+    #[test]
+    fn test_conversions() {
+        let eur = rate("EUR", 1_538);
+        // 1 sat = 0.065 cents.
+        assert_eq!(
+            sats_to_fiat(&eur, 1, Rounding::Down).unwrap().minor_units,
+            0
+        );
+        assert_eq!(sats_to_fiat(&eur, 1, Rounding::Up).unwrap().minor_units, 1);
+        assert_eq!(
+            sats_to_fiat(&eur, 1_538, Rounding::Down)
+                .unwrap()
+                .minor_units,
+            100
+        );
+        assert_eq!(
+            msats_to_fiat(&eur, 7_690, Rounding::HalfUp)
+                .unwrap()
+                .minor_units,
+            1
+        );
+        assert_eq!(
+            msats_to_fiat(&eur, 7_689, Rounding::HalfUp)
+                .unwrap()
+                .minor_units,
+            0
+        );
+        assert_eq!(
+            fiat_to_msats(&eur, 1, Rounding::Down).unwrap().msats,
+            15_380
+        );
+        assert_eq!(fiat_to_sats(&eur, 1, Rounding::Down).unwrap().msats, 15_000);
+        assert_eq!(
+            fiat_to_sats(&eur, 1, Rounding::HalfEven).unwrap().msats,
+            15_000
+        );
+        assert_eq!(fiat_to_sats(&eur, 1, Rounding::Up).unwrap().msats, 16_000);
+
+        let jpy = rate("JPY", 10);
+        let value = sats_to_fiat(&jpy, 1_005, Rounding::HalfEven).unwrap();
+        assert_eq!(value.minor_units, 100);
+        assert_eq!(value.currency_code, "JPY");
+        assert_eq!(value.rate_updated_at, jpy.updated_at);
+        assert_eq!(
+            fiat_to_msats(&jpy, 3, Rounding::Down).unwrap().msats,
+            30_000
+        );
+
+        let kwd = rate("KWD", 5_000);
+        assert_eq!(
+            sats_to_fiat(&kwd, 5_000, Rounding::Down)
+                .unwrap()
+                .minor_units,
+            1_000
+        );
+        assert_eq!(fiat_to_msats(&kwd, 1, Rounding::Down).unwrap().msats, 5_000);
+
+        let result = sats_to_fiat(&rate("EUR", 0), 1, Rounding::Down);
+        assert!(matches!(result, Err(Error::InvalidInput { .. })));
+        let result = sats_to_fiat(&eur, u64::MAX, Rounding::Down);
+        assert!(matches!(result, Err(Error::InvalidInput { .. })));
+        let result = fiat_to_msats(&eur, u64::MAX, Rounding::Down);
+        assert!(matches!(result, Err(Error::InvalidInput { .. })));
+    }
+
+    #[test]
+    fn test_backend_values() {
+        assert_eq!(sats_per_unit_from_price(18_507.0).unwrap(), 5_403);
+        assert!(sats_per_unit_from_price(0.0).is_err());
+        assert!(sats_per_unit_from_price(f64::NAN).is_err());
+        assert!(sats_per_unit_from_price(1e-9).is_err());
+
+        assert_eq!(major_to_minor_units(8.0, "EUR").unwrap(), 800);
+        assert_eq!(
+            major_to_minor_units(0.11999999731779099, "EUR").unwrap(),
+            12
+        );
+        assert_eq!(major_to_minor_units(1_000.4, "JPY").unwrap(), 1_000);
+        assert_eq!(major_to_minor_units(1.2345, "KWD").unwrap(), 1_235);
+        assert!(major_to_minor_units(-1.0, "EUR").is_err());
+    }
+
+    fn rate(currency_code: &str, sats_per_unit: u32) -> ExchangeRate {
+        ExchangeRate {
+            currency_code: currency_code.to_string(),
+            sats_per_unit,
+            updated_at: SystemTime::UNIX_EPOCH,
+        }
+    }
+}
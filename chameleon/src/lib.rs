@@ -1,68 +1,154 @@
+pub mod asynchronous;
+pub mod cache;
+pub mod conversion;
+
 use graphql::perro::OptionToError;
 use graphql::schema::*;
-use graphql::{build_client, post_blocking};
+use graphql::BackendClient;
 use graphql::{errors::*, parse_from_rfc3339};
 use honeybadger::Auth;
+use std::collections::BTreeMap;
 use std::sync::Arc;
-use std::time::SystemTime;
 
-pub struct ExchangeRate {
+pub use graphql::ExchangeRate;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Country {
+    /// ISO 3166-1 alpha-2 code, e.g. `CH`.
+    pub country_code: String,
+    pub name: String,
+    /// International calling code without the leading `+`, e.g. `41`.
+    pub calling_code: String,
+    /// ISO 4217 code of the currency of the country.
+    pub currency_code: String,
+    pub currency_name: String,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Currency {
     pub currency_code: String,
-    pub sats_per_unit: u32,
-    pub updated_at: SystemTime,
+    pub name: String,
 }
 
 pub struct ExchangeRateProvider {
-    backend_url: String,
-    auth: Arc<Auth>,
+    client: BackendClient,
 }
 
 impl ExchangeRateProvider {
+    #[deprecated(
+        note = "Use ExchangeRateProvider::with_client, which shares the client with the other crates"
+    )]
     pub fn new(backend_url: String, auth: Arc<Auth>) -> Self {
-        Self { backend_url, auth }
+        Self::with_client(BackendClient::with_default_config(backend_url).with_auth(auth))
+    }
+
+    pub fn with_client(client: BackendClient) -> Self {
+        Self { client }
     }
 
     pub fn list_currency_codes(&self) -> Result<Vec<String>> {
-        let token = self.auth.query_token()?;
-        let client = build_client(Some(&token))?;
         let variables = list_currency_codes::Variables {};
-        let data = post_blocking::<ListCurrencyCodes>(&client, &self.backend_url, variables)?;
-        let list = data.currency.into_iter().map(|c| c.currency_code).collect();
+        let data = self.client.post::<ListCurrencyCodes>(variables)?;
+        Ok(to_currency_codes(data))
+    }
 
-        Ok(list)
+    pub fn list_countries(&self) -> Result<Vec<Country>> {
+        let variables = list_countries::Variables {};
+        let data = self.client.post::<ListCountries>(variables)?;
+        Ok(to_countries(data))
+    }
+
+    /// Returns `None` if the backend does not know the country.
+    pub fn query_country(&self, country_code: String) -> Result<Option<Country>> {
+        let variables = get_country::Variables { country_code };
+        let data = self.client.post::<GetCountry>(variables)?;
+        Ok(to_country(data))
+    }
+
+    /// Lists the currencies of all countries, sorted by currency code.
+    pub fn list_currencies(&self) -> Result<Vec<Currency>> {
+        Ok(to_currencies(self.list_countries()?))
     }
 
     pub fn query_exchange_rate(&self, code: String) -> Result<u32> {
-        let token = self.auth.query_token()?;
-        let client = build_client(Some(&token))?;
         let variables = get_exchange_rate::Variables { code };
-        let data = post_blocking::<GetExchangeRate>(&client, &self.backend_url, variables)?;
-        let rate = data
-            .currency
-            .first()
-            .ok_or_invalid_input("Unknown currency")?
-            .sats_per_unit;
-
-        Ok(rate as u32)
+        let data = self.client.post::<GetExchangeRate>(variables)?;
+        to_sats_per_unit(data)
     }
 
     pub fn query_all_exchange_rates(&self) -> Result<Vec<ExchangeRate>> {
-        let token = self.auth.query_token()?;
-        let client = build_client(Some(&token))?;
         let variables = get_all_exchange_rates::Variables {};
-        let data = post_blocking::<GetAllExchangeRates>(&client, &self.backend_url, variables)?;
-        let list: Vec<Result<ExchangeRate>> = data
-            .currency
-            .into_iter()
-            .map(|c| {
-                Ok(ExchangeRate {
-                    currency_code: c.currency_code,
-                    sats_per_unit: c.sats_per_unit as u32,
-                    updated_at: parse_from_rfc3339(&c.conversion_rate_updated_at)?,
-                })
-            })
-            .collect();
-
-        list.into_iter().collect()
+        let data = self.client.post::<GetAllExchangeRates>(variables)?;
+        to_exchange_rates(data)
     }
 }
+
+fn to_currency_codes(data: list_currency_codes::ResponseData) -> Vec<String> {
+    data.currency.into_iter().map(|c| c.currency_code).collect()
+}
+
+/// Every query generates its own country type.
+macro_rules! impl_from_country {
+    ($module:ident :: $country:ident) => {
+        impl From<$module::$country> for Country {
+            fn from(country: $module::$country) -> Self {
+                Self {
+                    country_code: country.country_code,
+                    name: country.name,
+                    calling_code: country.calling_code,
+                    // bpchar values are blank-padded.
+                    currency_code: country.currency_code.trim_end().to_string(),
+                    currency_name: country.currency_name,
+                }
+            }
+        }
+    };
+}
+
+impl_from_country!(list_countries::ListCountriesCountry);
+impl_from_country!(get_country::GetCountryCountryByPk);
+
+fn to_countries(data: list_countries::ResponseData) -> Vec<Country> {
+    data.country.into_iter().map(Country::from).collect()
+}
+
+fn to_country(data: get_country::ResponseData) -> Option<Country> {
+    data.country_by_pk.map(Country::from)
+}
+
+fn to_currencies(countries: Vec<Country>) -> Vec<Currency> {
+    let currencies: BTreeMap<String, String> = countries
+        .into_iter()
+        .map(|c| (c.currency_code, c.currency_name))
+        .collect();
+    currencies
+        .into_iter()
+        .map(|(currency_code, name)| Currency {
+            currency_code,
+            name,
+        })
+        .collect()
+}
+
+fn to_sats_per_unit(data: get_exchange_rate::ResponseData) -> Result<u32> {
+    let rate = data
+        .currency
+        .first()
+        .ok_or_invalid_input("Unknown currency")?
+        .sats_per_unit;
+
+    Ok(rate as u32)
+}
+
+fn to_exchange_rates(data: get_all_exchange_rates::ResponseData) -> Result<Vec<ExchangeRate>> {
+    data.currency
+        .into_iter()
+        .map(|c| {
+            Ok(ExchangeRate {
+                currency_code: c.currency_code,
+                sats_per_unit: c.sats_per_unit as u32,
+                updated_at: parse_from_rfc3339(&c.conversion_rate_updated_at)?,
+            })
+        })
+        .collect()
+}
@@ -1,12 +1,18 @@
 use bitcoin::Network;
-use chameleon::ExchangeRateProvider;
+use chameleon::cache::{CachingExchangeRateProvider, StalenessPolicy};
+use chameleon::{asynchronous, ExchangeRate, ExchangeRateProvider};
+use futures_util::StreamExt;
+use graphql::asynchronous::Subscription;
 use graphql::perro::Error;
-use honeybadger::secrets::{derive_keys, generate_keypair, generate_mnemonic};
+use graphql::{BackendClient, BackendClientConfig, GraphQlRuntimeErrorCode, RetryPolicy};
+use honeybadger::secrets::{derive_keys, generate_keypair, generate_mnemonic, DerivationScheme};
 use honeybadger::{Auth, AuthLevel};
+use mockingbird::{Failure, MockBackend};
 use simplelog::TestLogger;
-use std::env;
-use std::sync::{Arc, Once};
-use std::time::{Duration, SystemTime};
+use std::sync::{Arc, Barrier, Once};
+use std::thread::{self, sleep};
+use std::time::{Duration, Instant, SystemTime};
+use tokio::time::timeout;
 
 static INIT_LOGGER_ONCE: Once = Once::new();
 
@@ -50,24 +56,420 @@ fn test_get_all_exchange_rates() {
             && (SystemTime::now() - Duration::from_secs(60 * 30)) < item.updated_at));
 }
 
+#[test]
+fn test_retry_on_transient_errors() {
+    let backend = MockBackend::start();
+    let provider = build_provider_with_config(
+        backend.url(),
+        BackendClientConfig {
+            retry_policy: RetryPolicy {
+                initial_backoff: Duration::from_millis(10),
+                ..RetryPolicy::default()
+            },
+            ..BackendClientConfig::default()
+        },
+    );
+    backend.fail_next("GetExchangeRate", Failure::BadGateway);
+    backend.fail_next("GetExchangeRate", Failure::BadGateway);
+    assert_eq!(
+        provider.query_exchange_rate("EUR".to_string()).unwrap(),
+        1_538
+    );
+    assert_eq!(backend.request_count("GetExchangeRate"), 3);
+
+    backend.fail_next("GetExchangeRate", Failure::BadGateway);
+    backend.fail_next("GetExchangeRate", Failure::BadGateway);
+    backend.fail_next("GetExchangeRate", Failure::BadGateway);
+    let result = provider.query_exchange_rate("EUR".to_string());
+    assert!(matches!(
+        result,
+        Err(Error::RuntimeError {
+            code: GraphQlRuntimeErrorCode::RemoteServiceUnavailable,
+            ..
+        })
+    ));
+    assert_eq!(backend.request_count("GetExchangeRate"), 6);
+
+    let provider = build_provider_with_config(
+        backend.url(),
+        BackendClientConfig {
+            retry_policy: RetryPolicy::no_retry(),
+            ..BackendClientConfig::default()
+        },
+    );
+    backend.fail_next("GetExchangeRate", Failure::BadGateway);
+    assert!(provider.query_exchange_rate("EUR".to_string()).is_err());
+    assert_eq!(backend.request_count("GetExchangeRate"), 7);
+}
+
+#[test]
+fn test_countries_and_currencies() {
+    let provider = build_provider();
+    let countries = provider.list_countries().unwrap();
+    let switzerland = countries.iter().find(|c| c.country_code == "CH").unwrap();
+    assert_eq!(switzerland.currency_code, "CHF");
+    assert!(switzerland.calling_code.ends_with("41"));
+
+    let country = provider.query_country("CH".to_string()).unwrap();
+    assert_eq!(country.as_ref(), Some(switzerland));
+    let country = provider.query_country("XX".to_string()).unwrap();
+    assert!(country.is_none());
+
+    let currencies = provider.list_currencies().unwrap();
+    assert!(currencies.iter().any(|c| c.currency_code == "EUR"));
+    assert!(currencies
+        .windows(2)
+        .all(|w| w[0].currency_code < w[1].currency_code));
+}
+
+#[tokio::test]
+async fn test_async_provider() {
+    let provider = build_async_provider();
+    let currency_list = provider.list_currency_codes().await.unwrap();
+    assert!(currency_list.contains(&"EUR".to_string()));
+
+    let rate = provider
+        .query_exchange_rate("EUR".to_string())
+        .await
+        .unwrap();
+    assert!(200 < rate);
+    assert!(rate < 3_000);
+    let result = provider.query_exchange_rate("XXX".to_string()).await;
+    assert!(matches!(result, Err(Error::InvalidInput { .. })));
+
+    let exchange_rate_list = provider.query_all_exchange_rates().await.unwrap();
+    assert!(exchange_rate_list
+        .iter()
+        .all(|item| currency_list.contains(&item.currency_code) && item.sats_per_unit > 0));
+
+    let country = provider.query_country("DE".to_string()).await.unwrap();
+    assert_eq!(country.unwrap().currency_code, "EUR");
+    let countries = provider.list_countries().await.unwrap();
+    assert!(countries.iter().any(|c| c.country_code == "DE"));
+}
+
+#[test]
+fn test_caching_provider() {
+    let backend = MockBackend::start();
+    let provider = CachingExchangeRateProvider::new(
+        build_provider_with_config(backend.url(), BackendClientConfig::default()),
+        StalenessPolicy {
+            max_age: Duration::from_secs(60 * 60),
+            min_refresh_interval: Duration::ZERO,
+        },
+    );
+
+    let rate = provider.get_exchange_rate("EUR").unwrap();
+    assert_eq!(rate.exchange_rate.sats_per_unit, 1_538);
+    assert!(!rate.is_stale);
+    assert_eq!(provider.get_all_exchange_rates().unwrap().len(), 4);
+    assert_eq!(
+        provider
+            .get_exchange_rate("CHF")
+            .unwrap()
+            .exchange_rate
+            .sats_per_unit,
+        1_493
+    );
+    assert_eq!(backend.request_count("GetAllExchangeRates"), 1);
+
+    let result = provider.get_exchange_rate("XXX");
+    assert!(matches!(result, Err(Error::InvalidInput { .. })));
+
+    // Fresh rates are served from the snapshot until refreshed.
+    backend.set_exchange_rate("EUR", 1_600);
+    assert_eq!(
+        provider
+            .get_exchange_rate("EUR")
+            .unwrap()
+            .exchange_rate
+            .sats_per_unit,
+        1_538
+    );
+    provider.refresh().unwrap();
+    assert_eq!(
+        provider
+            .get_exchange_rate("EUR")
+            .unwrap()
+            .exchange_rate
+            .sats_per_unit,
+        1_600
+    );
+    assert_eq!(backend.request_count("GetAllExchangeRates"), 2);
+
+    // Stale rates are refreshed on demand.
+    let two_hours_ago = SystemTime::now() - Duration::from_secs(2 * 60 * 60);
+    backend.set_exchange_rate_updated_at("GBP", 1_300, two_hours_ago);
+    provider.refresh().unwrap();
+    backend.set_exchange_rate("GBP", 1_350);
+    let rate = provider.get_exchange_rate("GBP").unwrap();
+    assert_eq!(rate.exchange_rate.sats_per_unit, 1_350);
+    assert!(!rate.is_stale);
+    assert_eq!(backend.request_count("GetAllExchangeRates"), 4);
+
+    // Stale rates are served if the backend is unreachable.
+    backend.set_exchange_rate_updated_at("GBP", 1_300, two_hours_ago);
+    provider.refresh().unwrap();
+    backend.fail_all(Failure::BadGateway);
+    assert!(provider.refresh().is_err());
+    let rate = provider.get_exchange_rate("GBP").unwrap();
+    assert_eq!(rate.exchange_rate.sats_per_unit, 1_300);
+    assert_eq!(
+        rate.exchange_rate
+            .updated_at
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_secs(),
+        two_hours_ago
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+    );
+    assert!(rate.is_stale);
+    assert!(!provider.get_exchange_rate("EUR").unwrap().is_stale);
+}
+
+#[test]
+fn test_caching_provider_refreshes_stale_rates_once() {
+    let backend = MockBackend::start();
+    let provider = CachingExchangeRateProvider::new(
+        build_provider_with_config(backend.url(), BackendClientConfig::default()),
+        StalenessPolicy {
+            max_age: Duration::from_secs(60 * 60),
+            min_refresh_interval: Duration::from_secs(1),
+        },
+    );
+    let two_hours_ago = SystemTime::now() - Duration::from_secs(2 * 60 * 60);
+    backend.set_exchange_rate_updated_at("GBP", 1_300, two_hours_ago);
+    assert!(provider.get_exchange_rate("GBP").unwrap().is_stale);
+    assert_eq!(backend.request_count("GetAllExchangeRates"), 1);
+
+    // The backend has no newer rate, concurrent readers refresh it only once.
+    sleep(Duration::from_millis(1_100));
+    let barrier = Barrier::new(8);
+    thread::scope(|s| {
+        for _ in 0..8 {
+            s.spawn(|| {
+                barrier.wait();
+                assert!(provider.get_exchange_rate("GBP").unwrap().is_stale);
+            });
+        }
+    });
+    assert_eq!(backend.request_count("GetAllExchangeRates"), 2);
+}
+
+#[test]
+fn test_caching_provider_background_refresh() {
+    let backend = MockBackend::start();
+    let provider = CachingExchangeRateProvider::new(
+        build_provider_with_config(backend.url(), BackendClientConfig::default()),
+        StalenessPolicy::default(),
+    );
+
+    let usd_rate = || {
+        provider
+            .get_exchange_rate("USD")
+            .unwrap()
+            .exchange_rate
+            .sats_per_unit
+    };
+
+    let background_refresh = provider.start_background_refresh(Duration::from_millis(10));
+    wait_until(|| backend.request_count("GetAllExchangeRates") > 0);
+    assert_eq!(usd_rate(), 1_652);
+
+    // Rates of the snapshot are not stale, only the background refresh picks up the new rate.
+    backend.set_exchange_rate("USD", 1_700);
+    wait_until(|| usd_rate() == 1_700);
+
+    // Dropping the handle waits for the background thread to stop.
+    drop(background_refresh);
+    let request_count = backend.request_count("GetAllExchangeRates");
+    backend.set_exchange_rate("USD", 1_750);
+    assert_eq!(usd_rate(), 1_700);
+    assert_eq!(backend.request_count("GetAllExchangeRates"), request_count);
+}
+
+#[tokio::test]
+async fn test_exchange_rate_subscription() {
+    let backend = MockBackend::start();
+    let provider = build_async_provider_for(backend.url());
+    let mut exchange_rates = provider.subscribe_to_exchange_rates().await.unwrap();
+
+    let mut currency_codes = Vec::new();
+    for _ in 0..4 {
+        let exchange_rate = next_exchange_rate(&mut exchange_rates).await;
+        currency_codes.push(exchange_rate.currency_code);
+    }
+    currency_codes.sort();
+    assert_eq!(currency_codes, vec!["CHF", "EUR", "GBP", "USD"]);
+
+    backend.set_exchange_rate("EUR", 1_600);
+    let exchange_rate = next_exchange_rate(&mut exchange_rates).await;
+    assert_eq!(exchange_rate.currency_code, "EUR");
+    assert_eq!(exchange_rate.sats_per_unit, 1_600);
+    drop(exchange_rates);
+
+    // Subscribing with a rejected access token refreshes the token.
+    backend.revoke_access_tokens();
+    let mut exchange_rates = provider.subscribe_to_exchange_rates().await.unwrap();
+    let exchange_rate = next_exchange_rate(&mut exchange_rates).await;
+    assert!(exchange_rate.sats_per_unit > 0);
+    assert_eq!(backend.request_count("CurrencyStream"), 2);
+
+    backend.fail_next(
+        "CurrencyStream",
+        Failure::ErrorCode("remote-schema-error".to_string()),
+    );
+    let mut exchange_rates = provider.subscribe_to_exchange_rates().await.unwrap();
+    let result = timeout(Duration::from_secs(5), exchange_rates.next())
+        .await
+        .unwrap();
+    assert!(matches!(result, Some(Err(Error::PermanentFailure { .. }))));
+    assert!(exchange_rates.next().await.is_none());
+}
+
+#[tokio::test]
+async fn test_exchange_rate_subscription_resubscribes() {
+    let backend = MockBackend::start();
+    let provider = build_async_provider_for(backend.url());
+    let mut exchange_rates = provider.subscribe_to_exchange_rates().await.unwrap();
+    for _ in 0..4 {
+        next_exchange_rate(&mut exchange_rates).await;
+    }
+
+    backend.disconnect_subscriptions();
+    let result = timeout(Duration::from_secs(5), exchange_rates.next())
+        .await
+        .unwrap();
+    assert!(matches!(
+        result,
+        Some(Err(Error::RuntimeError {
+            code: GraphQlRuntimeErrorCode::NetworkError,
+            ..
+        }))
+    ));
+
+    // After resubscribing, all current exchange rates are streamed again.
+    backend.set_exchange_rate("EUR", 1_600);
+    let mut currency_codes = Vec::new();
+    for _ in 0..4 {
+        let exchange_rate = next_exchange_rate(&mut exchange_rates).await;
+        if exchange_rate.currency_code == "EUR" {
+            assert_eq!(exchange_rate.sats_per_unit, 1_600);
+        }
+        currency_codes.push(exchange_rate.currency_code);
+    }
+    currency_codes.sort();
+    assert_eq!(currency_codes, vec!["CHF", "EUR", "GBP", "USD"]);
+    assert_eq!(backend.request_count("CurrencyStream"), 2);
+}
+
+#[tokio::test]
+async fn test_exchange_rate_subscription_uses_client_config() {
+    let backend = MockBackend::start();
+    let config = BackendClientConfig {
+        user_agent: "wild-test/1.0".to_string(),
+        proxy: Some(backend.proxy_url()),
+        default_headers: vec![("X-Client-Version".to_string(), "1.2.3".to_string())],
+        ..Default::default()
+    };
+    let provider = build_async_provider_with_config(backend.url(), config);
+    let mut exchange_rates = provider.subscribe_to_exchange_rates().await.unwrap();
+    next_exchange_rate(&mut exchange_rates).await;
+
+    assert_eq!(
+        backend.subscription_header("User-Agent").as_deref(),
+        Some("wild-test/1.0")
+    );
+    assert_eq!(
+        backend.subscription_header("X-Client-Version").as_deref(),
+        Some("1.2.3")
+    );
+    // The session is started over HTTP, only the websocket is tunneled.
+    assert_eq!(backend.request_count("CONNECT"), 1);
+}
+
+async fn next_exchange_rate(exchange_rates: &mut Subscription<ExchangeRate>) -> ExchangeRate {
+    timeout(Duration::from_secs(5), exchange_rates.next())
+        .await
+        .expect("Timed out waiting for an exchange rate")
+        .expect("Subscription ended")
+        .unwrap()
+}
+
+fn build_async_provider() -> asynchronous::ExchangeRateProvider {
+    build_async_provider_for(get_backend_url())
+}
+
+fn build_async_provider_for(backend_url: String) -> asynchronous::ExchangeRateProvider {
+    build_async_provider_with_config(backend_url, BackendClientConfig::default())
+}
+
+fn build_async_provider_with_config(
+    backend_url: String,
+    config: BackendClientConfig,
+) -> asynchronous::ExchangeRateProvider {
+    let mnemonic = generate_mnemonic(24).unwrap();
+    let wallet_keys = derive_keys(
+        Network::Testnet,
+        mnemonic,
+        None,
+        &DerivationScheme::LegacyMasterKey,
+    )
+    .unwrap()
+    .wallet_keypair;
+    let auth_keys = generate_keypair().unwrap();
+    let client = graphql::asynchronous::BackendClient::new(backend_url, config).unwrap();
+    let auth = honeybadger::asynchronous::Auth::with_client(
+        client.clone(),
+        AuthLevel::Pseudonymous,
+        wallet_keys,
+        auth_keys,
+    );
+    asynchronous::ExchangeRateProvider::with_client(client.with_auth(Arc::new(auth)))
+}
+
 fn build_provider() -> ExchangeRateProvider {
+    build_provider_with_config(get_backend_url(), BackendClientConfig::default())
+}
+
+fn build_provider_with_config(
+    backend_url: String,
+    config: BackendClientConfig,
+) -> ExchangeRateProvider {
     println!("Generating keys ...");
-    let mnemonic = generate_mnemonic();
+    let mnemonic = generate_mnemonic(24).unwrap();
     println!("mnemonic: {mnemonic:?}");
-    let wallet_keys = derive_keys(Network::Testnet, mnemonic).wallet_keypair;
-    let auth_keys = generate_keypair();
+    let wallet_keys = derive_keys(
+        Network::Testnet,
+        mnemonic,
+        None,
+        &DerivationScheme::LegacyMasterKey,
+    )
+    .unwrap()
+    .wallet_keypair;
+    let auth_keys = generate_keypair().unwrap();
 
-    let auth = Auth::new(
-        get_backend_url(),
+    let client = BackendClient::new(backend_url, config).unwrap();
+    let auth = Auth::with_client(
+        client.clone(),
         AuthLevel::Pseudonymous,
         wallet_keys,
         auth_keys,
-    )
-    .unwrap();
+    );
+    ExchangeRateProvider::with_client(client.with_auth(Arc::new(auth)))
+}
 
-    ExchangeRateProvider::new(get_backend_url(), Arc::new(auth))
+/// Polls the condition, fails if it does not become true within 10 seconds.
+fn wait_until(condition: impl Fn() -> bool) {
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while !condition() {
+        assert!(Instant::now() < deadline, "Condition not met in time");
+        sleep(Duration::from_millis(10));
+    }
 }
 
 fn get_backend_url() -> String {
-    env::var("GRAPHQL_API_URL").expect("GRAPHQL_API_URL environment variable is not set")
+    mockingbird::backend_url()
 }
@@ -0,0 +1,427 @@
+use crate::topup_errors::TopupErrorRegistry;
+use crate::validation::{
+    normalize_iban, validate_optional_email, validate_topup_setup_input, TopupSetupError,
+    SUPPORTED_TOPUP_CURRENCIES,
+};
+use crate::{
+    ensure_notification_token_registered, sign_topup_challenge, to_language_tag,
+    to_topup_history_variables, to_topup_info, to_topup_registration, to_topup_setup_challenge,
+    CountryCode, FiatTopupSetupChallenge, FiatTopupSetupInfo, LanguageCode, TopupFilter, TopupInfo,
+    TopupOrder, TopupRegistration, TopupStatus,
+};
+use futures_util::stream::{self, BoxStream};
+use futures_util::StreamExt;
+use graphql::asynchronous::BackendClient;
+use graphql::perro::OptionToError;
+use graphql::schema::{
+    complete_topup_setup, get_topup, hide_topup, list_uncompleted_topups,
+    register_notification_token, register_topup, start_topup_setup, topup_stream, topup_updates,
+    CompleteTopupSetup, GetTopup, HideTopup, ListTopups, ListUncompletedTopups,
+    RegisterNotificationToken, RegisterTopup, StartTopupSetup, TopupStream, TopupUpdates,
+};
+use graphql::{parse_from_rfc3339, Error, GraphQlRuntimeErrorCode, ToRfc3339};
+use honeybadger::secrets::KeyPair;
+use log::warn;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::time::{Duration, SystemTime};
+
+/// Backoff before reconnecting a lost topup subscription, doubled for every failed attempt.
+const RECONNECT_INITIAL_BACKOFF: Duration = Duration::from_millis(500);
+const RECONNECT_MAX_BACKOFF: Duration = Duration::from_secs(60);
+/// Maximum number of topups per batch of the topup stream.
+const STREAM_BATCH_SIZE: i64 = 10;
+
+/// Stream of topups whose status changed, see [`OfferManager::subscribe_to_topup_updates`].
+pub type TopupStatusUpdates = BoxStream<'static, graphql::Result<TopupInfo>>;
+
+pub struct OfferManager {
+    client: BackendClient,
+    supported_currencies: Vec<String>,
+    topup_error_registry: TopupErrorRegistry,
+}
+
+impl OfferManager {
+    pub fn with_client(client: BackendClient) -> Self {
+        Self {
+            client,
+            supported_currencies: SUPPORTED_TOPUP_CURRENCIES.map(String::from).to_vec(),
+            topup_error_registry: TopupErrorRegistry::default(),
+        }
+    }
+
+    /// Sets the ISO 4217 codes of the currencies fiat topups can be set up in, as accepted by the
+    /// topup provider. Defaults to [`SUPPORTED_TOPUP_CURRENCIES`].
+    pub fn with_supported_currencies(self, currency_codes: Vec<String>) -> Self {
+        Self {
+            supported_currencies: currency_codes,
+            ..self
+        }
+    }
+
+    /// Sets the error codes unknown to this crate which the topups are resolved with.
+    /// Defaults to an empty registry.
+    pub fn with_topup_error_registry(self, registry: TopupErrorRegistry) -> Self {
+        Self {
+            topup_error_registry: registry,
+            ..self
+        }
+    }
+
+    /// The IBAN gets normalized. Fails with [`TopupSetupError::InvalidInput`] without contacting
+    /// the backend if the IBAN, the currency or the email is invalid, see [`crate::validation`] for the checks.
+    pub async fn start_topup_setup(
+        &self,
+        node_pubkey: String,
+        provider: String,
+        source_iban: String,
+        user_currency: String,
+        email: Option<String>,
+        referral_code: Option<String>,
+    ) -> Result<FiatTopupSetupChallenge, TopupSetupError> {
+        let source_iban = validate_topup_setup_input(
+            &source_iban,
+            &user_currency,
+            &self.supported_currencies,
+            email.as_deref(),
+        )?;
+        let variables = start_topup_setup::Variables {
+            email,
+            node_pubkey,
+            provider,
+            referral_code,
+            source_iban,
+            user_currency,
+        };
+        let data = self.client.post::<StartTopupSetup>(variables).await?;
+        Ok(to_topup_setup_challenge(data))
+    }
+
+    /// Sets up fiat topups to the node in one call: starts the setup, signs the challenge with
+    /// the node key and completes the setup.
+    pub async fn setup_fiat_topup(
+        &self,
+        node_keypair: &KeyPair,
+        provider: String,
+        source_iban: String,
+        user_currency: String,
+        email: Option<String>,
+        referral_code: Option<String>,
+    ) -> Result<FiatTopupSetupInfo, TopupSetupError> {
+        let challenge = self
+            .start_topup_setup(
+                node_keypair.public_key.clone(),
+                provider,
+                source_iban.clone(),
+                user_currency,
+                email,
+                referral_code,
+            )
+            .await?;
+        let signed_challenge = sign_topup_challenge(&challenge.challenge, node_keypair)?;
+        self.complete_topup_setup(challenge.id, signed_challenge, source_iban)
+            .await
+    }
+
+    pub async fn complete_topup_setup(
+        &self,
+        id: String,
+        signed_challenge: String,
+        source_iban: String,
+    ) -> Result<FiatTopupSetupInfo, TopupSetupError> {
+        let source_iban = normalize_iban(&source_iban)?;
+        let variables = complete_topup_setup::Variables {
+            id,
+            signed_challenge,
+            source_iban,
+        };
+        let data = self.client.post::<CompleteTopupSetup>(variables).await?;
+
+        Ok(data.complete_topup_setup.into())
+    }
+
+    /// Registers an existing topup order, e.g. created by a partner, with the wallet.
+    pub async fn register_topup(
+        &self,
+        order_id: String,
+        email: Option<String>,
+        referral_code: Option<String>,
+    ) -> Result<TopupRegistration, TopupSetupError> {
+        validate_optional_email(email.as_deref())?;
+        let variables = register_topup::Variables {
+            order_id,
+            email,
+            referral_code,
+        };
+        let data = self.client.post::<RegisterTopup>(variables).await?;
+        Ok(to_topup_registration(data)?)
+    }
+
+    pub async fn register_notification_token(
+        &self,
+        notification_token: String,
+        language: LanguageCode,
+        country: CountryCode,
+    ) -> graphql::Result<()> {
+        let variables = register_notification_token::Variables {
+            notification_token,
+            language: to_language_tag(language, country),
+        };
+        let data = self
+            .client
+            .post::<RegisterNotificationToken>(variables)
+            .await?;
+        ensure_notification_token_registered(data)
+    }
+
+    pub async fn hide_topup(&self, id: String) -> graphql::Result<()> {
+        self.client
+            .post::<HideTopup>(hide_topup::Variables { id })
+            .await?;
+
+        Ok(())
+    }
+
+    pub async fn query_uncompleted_topups(&self) -> graphql::Result<Vec<TopupInfo>> {
+        let data = self
+            .client
+            .post::<ListUncompletedTopups>(list_uncompleted_topups::Variables {})
+            .await?;
+        data.topup
+            .into_iter()
+            .map(|t| to_topup_info(t.into(), &self.topup_error_registry))
+            .collect()
+    }
+
+    pub async fn query_topup(&self, id: String) -> graphql::Result<TopupInfo> {
+        let data = self
+            .client
+            .post::<GetTopup>(get_topup::Variables { id })
+            .await?;
+        to_topup_info(
+            data.topup_by_pk
+                .ok_or_runtime_error(
+                    GraphQlRuntimeErrorCode::ObjectNotFound,
+                    "No topup found with the provided id",
+                )?
+                .into(),
+            &self.topup_error_registry,
+        )
+    }
+
+    /// Lists the topups matching the filter, skipping the first `offset` ones.
+    ///
+    /// Returns all remaining topups if `limit` is `None`.
+    pub async fn query_topup_history(
+        &self,
+        filter: TopupFilter,
+        order: TopupOrder,
+        limit: Option<u32>,
+        offset: u32,
+    ) -> graphql::Result<Vec<TopupInfo>> {
+        let variables = to_topup_history_variables(filter, order, limit, offset);
+        let data = self.client.post::<ListTopups>(variables).await?;
+        data.topup
+            .into_iter()
+            .map(|t| to_topup_info(t.into(), &self.topup_error_registry))
+            .collect()
+    }
+
+    /// Subscribes to status changes of the topups of the wallet.
+    ///
+    /// First yields every uncompleted topup with its current status, then every new topup and
+    /// every topup whose status changed, including the change to a final status.
+    ///
+    /// New topups are streamed from the topup stream, whose cursor is the creation time of the
+    /// last streamed topup. Status changes are not streamed, as the topup table has no column
+    /// with the time of the last update to put a cursor on. They are found by comparing every
+    /// new result of a live query on the uncompleted topups with the last yielded status of
+    /// every topup. A topup reaching a final status drops out of that query and is queried once.
+    ///
+    /// If the connection is lost, the subscription reconnects with an exponential backoff.
+    /// The topup stream resumes from its cursor, so topups created in the meantime are yielded
+    /// even if they already completed. Statuses are compared with the last yielded ones again,
+    /// so nothing is yielded twice, but if the status of a topup changed more than once in the
+    /// meantime, only the latest status is yielded.
+    /// The stream ends after the first error which is not network related.
+    pub async fn subscribe_to_topup_updates(&self) -> graphql::Result<TopupStatusUpdates> {
+        let created_after = SystemTime::now();
+        let connection = connect_to_topup_updates(&self.client, created_after).await?;
+        let state = TopupUpdatesState {
+            client: self.client.clone(),
+            topup_error_registry: self.topup_error_registry.clone(),
+            connection: Some(connection),
+            statuses: HashMap::new(),
+            created_after,
+            pending: VecDeque::new(),
+            backoff: RECONNECT_INITIAL_BACKOFF,
+        };
+        Ok(stream::unfold(Some(state), |state| async move { state?.next().await }).boxed())
+    }
+}
+
+enum TopupEvent {
+    /// New result of the live query on the uncompleted topups.
+    Uncompleted(graphql::Result<topup_updates::ResponseData>),
+    /// New batch of the topup stream.
+    Created(graphql::Result<topup_stream::ResponseData>),
+    /// The backend completed one of the subscriptions.
+    Completed,
+}
+
+/// Subscribes to the live query on the uncompleted topups and to the topup stream.
+async fn connect_to_topup_updates(
+    client: &BackendClient,
+    created_after: SystemTime,
+) -> graphql::Result<BoxStream<'static, TopupEvent>> {
+    let uncompleted = client
+        .subscribe::<TopupUpdates>(topup_updates::Variables)
+        .await?;
+    let variables = topup_stream::Variables {
+        created_after: created_after.to_rfc3339(),
+        batch_size: STREAM_BATCH_SIZE,
+    };
+    let created = client.subscribe::<TopupStream>(variables).await?;
+    let uncompleted = uncompleted
+        .map(TopupEvent::Uncompleted)
+        .chain(stream::iter([TopupEvent::Completed]));
+    let created = created
+        .map(TopupEvent::Created)
+        .chain(stream::iter([TopupEvent::Completed]));
+    Ok(stream::select(uncompleted, created).boxed())
+}
+
+struct TopupUpdatesState {
+    client: BackendClient,
+    topup_error_registry: TopupErrorRegistry,
+    /// `None` while disconnected.
+    connection: Option<BoxStream<'static, TopupEvent>>,
+    /// Last queued status by id of every topup seen.
+    statuses: HashMap<String, TopupStatus>,
+    /// Creation time of the last streamed topup, the cursor the topup stream resumes from.
+    created_after: SystemTime,
+    /// Changed topups not yielded yet.
+    pending: VecDeque<TopupInfo>,
+    backoff: Duration,
+}
+
+impl TopupUpdatesState {
+    async fn next(mut self) -> Option<(graphql::Result<TopupInfo>, Option<Self>)> {
+        loop {
+            if let Some(topup) = self.pending.pop_front() {
+                return Some((Ok(topup), Some(self)));
+            }
+            let connection = match &mut self.connection {
+                Some(connection) => connection,
+                None => {
+                    tokio::time::sleep(self.backoff).await;
+                    self.backoff = (self.backoff * 2).min(RECONNECT_MAX_BACKOFF);
+                    match connect_to_topup_updates(&self.client, self.created_after).await {
+                        Ok(connection) => self.connection.insert(connection),
+                        Err(e) if is_connection_error(&e) => {
+                            warn!("Failed to resubscribe to topup updates: {e}");
+                            continue;
+                        }
+                        Err(e) => return Some((Err(e), None)),
+                    }
+                }
+            };
+            let result = match connection.next().await {
+                Some(TopupEvent::Uncompleted(Ok(data))) => self.on_uncompleted(data).await,
+                Some(TopupEvent::Created(Ok(data))) => self.on_created(data),
+                Some(TopupEvent::Uncompleted(Err(e)) | TopupEvent::Created(Err(e)))
+                    if is_connection_error(&e) =>
+                {
+                    warn!("Lost the subscription to topup updates: {e}");
+                    self.connection = None;
+                    Ok(())
+                }
+                Some(TopupEvent::Uncompleted(Err(e)) | TopupEvent::Created(Err(e))) => Err(e),
+                Some(TopupEvent::Completed) | None => {
+                    warn!("The backend completed the subscription to topup updates");
+                    self.connection = None;
+                    Ok(())
+                }
+            };
+            if let Err(e) = result {
+                return Some((Err(e), None));
+            }
+        }
+    }
+
+    async fn on_uncompleted(&mut self, data: topup_updates::ResponseData) -> graphql::Result<()> {
+        self.backoff = RECONNECT_INITIAL_BACKOFF;
+        let mut uncompleted = HashSet::new();
+        for topup in data.topup {
+            let topup = to_topup_info(topup.into(), &self.topup_error_registry)?;
+            uncompleted.insert(topup.id.clone());
+            self.queue(topup);
+        }
+        // Topups reaching a final status drop out of the live query.
+        let completed: Vec<String> = self
+            .statuses
+            .iter()
+            .filter(|(id, status)| !is_final(status) && !uncompleted.contains(*id))
+            .map(|(id, _)| id.clone())
+            .collect();
+        for id in completed {
+            let variables = get_topup::Variables { id: id.clone() };
+            match self.client.post::<GetTopup>(variables).await {
+                Ok(data) => match data.topup_by_pk {
+                    Some(topup) => {
+                        self.queue(to_topup_info(topup.into(), &self.topup_error_registry)?)
+                    }
+                    None => {
+                        self.statuses.remove(&id);
+                    }
+                },
+                Err(e) if is_connection_error(&e) => {
+                    // The reconnected live query misses the topup again.
+                    warn!("Failed to query the completed topup {id}: {e}");
+                    self.connection = None;
+                    break;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    fn on_created(&mut self, data: topup_stream::ResponseData) -> graphql::Result<()> {
+        self.backoff = RECONNECT_INITIAL_BACKOFF;
+        for topup in data.topup_stream {
+            self.created_after = self
+                .created_after
+                .max(parse_from_rfc3339(&topup.created_at)?);
+            let topup = to_topup_info(topup.into(), &self.topup_error_registry)?;
+            // The topup may have been queued from the live query already.
+            if !self.statuses.contains_key(&topup.id) {
+                self.queue(topup);
+            }
+        }
+        Ok(())
+    }
+
+    /// Queues the topup to be yielded if its status changed since it was queued last.
+    fn queue(&mut self, topup: TopupInfo) {
+        if self.statuses.get(&topup.id) == Some(&topup.status) {
+            return;
+        }
+        self.statuses.insert(topup.id.clone(), topup.status.clone());
+        self.pending.push_back(topup);
+    }
+}
+
+fn is_final(status: &TopupStatus) -> bool {
+    matches!(status, TopupStatus::SETTLED | TopupStatus::REFUND_HIDDEN)
+}
+
+fn is_connection_error(error: &Error) -> bool {
+    matches!(
+        error,
+        Error::RuntimeError {
+            code: GraphQlRuntimeErrorCode::NetworkError
+                | GraphQlRuntimeErrorCode::RemoteServiceUnavailable,
+            ..
+        }
+    )
+}
@@ -1,12 +1,29 @@
-use graphql::perro::{ensure, permanent_failure};
-use graphql::schema::list_uncompleted_topups::{topup_status_enum, ListUncompletedTopupsTopup};
+pub mod asynchronous;
+pub mod payment_instructions;
+pub mod topup_errors;
+pub mod tracker;
+pub mod validation;
+
+use crate::topup_errors::TopupErrorRegistry;
+use crate::validation::{
+    normalize_iban, validate_optional_email, validate_topup_setup_input, TopupSetupError,
+    SUPPORTED_TOPUP_CURRENCIES,
+};
+use chameleon::conversion::{major_to_minor_units, sats_per_unit_from_price};
+use graphql::perro::{ensure, permanent_failure, OptionToError};
 use graphql::schema::{
-    complete_topup_setup, hide_topup, list_uncompleted_topups, register_notification_token,
-    start_topup_setup, CompleteTopupSetup, HideTopup, ListUncompletedTopups,
-    RegisterNotificationToken, StartTopupSetup,
+    complete_topup_setup, get_topup, hide_topup, list_topups, list_uncompleted_topups,
+    register_notification_token, register_topup, start_topup_setup, CompleteTopupSetup, GetTopup,
+    HideTopup, ListTopups, ListUncompletedTopups, RegisterNotificationToken, RegisterTopup,
+    StartTopupSetup,
+};
+use graphql::{
+    parse_from_rfc3339, BackendClient, ExchangeRate, GraphQlRuntimeErrorCode, ToRfc3339,
 };
-use graphql::{build_client, parse_from_rfc3339, post_blocking, ExchangeRate};
+use honeybadger::secrets::KeyPair;
+use honeybadger::signing::{add_bitcoin_message_prefix, add_hex_prefix, sign};
 use honeybadger::Auth;
+use serde::{Deserialize, Serialize};
 use std::sync::Arc;
 use std::time::SystemTime;
 
@@ -15,11 +32,14 @@ use graphql::schema::complete_topup_setup::CompleteTopupSetupCompleteTopupSetup;
 pub use isocountry::CountryCode;
 pub use isolanguage_1::LanguageCode;
 
-#[derive(PartialEq, Eq, Debug, Clone)]
+#[allow(non_camel_case_types)]
+#[derive(PartialEq, Eq, Debug, Clone, Serialize, Deserialize)]
 pub enum TopupStatus {
     READY,
     FAILED,
     REFUNDED,
+    /// Refunded and hidden by the user.
+    REFUND_HIDDEN,
     SETTLED,
 }
 
@@ -28,7 +48,10 @@ pub enum TemporaryFailureCode {
     NoRoute,
     InvoiceExpired,
     Unexpected,
-    Unknown { msg: String },
+    /// A code unknown to this crate, see [`topup_errors::TopupErrorRegistry`].
+    Unknown {
+        msg: String,
+    },
 }
 
 #[derive(Clone, Debug, PartialEq, Eq)]
@@ -41,6 +64,10 @@ pub enum PermanentFailureCode {
     CustomerRequested,
     AccountNotMatching,
     PayoutExpired,
+    /// A code registered as permanent, see [`topup_errors::TopupErrorRegistry`].
+    Unknown {
+        msg: String,
+    },
 }
 
 #[derive(PartialEq, Eq, Debug, Clone)]
@@ -65,6 +92,34 @@ pub struct TopupInfo {
     pub error: Option<TopupError>,
 }
 
+/// Filter of the topup history, a topup has to match all criteria.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct TopupFilter {
+    /// Topups with any of the statuses, topups with any status if empty.
+    pub statuses: Vec<TopupStatus>,
+    /// Topups created at or after.
+    pub created_from: Option<SystemTime>,
+    /// Topups created before.
+    pub created_until: Option<SystemTime>,
+    /// ISO 4217 code of the user currency, e.g. `EUR`.
+    pub currency_code: Option<String>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TopupOrder {
+    NewestFirst,
+    OldestFirst,
+}
+
+/// Result of registering a topup order with the wallet.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TopupRegistration {
+    pub wallet_pubkey_id: String,
+    /// The node the order tops up.
+    pub node_pubkey: String,
+    pub email: Option<String>,
+}
+
 pub struct FiatTopupSetupChallenge {
     pub id: String,
     pub challenge: String,
@@ -118,15 +173,47 @@ impl From<CompleteTopupSetupCompleteTopupSetup> for FiatTopupSetupInfo {
 }
 
 pub struct OfferManager {
-    backend_url: String,
-    auth: Arc<Auth>,
+    client: BackendClient,
+    supported_currencies: Vec<String>,
+    topup_error_registry: TopupErrorRegistry,
 }
 
 impl OfferManager {
+    #[deprecated(
+        note = "Use OfferManager::with_client, which shares the client with the other crates"
+    )]
     pub fn new(backend_url: String, auth: Arc<Auth>) -> Self {
-        Self { backend_url, auth }
+        Self::with_client(BackendClient::with_default_config(backend_url).with_auth(auth))
+    }
+
+    pub fn with_client(client: BackendClient) -> Self {
+        Self {
+            client,
+            supported_currencies: SUPPORTED_TOPUP_CURRENCIES.map(String::from).to_vec(),
+            topup_error_registry: TopupErrorRegistry::default(),
+        }
+    }
+
+    /// Sets the ISO 4217 codes of the currencies fiat topups can be set up in, as accepted by the
+    /// topup provider. Defaults to [`SUPPORTED_TOPUP_CURRENCIES`].
+    pub fn with_supported_currencies(self, currency_codes: Vec<String>) -> Self {
+        Self {
+            supported_currencies: currency_codes,
+            ..self
+        }
+    }
+
+    /// Sets the error codes unknown to this crate which the topups are resolved with.
+    /// Defaults to an empty registry.
+    pub fn with_topup_error_registry(self, registry: TopupErrorRegistry) -> Self {
+        Self {
+            topup_error_registry: registry,
+            ..self
+        }
     }
 
+    /// The IBAN gets normalized. Fails with [`TopupSetupError::InvalidInput`] without contacting
+    /// the backend if the IBAN, the currency or the email is invalid, see [`validation`] for the checks.
     pub fn start_topup_setup(
         &self,
         node_pubkey: String,
@@ -135,7 +222,13 @@ impl OfferManager {
         user_currency: String,
         email: Option<String>,
         referral_code: Option<String>,
-    ) -> graphql::Result<FiatTopupSetupChallenge> {
+    ) -> Result<FiatTopupSetupChallenge, TopupSetupError> {
+        let source_iban = validate_topup_setup_input(
+            &source_iban,
+            &user_currency,
+            &self.supported_currencies,
+            email.as_deref(),
+        )?;
         let variables = start_topup_setup::Variables {
             email,
             node_pubkey,
@@ -144,14 +237,31 @@ impl OfferManager {
             source_iban,
             user_currency,
         };
-        let access_token = self.auth.query_token()?;
-        let client = build_client(Some(&access_token))?;
-        let data = post_blocking::<StartTopupSetup>(&client, &self.backend_url, variables)?;
+        let data = self.client.post::<StartTopupSetup>(variables)?;
+        Ok(to_topup_setup_challenge(data))
+    }
 
-        Ok(FiatTopupSetupChallenge {
-            id: data.start_topup_setup.id,
-            challenge: data.start_topup_setup.challenge,
-        })
+    /// Sets up fiat topups to the node in one call: starts the setup, signs the challenge with
+    /// the node key and completes the setup.
+    pub fn setup_fiat_topup(
+        &self,
+        node_keypair: &KeyPair,
+        provider: String,
+        source_iban: String,
+        user_currency: String,
+        email: Option<String>,
+        referral_code: Option<String>,
+    ) -> Result<FiatTopupSetupInfo, TopupSetupError> {
+        let challenge = self.start_topup_setup(
+            node_keypair.public_key.clone(),
+            provider,
+            source_iban.clone(),
+            user_currency,
+            email,
+            referral_code,
+        )?;
+        let signed_challenge = sign_topup_challenge(&challenge.challenge, node_keypair)?;
+        self.complete_topup_setup(challenge.id, signed_challenge, source_iban)
     }
 
     pub fn complete_topup_setup(
@@ -159,19 +269,35 @@ impl OfferManager {
         id: String,
         signed_challenge: String,
         source_iban: String,
-    ) -> graphql::Result<FiatTopupSetupInfo> {
+    ) -> Result<FiatTopupSetupInfo, TopupSetupError> {
+        let source_iban = normalize_iban(&source_iban)?;
         let variables = complete_topup_setup::Variables {
             id,
             signed_challenge,
             source_iban,
         };
-        let access_token = self.auth.query_token()?;
-        let client = build_client(Some(&access_token))?;
-        let data = post_blocking::<CompleteTopupSetup>(&client, &self.backend_url, variables)?;
+        let data = self.client.post::<CompleteTopupSetup>(variables)?;
 
         Ok(data.complete_topup_setup.into())
     }
 
+    /// Registers an existing topup order, e.g. created by a partner, with the wallet.
+    pub fn register_topup(
+        &self,
+        order_id: String,
+        email: Option<String>,
+        referral_code: Option<String>,
+    ) -> Result<TopupRegistration, TopupSetupError> {
+        validate_optional_email(email.as_deref())?;
+        let variables = register_topup::Variables {
+            order_id,
+            email,
+            referral_code,
+        };
+        let data = self.client.post::<RegisterTopup>(variables)?;
+        Ok(to_topup_registration(data)?)
+    }
+
     pub fn register_notification_token(
         &self,
         notification_token: String,
@@ -180,44 +306,237 @@ impl OfferManager {
     ) -> graphql::Result<()> {
         let variables = register_notification_token::Variables {
             notification_token,
-            language: format!("{}-{}", language.code(), country.alpha2()),
+            language: to_language_tag(language, country),
         };
-        let access_token = self.auth.query_token()?;
-        let client = build_client(Some(&access_token))?;
-        let data =
-            post_blocking::<RegisterNotificationToken>(&client, &self.backend_url, variables)?;
-        ensure!(matches!(
-            data.register_notification_token,
-            Some(
-                register_notification_token::RegisterNotificationTokenRegisterNotificationToken { .. }
-            )
-        ), permanent_failure("Backend rejected notification token registration"));
-        Ok(())
+        let data = self.client.post::<RegisterNotificationToken>(variables)?;
+        ensure_notification_token_registered(data)
     }
 
     pub fn hide_topup(&self, id: String) -> graphql::Result<()> {
-        let access_token = self.auth.query_token()?;
-        let client = build_client(Some(&access_token))?;
-        post_blocking::<HideTopup>(&client, &self.backend_url, hide_topup::Variables { id })?;
+        self.client
+            .post::<HideTopup>(hide_topup::Variables { id })?;
 
         Ok(())
     }
 
     pub fn query_uncompleted_topups(&self) -> graphql::Result<Vec<TopupInfo>> {
-        let access_token = self.auth.query_token()?;
-        let client = build_client(Some(&access_token))?;
-        let data = post_blocking::<ListUncompletedTopups>(
-            &client,
-            &self.backend_url,
-            list_uncompleted_topups::Variables {},
-        )?;
-        data.topup.into_iter().map(to_topup_info).collect()
+        let data = self
+            .client
+            .post::<ListUncompletedTopups>(list_uncompleted_topups::Variables {})?;
+        data.topup
+            .into_iter()
+            .map(|t| to_topup_info(t.into(), &self.topup_error_registry))
+            .collect()
+    }
+
+    pub fn query_topup(&self, id: String) -> graphql::Result<TopupInfo> {
+        let data = self.client.post::<GetTopup>(get_topup::Variables { id })?;
+        to_topup_info(
+            data.topup_by_pk
+                .ok_or_runtime_error(
+                    GraphQlRuntimeErrorCode::ObjectNotFound,
+                    "No topup found with the provided id",
+                )?
+                .into(),
+            &self.topup_error_registry,
+        )
+    }
+
+    /// Lists the topups matching the filter, skipping the first `offset` ones.
+    ///
+    /// Returns all remaining topups if `limit` is `None`.
+    pub fn query_topup_history(
+        &self,
+        filter: TopupFilter,
+        order: TopupOrder,
+        limit: Option<u32>,
+        offset: u32,
+    ) -> graphql::Result<Vec<TopupInfo>> {
+        let variables = to_topup_history_variables(filter, order, limit, offset);
+        let data = self.client.post::<ListTopups>(variables)?;
+        data.topup
+            .into_iter()
+            .map(|t| to_topup_info(t.into(), &self.topup_error_registry))
+            .collect()
     }
 }
 
-fn to_topup_info(topup: ListUncompletedTopupsTopup) -> graphql::Result<TopupInfo> {
+fn to_topup_history_variables(
+    filter: TopupFilter,
+    order: TopupOrder,
+    limit: Option<u32>,
+    offset: u32,
+) -> list_topups::Variables {
+    use list_topups::*;
+
+    let status = if filter.statuses.is_empty() {
+        None
+    } else {
+        let statuses = filter
+            .statuses
+            .into_iter()
+            .map(|s| match s {
+                TopupStatus::READY => topup_status_enum::READY,
+                TopupStatus::FAILED => topup_status_enum::FAILED,
+                TopupStatus::REFUNDED => topup_status_enum::REFUNDED,
+                TopupStatus::REFUND_HIDDEN => topup_status_enum::REFUND_HIDDEN,
+                TopupStatus::SETTLED => topup_status_enum::SETTLED,
+            })
+            .collect();
+        Some(topup_status_enum_comparison_exp {
+            in_: Some(statuses),
+            ..Default::default()
+        })
+    };
+    let created_at = if filter.created_from.is_none() && filter.created_until.is_none() {
+        None
+    } else {
+        Some(timestamptz_comparison_exp {
+            gte: filter.created_from.map(|t| t.to_rfc3339()),
+            lt: filter.created_until.map(|t| t.to_rfc3339()),
+            ..Default::default()
+        })
+    };
+    // The backend stores currency codes in lower case.
+    let user_currency = filter.currency_code.map(|c| String_comparison_exp {
+        eq: Some(c.to_lowercase()),
+        ..Default::default()
+    });
+    let direction = || match order {
+        TopupOrder::NewestFirst => order_by::desc,
+        TopupOrder::OldestFirst => order_by::asc,
+    };
+
+    Variables {
+        where_: topup_bool_exp {
+            status,
+            created_at,
+            user_currency,
+            ..Default::default()
+        },
+        // Order by id as well for the pagination to be stable.
+        order_by: Some(vec![
+            topup_order_by {
+                created_at: Some(direction()),
+                ..Default::default()
+            },
+            topup_order_by {
+                id: Some(direction()),
+                ..Default::default()
+            },
+        ]),
+        limit: limit.map(i64::from),
+        offset: Some(i64::from(offset)),
+    }
+}
+
+/// Signs the challenge of a fiat topup setup with the key of the node
+/// the topup setup was started for, as expected by `complete_topup_setup`.
+pub fn sign_topup_challenge(challenge: &str, node_keypair: &KeyPair) -> graphql::Result<String> {
+    let challenge_with_prefix = add_bitcoin_message_prefix(challenge);
+    let signature = sign(challenge_with_prefix, &node_keypair.secret_key)?;
+    Ok(add_hex_prefix(&signature))
+}
+
+fn to_topup_setup_challenge(data: start_topup_setup::ResponseData) -> FiatTopupSetupChallenge {
+    FiatTopupSetupChallenge {
+        id: data.start_topup_setup.id,
+        challenge: data.start_topup_setup.challenge,
+    }
+}
+
+fn to_language_tag(language: LanguageCode, country: CountryCode) -> String {
+    format!("{}-{}", language.code(), country.alpha2())
+}
+
+fn to_topup_registration(data: register_topup::ResponseData) -> graphql::Result<TopupRegistration> {
+    let registration = data
+        .register_topup
+        .ok_or_permanent_failure("Backend rejected topup registration")?;
+    Ok(TopupRegistration {
+        wallet_pubkey_id: registration.wallet_pub_key_id,
+        node_pubkey: registration.node_pub_key,
+        email: registration.email,
+    })
+}
+
+fn ensure_notification_token_registered(
+    data: register_notification_token::ResponseData,
+) -> graphql::Result<()> {
+    ensure!(
+        matches!(
+            data.register_notification_token,
+            Some(
+                register_notification_token::RegisterNotificationTokenRegisterNotificationToken { .. }
+            )
+        ),
+        permanent_failure("Backend rejected notification token registration")
+    );
+    Ok(())
+}
+
+/// Columns of the `topup` table, as selected by every topup query.
+struct TopupRow {
+    additional_info: Option<String>,
+    amount_sat: u64,
+    amount_user_currency: f64,
+    created_at: String,
+    exchange_fee_rate: f64,
+    exchange_fee_user_currency: f64,
+    exchange_rate: f64,
+    expires_at: Option<String>,
+    id: String,
+    lnurl: Option<String>,
+    /// The status as returned by the backend if it is unknown.
+    status: Result<TopupStatus, String>,
+    user_currency: String,
+}
+
+/// Every query generates its own topup type.
+macro_rules! impl_from_topup {
+    ($module:ident :: $topup:ident) => {
+        impl From<graphql::schema::$module::$topup> for TopupRow {
+            fn from(topup: graphql::schema::$module::$topup) -> Self {
+                use graphql::schema::$module::topup_status_enum;
+                let status = match topup.status {
+                    topup_status_enum::READY => Ok(TopupStatus::READY),
+                    topup_status_enum::FAILED => Ok(TopupStatus::FAILED),
+                    topup_status_enum::REFUNDED => Ok(TopupStatus::REFUNDED),
+                    topup_status_enum::REFUND_HIDDEN => Ok(TopupStatus::REFUND_HIDDEN),
+                    topup_status_enum::SETTLED => Ok(TopupStatus::SETTLED),
+                    topup_status_enum::Other(status) => Err(status),
+                };
+                Self {
+                    additional_info: topup.additional_info,
+                    amount_sat: topup.amount_sat,
+                    amount_user_currency: topup.amount_user_currency,
+                    created_at: topup.created_at,
+                    exchange_fee_rate: topup.exchange_fee_rate,
+                    exchange_fee_user_currency: topup.exchange_fee_user_currency,
+                    exchange_rate: topup.exchange_rate,
+                    expires_at: topup.expires_at,
+                    id: topup.id,
+                    lnurl: topup.lnurl,
+                    status,
+                    user_currency: topup.user_currency,
+                }
+            }
+        }
+    };
+}
+
+impl_from_topup!(list_uncompleted_topups::ListUncompletedTopupsTopup);
+impl_from_topup!(list_topups::ListTopupsTopup);
+impl_from_topup!(get_topup::GetTopupTopupByPk);
+impl_from_topup!(topup_updates::TopupUpdatesTopup);
+impl_from_topup!(topup_stream::TopupStreamTopupStream);
+
+fn to_topup_info(
+    topup: TopupRow,
+    topup_error_registry: &TopupErrorRegistry,
+) -> graphql::Result<TopupInfo> {
     let currency_code = topup.user_currency.to_string().to_uppercase();
-    let sats_per_unit = (100_000_000_f64 / topup.exchange_rate).round() as u32;
+    let sats_per_unit = sats_per_unit_from_price(topup.exchange_rate).map_err(to_corrupt_data)?;
     let created_at = parse_from_rfc3339(&topup.created_at)?;
     let exchange_rate = ExchangeRate {
         currency_code,
@@ -225,9 +544,15 @@ fn to_topup_info(topup: ListUncompletedTopupsTopup) -> graphql::Result<TopupInfo
         updated_at: created_at,
     };
 
-    let topup_value_minor_units = (topup.amount_user_currency * 100_f64).round() as u64;
+    let topup_value_minor_units =
+        major_to_minor_units(topup.amount_user_currency, &exchange_rate.currency_code)
+            .map_err(to_corrupt_data)?;
     let exchange_fee_rate_permyriad = (topup.exchange_fee_rate * 10_000_f64).round() as u16;
-    let exchange_fee_minor_units = (topup.exchange_fee_user_currency * 100_f64).round() as u64;
+    let exchange_fee_minor_units = major_to_minor_units(
+        topup.exchange_fee_user_currency,
+        &exchange_rate.currency_code,
+    )
+    .map_err(to_corrupt_data)?;
     let expires_at = match topup.expires_at {
         Some(e) => Some(parse_from_rfc3339(&e)?),
         None => None,
@@ -235,28 +560,19 @@ fn to_topup_info(topup: ListUncompletedTopupsTopup) -> graphql::Result<TopupInfo
     let lnurlw = topup.lnurl;
 
     let status = match topup.status {
-        topup_status_enum::FAILED => TopupStatus::FAILED,
-        topup_status_enum::READY => TopupStatus::READY,
-        topup_status_enum::REFUNDED => TopupStatus::REFUNDED,
-        topup_status_enum::SETTLED => TopupStatus::SETTLED,
-        topup_status_enum::REFUND_HIDDEN => {
-            runtime_error!(
-                graphql::GraphQlRuntimeErrorCode::CorruptData,
-                "The backend returned the unexpected status: REFUND_HIDDEN",
-            );
-        }
-        topup_status_enum::Other(_) => {
+        Ok(status) => status,
+        Err(status) => {
             runtime_error!(
                 graphql::GraphQlRuntimeErrorCode::CorruptData,
-                "The backend returned an unknown topup status: {:?}",
-                topup.status
+                "The backend returned an unknown topup status: {status:?}"
             );
         }
     };
 
-    let error = match topup.status {
-        topup_status_enum::FAILED => to_topup_error(topup.additional_info),
-        topup_status_enum::REFUNDED => to_topup_error(topup.additional_info),
+    let error = match status {
+        TopupStatus::FAILED | TopupStatus::REFUNDED | TopupStatus::REFUND_HIDDEN => {
+            topup_error_registry.to_topup_error(topup.additional_info)
+        }
         _ => None,
     };
 
@@ -317,17 +633,79 @@ pub fn to_topup_error(code: Option<String>) -> Option<TopupError> {
     })
 }
 
+/// Invalid amounts in a topup are corrupt data of the backend, not invalid input of the caller.
+fn to_corrupt_data(error: graphql::Error) -> graphql::Error {
+    let msg = match error {
+        graphql::Error::InvalidInput { msg } => msg,
+        error => error.to_string(),
+    };
+    runtime_error(
+        graphql::GraphQlRuntimeErrorCode::CorruptData,
+        format!("The backend returned an invalid topup: {msg}"),
+    )
+}
+
 #[cfg(test)]
 mod tests {
     use std::time::SystemTime;
 
+    use crate::topup_errors::TopupErrorRegistry;
     use crate::{
-        to_topup_info, PermanentFailureCode, TemporaryFailureCode, TopupError, TopupStatus,
+        sign_topup_challenge, to_topup_info, PermanentFailureCode, TemporaryFailureCode,
+        TopupError, TopupStatus,
     };
+    use bitcoin::hashes::hex::FromHex;
+    use bitcoin::hashes::sha256;
+    use bitcoin::secp256k1::ecdsa::Signature;
+    use bitcoin::secp256k1::{Message, PublicKey, Secp256k1};
     use graphql::schema::list_uncompleted_topups::{topup_status_enum, ListUncompletedTopupsTopup};
+    use graphql::{Error, GraphQlRuntimeErrorCode};
+    use honeybadger::secrets::{KeyPair, SecretKey};
+    use std::str::FromStr;
+
+    const NODE_SECRET_KEY: &str =
+        "969063eb7417a919e904a023eaef42bcd6a0d3d67598234b8fa2914ce3bda835";
+    const NODE_PUBLIC_KEY: &str =
+        "02e2ad1cab160ee32e9840801ef200629cb4cca2e9945dd549d7955218a0876099";
+    /// Signatures are deterministic (RFC 6979).
+    const SIGNED_CHALLENGES: [(&str, &str); 2] = [
+        (
+            "e4fcd3f0c7d7e0a9b1c1c5bd6d4a1f4b",
+            "\\x30440220311441f466085f2642c964eed67daa1172b70f005f5b4ae05c7864648cc5ec1902202847e5c734ed74e2e8c60ad57d25a5ddc4daf0520b71ba85db42ab6d519677f2",
+        ),
+        (
+            "6J3nWb9RkqAqB2mLdgSgZ7h1",
+            "\\x30440220368b8d992c788f0fd9f2d10a28bd22650e2c03ffcc639a361634167177c8c3a702204574669c330e04c436ea49bb087bd5aca6c9c4ec06c80fdabba05ff5e685f27f",
+        ),
+    ];
 
     const LNURL: &str = "LNURL1DP68GURN8GHJ7UR0VD4K2ARPWPCZ6EMFWSKHXARPVA5KUEEDWPHKX6M9W3SHQUPWWEJHYCM9DSHXZURS9ASHQ6F0D3H82UNV9AMKJARGV3EXZAE0XVUNQDNYVDJRGTF4XGEKXTF5X56NXTTZX3NRWTT9XDJRJEP4VE3XGD3KXVXTX4LS";
 
+    #[test]
+    fn test_sign_topup_challenge() {
+        let node_keypair = KeyPair {
+            secret_key: SecretKey::from_hex(NODE_SECRET_KEY).unwrap(),
+            public_key: NODE_PUBLIC_KEY.to_string(),
+        };
+        for (challenge, signed_challenge) in SIGNED_CHALLENGES {
+            let signature = sign_topup_challenge(challenge, &node_keypair).unwrap();
+            assert_eq!(signature, signed_challenge);
+            verify_signature(
+                &format!("\\x18Bitcoin Signed Message:{challenge}"),
+                signature.strip_prefix("\\x").unwrap(),
+            );
+        }
+    }
+
+    fn verify_signature(message: &str, signature: &str) {
+        let message = Message::from_hashed_data::<sha256::Hash>(message.as_bytes());
+        let signature = Signature::from_der(&Vec::from_hex(signature).unwrap()).unwrap();
+        let public_key = PublicKey::from_str(NODE_PUBLIC_KEY).unwrap();
+        Secp256k1::verification_only()
+            .verify_ecdsa(&message, &signature, &public_key)
+            .unwrap();
+    }
+
     #[test]
     fn test_topup_to_offer_info() {
         let amount_user_currency = 8.0;
@@ -349,7 +727,7 @@ mod tests {
             user_currency: "eur".to_string(),
         };
 
-        let topup_info = to_topup_info(topup).unwrap();
+        let topup_info = to_topup_info(topup.into(), &TopupErrorRegistry::default()).unwrap();
         assert_eq!(topup_info.id, "1707e09e-ebe1-4004-abd7-7a64604501b3");
         assert_eq!(topup_info.amount_sat, 42578);
         assert_eq!(topup_info.topup_value_minor_units, 800);
@@ -394,7 +772,7 @@ mod tests {
             user_currency: "eur".to_string(),
         };
 
-        let topup_info = to_topup_info(topup).unwrap();
+        let topup_info = to_topup_info(topup.into(), &TopupErrorRegistry::default()).unwrap();
 
         assert!(matches!(
             topup_info.error,
@@ -423,7 +801,7 @@ mod tests {
             user_currency: "eur".to_string(),
         };
 
-        let topup_info = to_topup_info(topup).unwrap();
+        let topup_info = to_topup_info(topup.into(), &TopupErrorRegistry::default()).unwrap();
 
         assert!(matches!(
             topup_info.error,
@@ -434,4 +812,69 @@ mod tests {
         assert!(topup_info.expires_at.is_some());
         assert!(topup_info.lnurlw.is_none());
     }
+
+    #[test]
+    fn test_topup_with_corrupt_amounts() {
+        let topup = |exchange_rate, amount_user_currency| ListUncompletedTopupsTopup {
+            additional_info: None,
+            amount_sat: 42578,
+            amount_user_currency,
+            created_at: "2023-07-21T16:39:21.271+00:00".to_string(),
+            exchange_fee_rate: 0.014999999664723873,
+            exchange_fee_user_currency: 0.11999999731779099,
+            exchange_rate,
+            expires_at: None,
+            id: "1707e09e-ebe1-4004-abd7-7a64604501b3".to_string(),
+            lightning_fee_user_currency: 0.0,
+            lnurl: None,
+            node_pub_key: "0233786a3f5c79d25508ed973e7a37506ddab49d41a07fcb3d341ab638000d69cf"
+                .to_string(),
+            status: topup_status_enum::READY,
+            user_currency: "eur".to_string(),
+        };
+
+        for (exchange_rate, amount_user_currency) in [(0.0, 8.0), (18507.0, -8.0)] {
+            let result = to_topup_info(
+                topup(exchange_rate, amount_user_currency).into(),
+                &TopupErrorRegistry::default(),
+            );
+            assert!(matches!(
+                result,
+                Err(Error::RuntimeError {
+                    code: GraphQlRuntimeErrorCode::CorruptData,
+                    ..
+                })
+            ));
+        }
+    }
+
+    #[test]
+    fn test_topup_with_unknown_status() {
+        let topup = ListUncompletedTopupsTopup {
+            additional_info: None,
+            amount_sat: 42578,
+            amount_user_currency: 8.0,
+            created_at: "2023-07-21T16:39:21.271+00:00".to_string(),
+            exchange_fee_rate: 0.014999999664723873,
+            exchange_fee_user_currency: 0.11999999731779099,
+            exchange_rate: 18507.0,
+            expires_at: None,
+            id: "1707e09e-ebe1-4004-abd7-7a64604501b3".to_string(),
+            lightning_fee_user_currency: 0.0,
+            lnurl: None,
+            node_pub_key: "0233786a3f5c79d25508ed973e7a37506ddab49d41a07fcb3d341ab638000d69cf"
+                .to_string(),
+            status: topup_status_enum::Other("EXPIRED".to_string()),
+            user_currency: "eur".to_string(),
+        };
+
+        let result = to_topup_info(topup.into(), &TopupErrorRegistry::default());
+        assert!(matches!(
+            result,
+            Err(Error::RuntimeError {
+                code: GraphQlRuntimeErrorCode::CorruptData,
+                ..
+            })
+        ));
+    }
 }
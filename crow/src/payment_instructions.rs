@@ -0,0 +1,468 @@
+use crate::validation::mod97_remainder;
+use crate::FiatTopupSetupInfo;
+use chameleon::conversion::minor_unit_exponent;
+use graphql::perro::{ensure, invalid_input};
+
+/// Check digits of the recursive modulo 10 algorithm used by QR references.
+const MOD10_TABLE: [usize; 10] = [0, 9, 4, 6, 8, 2, 7, 1, 3, 5];
+/// Institution ids of QR-IBANs, which have to be used with QR references.
+const QR_IID_RANGE: std::ops::RangeInclusive<u32> = 30_000..=31_999;
+/// Largest amount both the QR-bill and the EPC QR code allow, in cents.
+const MAX_AMOUNT_MINOR_UNITS: u64 = 99_999_999_999;
+const MAX_EPC_PAYLOAD_BYTES: usize = 331;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum CreditorReference {
+    /// Swiss QR reference, 27 digits including a check digit.
+    Qrr(String),
+    /// ISO 11649 structured creditor reference, e.g. `RF18539007547034`.
+    Scor(String),
+}
+
+impl CreditorReference {
+    pub fn as_str(&self) -> &str {
+        match self {
+            Self::Qrr(reference) | Self::Scor(reference) => reference,
+        }
+    }
+
+    /// The reference grouped for reading as printed on payment slips,
+    /// QR references in blocks of 5 from the right, creditor references in blocks of 4.
+    pub fn to_display_string(&self) -> String {
+        let chars: Vec<char> = self.as_str().chars().collect();
+        match self {
+            Self::Qrr(_) => {
+                let mut groups: Vec<String> = chars
+                    .rchunks(5)
+                    .map(|group| group.iter().collect())
+                    .collect();
+                groups.reverse();
+                groups.join(" ")
+            }
+            Self::Scor(_) => chars
+                .chunks(4)
+                .map(|group| group.iter().collect::<String>())
+                .collect::<Vec<_>>()
+                .join(" "),
+        }
+    }
+}
+
+/// Validates the reference, ignoring spaces, to be either a QR reference or a creditor reference.
+pub fn parse_creditor_reference(reference: &str) -> graphql::Result<CreditorReference> {
+    let reference: String = reference
+        .chars()
+        .filter(|c| !c.is_whitespace())
+        .map(|c| c.to_ascii_uppercase())
+        .collect();
+    if reference.starts_with("RF") {
+        ensure!(
+            (5..=25).contains(&reference.len())
+                && reference.chars().all(|c| c.is_ascii_alphanumeric())
+                && reference[2..4].chars().all(|c| c.is_ascii_digit())
+                && mod97_remainder(&reference) == 1,
+            invalid_input(format!("Invalid creditor reference: {reference}"))
+        );
+        Ok(CreditorReference::Scor(reference))
+    } else {
+        ensure!(
+            reference.len() == 27
+                && reference.chars().all(|c| c.is_ascii_digit())
+                && qr_reference_check_digit(&reference[..26]) == reference.as_bytes()[26] - b'0',
+            invalid_input(format!("Invalid QR reference: {reference}"))
+        );
+        Ok(CreditorReference::Qrr(reference))
+    }
+}
+
+/// Data of the QR code of a Swiss QR-bill (version 2.0) with a structured creditor address.
+///
+/// Without an amount the user enters it in the banking app.
+pub fn to_swiss_qr_bill(
+    info: &FiatTopupSetupInfo,
+    amount_minor_units: Option<u64>,
+) -> graphql::Result<String> {
+    let currency = info.currency.to_uppercase();
+    ensure!(
+        currency == "CHF" || currency == "EUR",
+        invalid_input(format!("QR-bills do not support currency: {currency}"))
+    );
+    let iban = compact(&info.creditor_iban);
+    ensure!(
+        iban.starts_with("CH") || iban.starts_with("LI"),
+        invalid_input("QR-bills require a Swiss or Liechtenstein creditor IBAN")
+    );
+    let reference = parse_creditor_reference(&info.creditor_reference)?;
+    let reference_type = match (&reference, is_qr_iban(&iban)) {
+        (CreditorReference::Qrr(_), true) => "QRR",
+        (CreditorReference::Scor(_), false) => "SCOR",
+        (CreditorReference::Qrr(_), false) => {
+            return Err(invalid_input("QR references require a QR-IBAN"))
+        }
+        (CreditorReference::Scor(_), true) => {
+            return Err(invalid_input("QR-IBANs require a QR reference"))
+        }
+    };
+
+    let empty_address = [""; 7];
+    let mut lines = vec!["SPC", "0200", "1", &iban];
+    lines.extend([
+        "S",
+        limit(&info.creditor_name, 70)?,
+        limit(&info.creditor_street, 70)?,
+        "",
+        limit(&info.creditor_postal_code, 16)?,
+        limit(&info.creditor_town, 35)?,
+        limit(&info.creditor_country, 2)?,
+    ]);
+    // Ultimate creditor, reserved for future use.
+    lines.extend(empty_address);
+    let amount = format_amount(amount_minor_units, 2)?;
+    lines.extend([amount.as_str(), &currency]);
+    // Ultimate debtor, unknown to the backend.
+    lines.extend(empty_address);
+    lines.extend([reference_type, reference.as_str(), "", "EPD"]);
+    Ok(lines.join("\n"))
+}
+
+/// Payload of an EPC069-12 (version 002) QR code for a SEPA credit transfer.
+///
+/// Without an amount the user enters it in the banking app.
+pub fn to_epc_qr_code(
+    info: &FiatTopupSetupInfo,
+    amount_minor_units: Option<u64>,
+) -> graphql::Result<String> {
+    let currency = info.currency.to_uppercase();
+    ensure!(
+        currency == "EUR",
+        invalid_input(format!(
+            "SEPA credit transfers do not support currency: {currency}"
+        ))
+    );
+    let amount = format_amount(amount_minor_units, 2)?;
+    let amount = if amount.is_empty() {
+        amount
+    } else {
+        format!("EUR{amount}")
+    };
+    let reference = parse_creditor_reference(&info.creditor_reference)?;
+    // Only creditor references are structured in SEPA.
+    let (structured_reference, unstructured_reference) = match &reference {
+        CreditorReference::Scor(reference) => (reference.as_str(), ""),
+        CreditorReference::Qrr(reference) => ("", reference.as_str()),
+    };
+
+    let iban = compact(&info.creditor_iban);
+    let bic = compact(&info.creditor_bank_bic);
+    let mut lines = vec![
+        "BCD",
+        "002",
+        "1",
+        "SCT",
+        limit(&bic, 11)?,
+        limit(&info.creditor_name, 70)?,
+        limit(&iban, 34)?,
+        &amount,
+        "",
+        structured_reference,
+        unstructured_reference,
+    ];
+    while lines.last() == Some(&"") {
+        lines.pop();
+    }
+    let payload = lines.join("\n");
+    ensure!(
+        payload.len() <= MAX_EPC_PAYLOAD_BYTES,
+        invalid_input("The payment information is too long for an EPC QR code")
+    );
+    Ok(payload)
+}
+
+/// Payment information for the user to type into a banking app.
+pub fn to_payment_text(
+    info: &FiatTopupSetupInfo,
+    amount_minor_units: Option<u64>,
+) -> graphql::Result<String> {
+    let currency = info.currency.to_uppercase();
+    let reference = parse_creditor_reference(&info.creditor_reference)?;
+    let amount = match amount_minor_units {
+        Some(_) => format!(
+            "{currency} {}",
+            format_amount(amount_minor_units, minor_unit_exponent(&currency))?
+        ),
+        None => format!("{currency}, any amount"),
+    };
+    let creditor = [
+        info.creditor_name.as_str(),
+        &info.creditor_street,
+        &format!("{} {}", info.creditor_postal_code, info.creditor_town),
+        &info.creditor_country,
+    ];
+    let bank = [
+        info.creditor_bank_name.as_str(),
+        &info.creditor_bank_street,
+        &format!(
+            "{} {}",
+            info.creditor_bank_postal_code, info.creditor_bank_town
+        ),
+        &info.creditor_bank_country,
+    ];
+    Ok([
+        format!("Amount: {amount}"),
+        format!("IBAN: {}", group_by_four(&compact(&info.creditor_iban))),
+        format!("Reference: {}", reference.to_display_string()),
+        format!("Beneficiary: {}", creditor.join(", ")),
+        format!("Bank: {}", bank.join(", ")),
+        format!("BIC: {}", compact(&info.creditor_bank_bic)),
+    ]
+    .join("\n"))
+}
+
+fn qr_reference_check_digit(digits: &str) -> u8 {
+    let carry = digits.bytes().fold(0, |carry, digit| {
+        MOD10_TABLE[(carry + (digit - b'0') as usize) % 10]
+    });
+    ((10 - carry) % 10) as u8
+}
+
+fn is_qr_iban(iban: &str) -> bool {
+    iban.get(4..9)
+        .and_then(|iid| iid.parse().ok())
+        .is_some_and(|iid| QR_IID_RANGE.contains(&iid))
+}
+
+fn format_amount(amount_minor_units: Option<u64>, exponent: u32) -> graphql::Result<String> {
+    let amount_minor_units = match amount_minor_units {
+        Some(amount_minor_units) => amount_minor_units,
+        None => return Ok(String::new()),
+    };
+    ensure!(
+        (1..=MAX_AMOUNT_MINOR_UNITS).contains(&amount_minor_units),
+        invalid_input(format!("Amount out of range: {amount_minor_units}"))
+    );
+    let minor_units_per_unit = 10_u64.pow(exponent);
+    let units = amount_minor_units / minor_units_per_unit;
+    if exponent == 0 {
+        return Ok(units.to_string());
+    }
+    let minor_units = amount_minor_units % minor_units_per_unit;
+    Ok(format!(
+        "{units}.{minor_units:0width$}",
+        width = exponent as usize
+    ))
+}
+
+fn limit(value: &str, max_chars: usize) -> graphql::Result<&str> {
+    ensure!(
+        value.chars().count() <= max_chars,
+        invalid_input(format!("Exceeds {max_chars} characters: {value}"))
+    );
+    Ok(value)
+}
+
+fn compact(value: &str) -> String {
+    value.chars().filter(|c| !c.is_whitespace()).collect()
+}
+
+fn group_by_four(value: &str) -> String {
+    let chars: Vec<char> = value.chars().collect();
+    chars
+        .chunks(4)
+        .map(|group| group.iter().collect::<String>())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use graphql::Error;
+
+    const QR_IBAN: &str = "CH4431999123000889012";
+    const QR_REFERENCE: &str = "210000000003139471430009017";
+    const IBAN: &str = "CH5604835012345678009";
+    const CREDITOR_REFERENCE: &str = "RF18539007547034";
+
+    #[test]
+    fn test_parse_creditor_reference() {
+        assert_eq!(
+            parse_creditor_reference("21 00000 00003 13947 14300 09017").unwrap(),
+            CreditorReference::Qrr(QR_REFERENCE.to_string())
+        );
+        assert_eq!(
+            parse_creditor_reference("rf18 5390 0754 7034").unwrap(),
+            CreditorReference::Scor(CREDITOR_REFERENCE.to_string())
+        );
+        for reference in [
+            "",
+            "210000000003139471430009018",
+            "21000000000313947143000901",
+            "RF19539007547034",
+            "RF18",
+            "RF18-5390-0754-7034",
+        ] {
+            assert!(matches!(
+                parse_creditor_reference(reference),
+                Err(Error::InvalidInput { .. })
+            ));
+        }
+
+        let reference = CreditorReference::Qrr(QR_REFERENCE.to_string());
+        assert_eq!(
+            reference.to_display_string(),
+            "21 00000 00003 13947 14300 09017"
+        );
+        let reference = CreditorReference::Scor(CREDITOR_REFERENCE.to_string());
+        assert_eq!(reference.to_display_string(), "RF18 5390 0754 7034");
+    }
+
+    #[test]
+    fn test_to_swiss_qr_bill() {
+        let info = setup_info(QR_IBAN, QR_REFERENCE, "CHF");
+        let expected = [
+            "SPC",
+            "0200",
+            "1",
+            QR_IBAN,
+            "S",
+            "Mock Exchange AG",
+            "Musterstrasse 42",
+            "",
+            "8000",
+            "Zürich",
+            "CH",
+            "",
+            "",
+            "",
+            "",
+            "",
+            "",
+            "",
+            "1250.05",
+            "CHF",
+            "",
+            "",
+            "",
+            "",
+            "",
+            "",
+            "",
+            "QRR",
+            QR_REFERENCE,
+            "",
+            "EPD",
+        ]
+        .join("\n");
+        assert_eq!(to_swiss_qr_bill(&info, Some(125_005)).unwrap(), expected);
+
+        let info = setup_info(IBAN, CREDITOR_REFERENCE, "EUR");
+        let qr_bill = to_swiss_qr_bill(&info, None).unwrap();
+        let lines: Vec<&str> = qr_bill.lines().collect();
+        assert_eq!(lines.len(), 31);
+        assert_eq!(lines[18..20], ["", "EUR"]);
+        assert_eq!(lines[27..29], ["SCOR", CREDITOR_REFERENCE]);
+
+        for info in [
+            setup_info(IBAN, QR_REFERENCE, "CHF"),
+            setup_info(QR_IBAN, CREDITOR_REFERENCE, "CHF"),
+            setup_info(QR_IBAN, QR_REFERENCE, "GBP"),
+            setup_info("DE89370400440532013000", CREDITOR_REFERENCE, "EUR"),
+        ] {
+            assert!(matches!(
+                to_swiss_qr_bill(&info, None),
+                Err(Error::InvalidInput { .. })
+            ));
+        }
+    }
+
+    #[test]
+    fn test_to_epc_qr_code() {
+        let info = setup_info(IBAN, CREDITOR_REFERENCE, "EUR");
+        assert_eq!(
+            to_epc_qr_code(&info, Some(1_000)).unwrap(),
+            [
+                "BCD",
+                "002",
+                "1",
+                "SCT",
+                "MOCKCHZZXXX",
+                "Mock Exchange AG",
+                IBAN,
+                "EUR10.00",
+                "",
+                CREDITOR_REFERENCE,
+            ]
+            .join("\n")
+        );
+        assert_eq!(
+            to_epc_qr_code(&info, None).unwrap(),
+            [
+                "BCD",
+                "002",
+                "1",
+                "SCT",
+                "MOCKCHZZXXX",
+                "Mock Exchange AG",
+                IBAN,
+                "",
+                "",
+                CREDITOR_REFERENCE
+            ]
+            .join("\n")
+        );
+
+        let info = setup_info(QR_IBAN, QR_REFERENCE, "CHF");
+        assert!(matches!(
+            to_epc_qr_code(&info, None),
+            Err(Error::InvalidInput { .. })
+        ));
+        let info = setup_info(IBAN, CREDITOR_REFERENCE, "EUR");
+        assert!(matches!(
+            to_epc_qr_code(&info, Some(0)),
+            Err(Error::InvalidInput { .. })
+        ));
+    }
+
+    #[test]
+    fn test_to_payment_text() {
+        let info = setup_info(QR_IBAN, QR_REFERENCE, "CHF");
+        assert_eq!(
+            to_payment_text(&info, Some(5_000)).unwrap(),
+            "Amount: CHF 50.00\n\
+             IBAN: CH44 3199 9123 0008 8901 2\n\
+             Reference: 21 00000 00003 13947 14300 09017\n\
+             Beneficiary: Mock Exchange AG, Musterstrasse 42, 8000 Zürich, CH\n\
+             Bank: Mock Bank AG, Bahnhofstrasse 1, 8001 Zürich, CH\n\
+             BIC: MOCKCHZZXXX"
+        );
+        let info = setup_info(IBAN, CREDITOR_REFERENCE, "EUR");
+        let text = to_payment_text(&info, None).unwrap();
+        assert!(text.starts_with("Amount: EUR, any amount\n"));
+        assert!(text.contains("Reference: RF18 5390 0754 7034\n"));
+    }
+
+    fn setup_info(
+        creditor_iban: &str,
+        creditor_reference: &str,
+        currency: &str,
+    ) -> FiatTopupSetupInfo {
+        FiatTopupSetupInfo {
+            order_id: "f4b9c2d6-5e1a-4c7b-9d3e-2a8f6b1c0e95".to_string(),
+            debitor_iban: "CH9300762011623852957".to_string(),
+            creditor_reference: creditor_reference.to_string(),
+            creditor_iban: creditor_iban.to_string(),
+            creditor_bank_name: "Mock Bank AG".to_string(),
+            creditor_bank_street: "Bahnhofstrasse 1".to_string(),
+            creditor_bank_postal_code: "8001".to_string(),
+            creditor_bank_town: "Zürich".to_string(),
+            creditor_bank_country: "CH".to_string(),
+            creditor_bank_bic: "MOCKCHZZXXX".to_string(),
+            creditor_name: "Mock Exchange AG".to_string(),
+            creditor_street: "Musterstrasse 42".to_string(),
+            creditor_postal_code: "8000".to_string(),
+            creditor_town: "Zürich".to_string(),
+            creditor_country: "CH".to_string(),
+            currency: currency.to_string(),
+        }
+    }
+}
@@ -0,0 +1,256 @@
+use crate::{to_topup_error, PermanentFailureCode, TemporaryFailureCode, TopupError};
+use std::collections::BTreeMap;
+
+/// What the user can do about a failed topup.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TopupErrorAction {
+    /// The topup can be claimed again later.
+    RetryLater,
+    ContactSupport,
+    /// Future topups have to be sent from a different bank account.
+    ChangeBankAccount,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TopupErrorDetails {
+    /// Key of the message to show to the user, e.g. `topup_error.no_route`.
+    pub message_key: String,
+    /// `None` if there is nothing the user has to do.
+    pub suggested_action: Option<TopupErrorAction>,
+    /// Whether the fiat amount gets refunded without the user asking for it.
+    pub refund_is_automatic: bool,
+}
+
+/// How to treat a backend error code this crate does not know.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TopupErrorCodeDefinition {
+    pub is_permanent: bool,
+    pub details: TopupErrorDetails,
+}
+
+/// Error codes the backend reports in `additional_info` which are unknown to this crate version,
+/// registered at runtime. Passed to `OfferManager::with_topup_error_registry`.
+#[derive(Clone, Debug, Default)]
+pub struct TopupErrorRegistry {
+    codes: BTreeMap<String, TopupErrorCodeDefinition>,
+}
+
+impl TopupErrorRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers an error code unknown to this crate. Codes known to this crate cannot be
+    /// redefined.
+    ///
+    /// Returns `false` without registering anything if the code is known to this crate.
+    pub fn register(&mut self, code: String, definition: TopupErrorCodeDefinition) -> bool {
+        if is_known_code(&code) {
+            return false;
+        }
+        self.codes.insert(code, definition);
+        true
+    }
+
+    /// Same as [`to_topup_error`], but unknown codes registered as permanent
+    /// become [`TopupError::PermanentFailure`].
+    pub fn to_topup_error(&self, code: Option<String>) -> Option<TopupError> {
+        match to_topup_error(code)? {
+            TopupError::TemporaryFailure {
+                code: TemporaryFailureCode::Unknown { msg },
+            } if self.codes.get(&msg).is_some_and(|d| d.is_permanent) => {
+                Some(TopupError::PermanentFailure {
+                    code: PermanentFailureCode::Unknown { msg },
+                })
+            }
+            error => Some(error),
+        }
+    }
+
+    /// Same as [`TopupError::details`], but returns the details of registered codes.
+    pub fn details(&self, error: &TopupError) -> TopupErrorDetails {
+        let registered = match error {
+            TopupError::TemporaryFailure {
+                code: TemporaryFailureCode::Unknown { msg },
+            }
+            | TopupError::PermanentFailure {
+                code: PermanentFailureCode::Unknown { msg },
+            } => self.codes.get(msg),
+            _ => None,
+        };
+        match registered {
+            Some(definition) => definition.details.clone(),
+            None => error.details(),
+        }
+    }
+}
+
+fn is_known_code(code: &str) -> bool {
+    !matches!(
+        to_topup_error(Some(code.to_string())),
+        Some(TopupError::TemporaryFailure {
+            code: TemporaryFailureCode::Unknown { .. }
+        })
+    )
+}
+
+impl TopupError {
+    /// Codes unknown to this crate get generic details, see [`TopupErrorRegistry::details`].
+    pub fn details(&self) -> TopupErrorDetails {
+        match self {
+            TopupError::TemporaryFailure { code } => temporary_failure_details(code),
+            TopupError::PermanentFailure { code } => permanent_failure_details(code),
+        }
+    }
+}
+
+fn temporary_failure_details(code: &TemporaryFailureCode) -> TopupErrorDetails {
+    let key = match code {
+        TemporaryFailureCode::NoRoute => "no_route",
+        TemporaryFailureCode::InvoiceExpired => "invoice_expired",
+        TemporaryFailureCode::Unexpected => "unexpected",
+        TemporaryFailureCode::Unknown { .. } => return unknown_code_details(),
+    };
+    details(key, Some(TopupErrorAction::RetryLater), false)
+}
+
+fn permanent_failure_details(code: &PermanentFailureCode) -> TopupErrorDetails {
+    let (key, action) = match code {
+        PermanentFailureCode::ThresholdExceeded => {
+            ("threshold_exceeded", Some(TopupErrorAction::ContactSupport))
+        }
+        PermanentFailureCode::OrderInactive => {
+            ("order_inactive", Some(TopupErrorAction::ContactSupport))
+        }
+        PermanentFailureCode::CompaniesUnsupported => (
+            "companies_unsupported",
+            Some(TopupErrorAction::ChangeBankAccount),
+        ),
+        PermanentFailureCode::CountryUnsupported => (
+            "country_unsupported",
+            Some(TopupErrorAction::ChangeBankAccount),
+        ),
+        PermanentFailureCode::OtherRiskDetected => (
+            "other_risk_detected",
+            Some(TopupErrorAction::ContactSupport),
+        ),
+        PermanentFailureCode::CustomerRequested => ("customer_requested", None),
+        PermanentFailureCode::AccountNotMatching => (
+            "account_not_matching",
+            Some(TopupErrorAction::ChangeBankAccount),
+        ),
+        // The payout to the wallet expired, the funds are with the provider.
+        PermanentFailureCode::PayoutExpired => {
+            return details(
+                "payout_expired",
+                Some(TopupErrorAction::ContactSupport),
+                false,
+            )
+        }
+        PermanentFailureCode::Unknown { .. } => return unknown_code_details(),
+    };
+    details(key, action, true)
+}
+
+fn unknown_code_details() -> TopupErrorDetails {
+    details("unknown", Some(TopupErrorAction::ContactSupport), false)
+}
+
+fn details(
+    key: &str,
+    suggested_action: Option<TopupErrorAction>,
+    refund_is_automatic: bool,
+) -> TopupErrorDetails {
+    TopupErrorDetails {
+        message_key: format!("topup_error.{key}"),
+        suggested_action,
+        refund_is_automatic,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_details_of_known_codes() {
+        let details = to_topup_error(Some("no_route".to_string()))
+            .unwrap()
+            .details();
+        assert_eq!(details.message_key, "topup_error.no_route");
+        assert_eq!(details.suggested_action, Some(TopupErrorAction::RetryLater));
+        assert!(!details.refund_is_automatic);
+
+        let details = to_topup_error(Some("account_not_matching".to_string()))
+            .unwrap()
+            .details();
+        assert_eq!(details.message_key, "topup_error.account_not_matching");
+        assert_eq!(
+            details.suggested_action,
+            Some(TopupErrorAction::ChangeBankAccount)
+        );
+        assert!(details.refund_is_automatic);
+
+        let details = to_topup_error(Some("customer_requested".to_string()))
+            .unwrap()
+            .details();
+        assert_eq!(details.suggested_action, None);
+    }
+
+    #[test]
+    fn test_registered_codes() {
+        let mut registry = TopupErrorRegistry::new();
+        let error = registry
+            .to_topup_error(Some("iban_blocked".to_string()))
+            .unwrap();
+        assert_eq!(
+            error,
+            TopupError::TemporaryFailure {
+                code: TemporaryFailureCode::Unknown {
+                    msg: "iban_blocked".to_string()
+                }
+            }
+        );
+        assert_eq!(registry.details(&error).message_key, "topup_error.unknown");
+
+        let details = TopupErrorDetails {
+            message_key: "topup_error.iban_blocked".to_string(),
+            suggested_action: Some(TopupErrorAction::ChangeBankAccount),
+            refund_is_automatic: true,
+        };
+        assert!(registry.register(
+            "iban_blocked".to_string(),
+            TopupErrorCodeDefinition {
+                is_permanent: true,
+                details: details.clone(),
+            },
+        ));
+        let error = registry
+            .to_topup_error(Some("iban_blocked".to_string()))
+            .unwrap();
+        assert_eq!(
+            error,
+            TopupError::PermanentFailure {
+                code: PermanentFailureCode::Unknown {
+                    msg: "iban_blocked".to_string()
+                }
+            }
+        );
+        assert_eq!(registry.details(&error), details);
+        // Without the registry the code stays unknown.
+        assert_eq!(error.details().message_key, "topup_error.unknown");
+
+        // Known codes keep their meaning.
+        assert!(!registry.register(
+            "no_route".to_string(),
+            TopupErrorCodeDefinition {
+                is_permanent: true,
+                details,
+            },
+        ));
+        let error = registry
+            .to_topup_error(Some("no_route".to_string()))
+            .unwrap();
+        assert_eq!(registry.details(&error).message_key, "topup_error.no_route");
+    }
+}
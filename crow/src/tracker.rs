@@ -0,0 +1,192 @@
+use crate::{TemporaryFailureCode, TopupError, TopupInfo, TopupStatus};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::time::{Duration, SystemTime};
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TopupEvent {
+    NewTopupReady(TopupInfo),
+    /// If the backend did not report a reason, the error is an unexpected temporary failure.
+    TopupFailed {
+        topup: TopupInfo,
+        error: TopupError,
+    },
+    TopupRefunded(TopupInfo),
+    /// Emitted once per topup, when it is still ready and expires within the warning period.
+    TopupExpiringSoon(TopupInfo),
+    /// The topup is no longer uncompleted, e.g. it was claimed or the refund got hidden.
+    TopupDisappeared {
+        id: String,
+    },
+}
+
+/// What the tracker knows about the topups, persist it to resume tracking after a restart.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TopupTrackerState {
+    topups: BTreeMap<String, TrackedTopup>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+struct TrackedTopup {
+    status: TopupStatus,
+    expiry_reported: bool,
+}
+
+/// Turns successive results of `query_uncompleted_topups` into [`TopupEvent`]s.
+pub struct TopupTracker {
+    state: TopupTrackerState,
+    expiry_warning_period: Duration,
+}
+
+impl TopupTracker {
+    pub fn new(state: TopupTrackerState, expiry_warning_period: Duration) -> Self {
+        Self {
+            state,
+            expiry_warning_period,
+        }
+    }
+
+    pub fn state(&self) -> &TopupTrackerState {
+        &self.state
+    }
+
+    /// Compares the uncompleted topups with the last known ones and returns what changed.
+    pub fn sync(&mut self, topups: Vec<TopupInfo>) -> Vec<TopupEvent> {
+        self.sync_at(topups, SystemTime::now())
+    }
+
+    fn sync_at(&mut self, topups: Vec<TopupInfo>, now: SystemTime) -> Vec<TopupEvent> {
+        let mut events = Vec::new();
+        let mut topups_by_id = BTreeMap::new();
+        for topup in topups {
+            let known = self.state.topups.remove(&topup.id);
+            let status_changed = known.as_ref().map(|k| &k.status) != Some(&topup.status);
+            let mut expiry_reported = known.is_some_and(|k| k.expiry_reported && !status_changed);
+            if status_changed {
+                events.extend(to_status_event(&topup));
+            }
+            if topup.status == TopupStatus::READY
+                && !expiry_reported
+                && self.expires_soon(&topup, now)
+            {
+                events.push(TopupEvent::TopupExpiringSoon(topup.clone()));
+                expiry_reported = true;
+            }
+            topups_by_id.insert(
+                topup.id,
+                TrackedTopup {
+                    status: topup.status,
+                    expiry_reported,
+                },
+            );
+        }
+        for id in std::mem::replace(&mut self.state.topups, topups_by_id).into_keys() {
+            events.push(TopupEvent::TopupDisappeared { id });
+        }
+        events
+    }
+
+    fn expires_soon(&self, topup: &TopupInfo, now: SystemTime) -> bool {
+        topup
+            .expires_at
+            .is_some_and(|expires_at| expires_at <= now + self.expiry_warning_period)
+    }
+}
+
+fn to_status_event(topup: &TopupInfo) -> Option<TopupEvent> {
+    match topup.status {
+        TopupStatus::READY => Some(TopupEvent::NewTopupReady(topup.clone())),
+        TopupStatus::FAILED => Some(TopupEvent::TopupFailed {
+            topup: topup.clone(),
+            error: topup.error.clone().unwrap_or(TopupError::TemporaryFailure {
+                code: TemporaryFailureCode::Unexpected,
+            }),
+        }),
+        TopupStatus::REFUNDED => Some(TopupEvent::TopupRefunded(topup.clone())),
+        TopupStatus::REFUND_HIDDEN | TopupStatus::SETTLED => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ExchangeRate;
+
+    const HOUR: Duration = Duration::from_secs(60 * 60);
+
+    #[test]
+    fn test_sync() {
+        let now = SystemTime::now();
+        let mut tracker = TopupTracker::new(TopupTrackerState::default(), HOUR);
+        assert_eq!(tracker.sync_at(Vec::new(), now), Vec::new());
+
+        let ready = topup("topup-1", TopupStatus::READY, Some(now + 2 * HOUR));
+        let events = tracker.sync_at(vec![ready.clone()], now);
+        assert_eq!(events, vec![TopupEvent::NewTopupReady(ready.clone())]);
+        assert_eq!(tracker.sync_at(vec![ready.clone()], now), Vec::new());
+
+        let later = now + HOUR + HOUR / 2;
+        let events = tracker.sync_at(vec![ready.clone()], later);
+        assert_eq!(events, vec![TopupEvent::TopupExpiringSoon(ready.clone())]);
+        assert_eq!(tracker.sync_at(vec![ready.clone()], later), Vec::new());
+
+        let failed = TopupInfo {
+            status: TopupStatus::FAILED,
+            ..ready
+        };
+        let refunded = topup("topup-2", TopupStatus::REFUNDED, None);
+        let events = tracker.sync_at(vec![failed.clone(), refunded.clone()], later);
+        assert_eq!(
+            events,
+            vec![
+                TopupEvent::TopupFailed {
+                    topup: failed.clone(),
+                    error: TopupError::TemporaryFailure {
+                        code: TemporaryFailureCode::Unexpected
+                    },
+                },
+                TopupEvent::TopupRefunded(refunded),
+            ]
+        );
+
+        let events = tracker.sync_at(vec![failed], later);
+        assert_eq!(
+            events,
+            vec![TopupEvent::TopupDisappeared {
+                id: "topup-2".to_string()
+            }]
+        );
+    }
+
+    #[test]
+    fn test_resume_from_state() {
+        let now = SystemTime::now();
+        let ready = topup("topup-1", TopupStatus::READY, Some(now));
+        let mut tracker = TopupTracker::new(TopupTrackerState::default(), HOUR);
+        assert_eq!(tracker.sync_at(vec![ready.clone()], now).len(), 2);
+
+        let state = serde_json::to_string(tracker.state()).unwrap();
+        let state = serde_json::from_str(&state).unwrap();
+        let mut tracker = TopupTracker::new(state, HOUR);
+        assert_eq!(tracker.sync_at(vec![ready], now), Vec::new());
+    }
+
+    fn topup(id: &str, status: TopupStatus, expires_at: Option<SystemTime>) -> TopupInfo {
+        TopupInfo {
+            id: id.to_string(),
+            status,
+            amount_sat: 42_000,
+            topup_value_minor_units: 1_000,
+            exchange_fee_rate_permyriad: 50,
+            exchange_fee_minor_units: 5,
+            exchange_rate: ExchangeRate {
+                currency_code: "EUR".to_string(),
+                sats_per_unit: 4_200,
+                updated_at: SystemTime::UNIX_EPOCH,
+            },
+            expires_at,
+            lnurlw: None,
+            error: None,
+        }
+    }
+}
@@ -0,0 +1,377 @@
+use std::fmt::{Display, Formatter};
+
+/// Currencies fiat topups can be set up in if the caller does not pass them,
+/// see `OfferManager::with_supported_currencies`.
+///
+/// The backend does not expose them, `list_currencies` of chameleon lists the currencies of all
+/// countries instead. This is only a fallback mirroring the currencies the topup provider
+/// accepted when it was written.
+pub const SUPPORTED_TOPUP_CURRENCIES: [&str; 3] = ["CHF", "EUR", "GBP"];
+
+/// IBAN length by country as in the IBAN registry.
+const IBAN_LENGTHS: [(&str, usize); 88] = [
+    ("AD", 24),
+    ("AE", 23),
+    ("AL", 28),
+    ("AT", 20),
+    ("AZ", 28),
+    ("BA", 20),
+    ("BE", 16),
+    ("BG", 22),
+    ("BH", 22),
+    ("BI", 27),
+    ("BR", 29),
+    ("BY", 28),
+    ("CH", 21),
+    ("CR", 22),
+    ("CY", 28),
+    ("CZ", 24),
+    ("DE", 22),
+    ("DJ", 27),
+    ("DK", 18),
+    ("DO", 28),
+    ("EE", 20),
+    ("EG", 29),
+    ("ES", 24),
+    ("FI", 18),
+    ("FK", 18),
+    ("FO", 18),
+    ("FR", 27),
+    ("GB", 22),
+    ("GE", 22),
+    ("GI", 23),
+    ("GL", 18),
+    ("GR", 27),
+    ("GT", 28),
+    ("HR", 21),
+    ("HU", 28),
+    ("IE", 22),
+    ("IL", 23),
+    ("IQ", 23),
+    ("IS", 26),
+    ("IT", 27),
+    ("JO", 30),
+    ("KW", 30),
+    ("KZ", 20),
+    ("LB", 28),
+    ("LC", 32),
+    ("LI", 21),
+    ("LT", 20),
+    ("LU", 20),
+    ("LV", 21),
+    ("LY", 25),
+    ("MC", 27),
+    ("MD", 24),
+    ("ME", 22),
+    ("MK", 19),
+    ("MN", 20),
+    ("MR", 27),
+    ("MT", 31),
+    ("MU", 30),
+    ("NI", 28),
+    ("NL", 18),
+    ("NO", 15),
+    ("OM", 23),
+    ("PK", 24),
+    ("PL", 28),
+    ("PS", 29),
+    ("PT", 25),
+    ("QA", 29),
+    ("RO", 24),
+    ("RS", 22),
+    ("RU", 33),
+    ("SA", 24),
+    ("SC", 31),
+    ("SD", 18),
+    ("SE", 24),
+    ("SI", 19),
+    ("SK", 24),
+    ("SM", 27),
+    ("SO", 23),
+    ("ST", 25),
+    ("SV", 28),
+    ("TL", 23),
+    ("TN", 24),
+    ("TR", 26),
+    ("UA", 29),
+    ("VA", 22),
+    ("VG", 24),
+    ("XK", 20),
+    ("YE", 30),
+];
+
+const MAX_EMAIL_LENGTH: usize = 254;
+const MAX_EMAIL_LOCAL_PART_LENGTH: usize = 64;
+
+/// Why the input of a topup setup is invalid.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TopupInputError {
+    /// The IBAN contains characters other than letters, digits and spaces.
+    IbanInvalidCharacters,
+    /// The IBAN does not start with the code of a country using IBANs.
+    IbanUnknownCountry {
+        country_code: String,
+    },
+    IbanInvalidLength {
+        expected: usize,
+        actual: usize,
+    },
+    /// The check digits do not match, most likely the IBAN contains a typo.
+    IbanInvalidChecksum,
+    UnsupportedCurrency {
+        currency_code: String,
+    },
+    InvalidEmail,
+}
+
+impl Display for TopupInputError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::IbanInvalidCharacters => write!(f, "The IBAN contains invalid characters"),
+            Self::IbanUnknownCountry { country_code } => {
+                write!(f, "The IBAN has an unknown country code: {country_code}")
+            }
+            Self::IbanInvalidLength { expected, actual } => {
+                write!(f, "The IBAN has {actual} characters instead of {expected}")
+            }
+            Self::IbanInvalidChecksum => write!(f, "The IBAN has an invalid checksum"),
+            Self::UnsupportedCurrency { currency_code } => {
+                write!(f, "Topups are not supported in currency: {currency_code}")
+            }
+            Self::InvalidEmail => write!(f, "The email address is invalid"),
+        }
+    }
+}
+
+impl std::error::Error for TopupInputError {}
+
+/// Why setting up or registering a topup failed.
+#[derive(Debug, PartialEq)]
+pub enum TopupSetupError {
+    /// The input was rejected without contacting the backend, tells which field is invalid.
+    InvalidInput(TopupInputError),
+    Backend(graphql::Error),
+}
+
+impl Display for TopupSetupError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::InvalidInput(e) => write!(f, "Invalid topup input: {e}"),
+            Self::Backend(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl std::error::Error for TopupSetupError {}
+
+impl From<TopupInputError> for TopupSetupError {
+    fn from(error: TopupInputError) -> Self {
+        Self::InvalidInput(error)
+    }
+}
+
+impl From<graphql::Error> for TopupSetupError {
+    fn from(error: graphql::Error) -> Self {
+        Self::Backend(error)
+    }
+}
+
+/// Validates the IBAN and returns it in its electronic format,
+/// i.e. without spaces and in upper case.
+pub fn normalize_iban(iban: &str) -> Result<String, TopupInputError> {
+    let iban: String = iban
+        .chars()
+        .filter(|c| !c.is_whitespace())
+        .map(|c| c.to_ascii_uppercase())
+        .collect();
+    if !iban.chars().all(|c| c.is_ascii_alphanumeric()) {
+        return Err(TopupInputError::IbanInvalidCharacters);
+    }
+
+    let country_code = iban.get(..2).unwrap_or(&iban);
+    let expected = IBAN_LENGTHS
+        .iter()
+        .find(|(code, _)| *code == country_code)
+        .map(|(_, length)| *length)
+        .ok_or_else(|| TopupInputError::IbanUnknownCountry {
+            country_code: country_code.to_string(),
+        })?;
+    if iban.len() != expected {
+        return Err(TopupInputError::IbanInvalidLength {
+            expected,
+            actual: iban.len(),
+        });
+    }
+
+    if mod97_remainder(&iban) != 1 {
+        return Err(TopupInputError::IbanInvalidChecksum);
+    }
+    Ok(iban)
+}
+
+/// Validates the ISO 4217 currency code against the supported currency codes, ignoring case.
+pub fn validate_topup_currency<S: AsRef<str>>(
+    currency_code: &str,
+    supported_currencies: &[S],
+) -> Result<(), TopupInputError> {
+    let is_supported = supported_currencies
+        .iter()
+        .any(|c| c.as_ref().eq_ignore_ascii_case(currency_code));
+    if !is_supported {
+        return Err(TopupInputError::UnsupportedCurrency {
+            currency_code: currency_code.to_string(),
+        });
+    }
+    Ok(())
+}
+
+/// Checks the syntax of the email address, not whether it exists.
+pub fn validate_email(email: &str) -> Result<(), TopupInputError> {
+    let (local_part, domain) = email
+        .rsplit_once('@')
+        .ok_or(TopupInputError::InvalidEmail)?;
+    let valid_local_part = !local_part.is_empty()
+        && local_part.len() <= MAX_EMAIL_LOCAL_PART_LENGTH
+        && !local_part.starts_with('.')
+        && !local_part.ends_with('.')
+        && !local_part.contains("..")
+        && local_part
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "!#$%&'*+-/=?^_`{|}~.".contains(c));
+    let labels: Vec<&str> = domain.split('.').collect();
+    let valid_domain = labels.len() >= 2
+        && labels.iter().all(|label| {
+            !label.is_empty()
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        });
+    if !valid_local_part || !valid_domain || email.len() > MAX_EMAIL_LENGTH {
+        return Err(TopupInputError::InvalidEmail);
+    }
+    Ok(())
+}
+
+/// Validates the input of a topup setup and returns the normalized IBAN.
+pub(crate) fn validate_topup_setup_input(
+    source_iban: &str,
+    user_currency: &str,
+    supported_currencies: &[String],
+    email: Option<&str>,
+) -> Result<String, TopupInputError> {
+    let source_iban = normalize_iban(source_iban)?;
+    validate_topup_currency(user_currency, supported_currencies)?;
+    validate_optional_email(email)?;
+    Ok(source_iban)
+}
+
+pub(crate) fn validate_optional_email(email: Option<&str>) -> Result<(), TopupInputError> {
+    match email {
+        Some(email) => validate_email(email),
+        None => Ok(()),
+    }
+}
+
+/// Remainder of the value interpreted as a number after moving the first four characters
+/// to the end and replacing letters by 10 to 35 (ISO 7064 MOD 97-10).
+///
+/// Valid IBANs and ISO 11649 creditor references have a remainder of 1.
+pub(crate) fn mod97_remainder(value: &str) -> u32 {
+    let (head, tail) = value.split_at(4);
+    tail.chars()
+        .chain(head.chars())
+        .fold(0, |remainder, c| match c.to_digit(36) {
+            Some(digit) if digit < 10 => (remainder * 10 + digit) % 97,
+            Some(digit) => (remainder * 100 + digit) % 97,
+            None => remainder,
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_normalize_iban() {
+        assert_eq!(
+            normalize_iban("ch93 0076 2011 6238 5295 7").unwrap(),
+            "CH9300762011623852957"
+        );
+        assert_eq!(
+            normalize_iban("DE89370400440532013000").unwrap(),
+            "DE89370400440532013000"
+        );
+        assert_eq!(
+            normalize_iban("GB29 NWBK 6016 1331 9268 19").unwrap(),
+            "GB29NWBK60161331926819"
+        );
+        assert_eq!(
+            normalize_iban("CH93-0076-2011-6238-5295-7"),
+            Err(TopupInputError::IbanInvalidCharacters)
+        );
+        assert_eq!(
+            normalize_iban("XX93 0076 2011 6238 5295 7"),
+            Err(TopupInputError::IbanUnknownCountry {
+                country_code: "XX".to_string()
+            })
+        );
+        assert_eq!(
+            normalize_iban(""),
+            Err(TopupInputError::IbanUnknownCountry {
+                country_code: String::new()
+            })
+        );
+        assert_eq!(
+            normalize_iban("CH93 0076 2011 6238 5295"),
+            Err(TopupInputError::IbanInvalidLength {
+                expected: 21,
+                actual: 20
+            })
+        );
+        assert_eq!(
+            normalize_iban("CH93 0076 2011 6238 5295 8"),
+            Err(TopupInputError::IbanInvalidChecksum)
+        );
+    }
+
+    #[test]
+    fn test_validate_topup_currency() {
+        let supported = SUPPORTED_TOPUP_CURRENCIES;
+        assert_eq!(validate_topup_currency("EUR", &supported), Ok(()));
+        assert_eq!(validate_topup_currency("chf", &supported), Ok(()));
+        assert_eq!(
+            validate_topup_currency("USD", &supported),
+            Err(TopupInputError::UnsupportedCurrency {
+                currency_code: "USD".to_string()
+            })
+        );
+
+        let supported = vec!["usd".to_string()];
+        assert_eq!(validate_topup_currency("USD", &supported), Ok(()));
+        assert_eq!(
+            validate_topup_currency("EUR", &supported),
+            Err(TopupInputError::UnsupportedCurrency {
+                currency_code: "EUR".to_string()
+            })
+        );
+    }
+
+    #[test]
+    fn test_validate_email() {
+        assert_eq!(validate_email("satoshi@example.com"), Ok(()));
+        assert_eq!(validate_email("satoshi.n+topup@mail.example.ch"), Ok(()));
+        for email in [
+            "",
+            "satoshi",
+            "@example.com",
+            "satoshi@",
+            "satoshi@localhost",
+            "satoshi@example..com",
+            "satoshi@-example.com",
+            ".satoshi@example.com",
+            "sat oshi@example.com",
+        ] {
+            assert_eq!(validate_email(email), Err(TopupInputError::InvalidEmail));
+        }
+    }
+}
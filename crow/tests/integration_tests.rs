@@ -1,11 +1,19 @@
 use bitcoin::Network;
-use crow::OfferManager;
-use honeybadger::secrets::{derive_keys, generate_keypair, generate_mnemonic};
+use crow::validation::{TopupInputError, TopupSetupError};
+use crow::{
+    asynchronous, payment_instructions, sign_topup_challenge, OfferManager, PermanentFailureCode,
+    TopupError, TopupFilter, TopupInfo, TopupOrder, TopupStatus,
+};
+use futures_util::StreamExt;
+use graphql::perro::Error;
+use graphql::{BackendClient, BackendClientConfig, GraphQlRuntimeErrorCode};
+use honeybadger::secrets::{derive_keys, generate_keypair, generate_mnemonic, DerivationScheme};
 use honeybadger::{Auth, AuthLevel};
 use isocountry::CountryCode;
 use isolanguage_1::LanguageCode;
-use std::env;
+use mockingbird::{Failure, MockBackend, Topup};
 use std::sync::Arc;
+use std::time::{Duration, SystemTime};
 
 #[test]
 fn test_query_uncompleted_topups() {
@@ -13,33 +21,522 @@ fn test_query_uncompleted_topups() {
     manager.query_uncompleted_topups().unwrap();
 }
 
+#[test]
+#[allow(deprecated)]
+fn test_deprecated_constructor() {
+    let auth = Auth::new(
+        get_backend_url(),
+        AuthLevel::Pseudonymous,
+        generate_keypair().unwrap(),
+        generate_keypair().unwrap(),
+    )
+    .unwrap();
+    let manager = OfferManager::new(get_backend_url(), Arc::new(auth));
+    manager.query_uncompleted_topups().unwrap();
+}
+
 #[test]
 fn test_register_notification_token() {
     let manager = build_offer_manager();
-    let notification_token = generate_keypair().public_key;
+    let notification_token = generate_keypair().unwrap().public_key;
     manager
         .register_notification_token(notification_token, LanguageCode::En, CountryCode::GBR)
         .unwrap();
 }
 
+#[test]
+fn test_reauthentication_on_rejected_token() {
+    let backend = MockBackend::start();
+    let manager = build_offer_manager_for(backend.url());
+    manager.query_uncompleted_topups().unwrap();
+    assert_eq!(backend.request_count("RefreshSession"), 0);
+
+    backend.revoke_access_tokens();
+    manager.query_uncompleted_topups().unwrap();
+    assert_eq!(backend.request_count("RefreshSession"), 1);
+    assert_eq!(backend.request_count("ListUncompletedTopups"), 3);
+
+    backend.fail_next(
+        "ListUncompletedTopups",
+        Failure::ErrorCode("authentication-exception".to_string()),
+    );
+    manager.query_uncompleted_topups().unwrap();
+    assert_eq!(backend.request_count("RefreshSession"), 2);
+
+    // The query is replayed only once.
+    backend.fail_next(
+        "ListUncompletedTopups",
+        Failure::ErrorCode("invalid-jwt".to_string()),
+    );
+    backend.fail_next(
+        "ListUncompletedTopups",
+        Failure::ErrorCode("invalid-jwt".to_string()),
+    );
+    let result = manager.query_uncompleted_topups();
+    assert!(matches!(
+        result,
+        Err(Error::RuntimeError {
+            code: GraphQlRuntimeErrorCode::AuthServiceError,
+            ..
+        })
+    ));
+    assert_eq!(backend.request_count("RefreshSession"), 3);
+}
+
+#[tokio::test]
+async fn test_async_offer_manager() {
+    let backend = MockBackend::start();
+    let (manager, wallet_pubkey_id) = build_async_offer_manager_for(backend.url());
+
+    let notification_token = generate_keypair().unwrap().public_key;
+    manager
+        .register_notification_token(notification_token, LanguageCode::De, CountryCode::CHE)
+        .await
+        .unwrap();
+
+    assert!(manager.query_uncompleted_topups().await.unwrap().is_empty());
+    backend.add_topup(&wallet_pubkey_id, Topup::new("topup-1", "READY"));
+    backend.add_topup(&wallet_pubkey_id, Topup::new("topup-2", "SETTLED"));
+    let topups = manager.query_uncompleted_topups().await.unwrap();
+    assert_eq!(topups.len(), 1);
+    assert_eq!(topups[0].id, "topup-1");
+    assert_eq!(topups[0].status, TopupStatus::READY);
+    assert_eq!(topups[0].topup_value_minor_units, 800);
+
+    let topup = manager.query_topup("topup-2".to_string()).await.unwrap();
+    assert_eq!(topup.status, TopupStatus::SETTLED);
+
+    manager.hide_topup("topup-1".to_string()).await.unwrap();
+    assert!(manager.query_uncompleted_topups().await.unwrap().is_empty());
+    let topup = manager.query_topup("topup-1".to_string()).await.unwrap();
+    assert_eq!(topup.status, TopupStatus::REFUND_HIDDEN);
+
+    // Topups of other wallets are not visible.
+    backend.add_topup("other-wallet", Topup::new("topup-3", "READY"));
+    let result = manager.query_topup("topup-3".to_string()).await;
+    assert!(matches!(
+        result,
+        Err(Error::RuntimeError {
+            code: GraphQlRuntimeErrorCode::ObjectNotFound,
+            ..
+        })
+    ));
+
+    let info = manager
+        .setup_fiat_topup(
+            &generate_keypair().unwrap(),
+            "pocket".to_string(),
+            "CH9300762011623852957".to_string(),
+            "chf".to_string(),
+            None,
+            None,
+        )
+        .await
+        .unwrap();
+    assert_eq!(info.currency, "CHF");
+}
+
+#[test]
+fn test_query_topup() {
+    let manager = build_offer_manager();
+    let result = manager.query_topup("3c6a2bd6-1d42-4d67-9e8a-5a1cfb2b0e0f".to_string());
+    assert!(matches!(
+        result,
+        Err(Error::RuntimeError {
+            code: GraphQlRuntimeErrorCode::ObjectNotFound,
+            ..
+        })
+    ));
+}
+
+#[test]
+fn test_setup_fiat_topup() {
+    let manager = build_offer_manager();
+    let node_keypair = generate_keypair().unwrap();
+    let info = manager
+        .setup_fiat_topup(
+            &node_keypair,
+            "pocket".to_string(),
+            "CH93 0076 2011 6238 5295 7".to_string(),
+            "eur".to_string(),
+            Some("satoshi@example.com".to_string()),
+            None,
+        )
+        .unwrap();
+    assert_eq!(info.debitor_iban, "CH9300762011623852957");
+    assert_eq!(info.currency, "EUR");
+    assert!(payment_instructions::to_epc_qr_code(&info, Some(10_000)).is_ok());
+    assert!(payment_instructions::to_swiss_qr_bill(&info, Some(10_000)).is_ok());
+
+    // The challenge has to be signed with the key of the node the setup was started for.
+    let challenge = manager
+        .start_topup_setup(
+            node_keypair.public_key,
+            "pocket".to_string(),
+            "CH9300762011623852957".to_string(),
+            "eur".to_string(),
+            None,
+            None,
+        )
+        .unwrap();
+    let signed_challenge =
+        sign_topup_challenge(&challenge.challenge, &generate_keypair().unwrap()).unwrap();
+    let result = manager.complete_topup_setup(
+        challenge.id,
+        signed_challenge,
+        "CH9300762011623852957".to_string(),
+    );
+    assert!(result.is_err());
+}
+
+#[test]
+fn test_register_topup() {
+    let manager = build_offer_manager();
+    let node_pubkey = generate_keypair().unwrap().public_key;
+    let challenge = manager
+        .start_topup_setup(
+            node_pubkey.clone(),
+            "pocket".to_string(),
+            "CH9300762011623852957".to_string(),
+            "chf".to_string(),
+            None,
+            None,
+        )
+        .unwrap();
+
+    let registration = manager
+        .register_topup(
+            challenge.id,
+            Some("satoshi@example.com".to_string()),
+            Some("REFERRAL".to_string()),
+        )
+        .unwrap();
+    assert_eq!(registration.node_pubkey, node_pubkey);
+    assert_eq!(registration.email.as_deref(), Some("satoshi@example.com"));
+    assert!(!registration.wallet_pubkey_id.is_empty());
+
+    let result = manager.register_topup("unknown-order".to_string(), None, None);
+    assert!(result.is_err());
+    let result = manager.register_topup("any-order".to_string(), Some("satoshi".to_string()), None);
+    assert_eq!(
+        result,
+        Err(TopupSetupError::InvalidInput(TopupInputError::InvalidEmail))
+    );
+}
+
+#[test]
+fn test_start_topup_setup_with_invalid_input() {
+    let backend = MockBackend::start();
+    let manager = build_offer_manager_for(backend.url());
+    let start_topup_setup = |source_iban: &str, user_currency: &str, email: Option<&str>| {
+        manager.start_topup_setup(
+            "02".repeat(33),
+            "pocket".to_string(),
+            source_iban.to_string(),
+            user_currency.to_string(),
+            email.map(String::from),
+            None,
+        )
+    };
+    for (source_iban, user_currency, email, error) in [
+        (
+            "CH93 0076 2011 6238 5295 8",
+            "chf",
+            None,
+            TopupInputError::IbanInvalidChecksum,
+        ),
+        (
+            "CH93 0076 2011 6238 5295 7",
+            "usd",
+            None,
+            TopupInputError::UnsupportedCurrency {
+                currency_code: "usd".to_string(),
+            },
+        ),
+        (
+            "CH93 0076 2011 6238 5295 7",
+            "chf",
+            Some("satoshi@"),
+            TopupInputError::InvalidEmail,
+        ),
+    ] {
+        let result = start_topup_setup(source_iban, user_currency, email);
+        assert_eq!(result.err(), Some(TopupSetupError::InvalidInput(error)));
+    }
+    assert_eq!(backend.request_count("StartTopupSetup"), 0);
+
+    let challenge = start_topup_setup("ch93 0076 2011 6238 5295 7", "chf", None).unwrap();
+    assert!(!challenge.id.is_empty());
+
+    let manager =
+        build_offer_manager_for(backend.url()).with_supported_currencies(vec!["EUR".to_string()]);
+    let result = manager.start_topup_setup(
+        "02".repeat(33),
+        "pocket".to_string(),
+        "CH93 0076 2011 6238 5295 7".to_string(),
+        "chf".to_string(),
+        None,
+        None,
+    );
+    assert_eq!(
+        result.err(),
+        Some(TopupSetupError::InvalidInput(
+            TopupInputError::UnsupportedCurrency {
+                currency_code: "chf".to_string()
+            }
+        ))
+    );
+    assert_eq!(backend.request_count("StartTopupSetup"), 1);
+}
+
+#[tokio::test]
+async fn test_topup_status_updates() {
+    let backend = MockBackend::start();
+    let (manager, wallet_pubkey_id) = build_async_offer_manager_for(backend.url());
+    let now = SystemTime::now();
+    let topup = |id: &str, created_at: SystemTime| Topup {
+        created_at,
+        ..Topup::new(id, "READY")
+    };
+    backend.add_topup(
+        &wallet_pubkey_id,
+        topup("topup-1", now - Duration::from_secs(60)),
+    );
+
+    let mut updates = manager.subscribe_to_topup_updates().await.unwrap();
+    assert_eq!(
+        next_update(&mut updates).await,
+        ("topup-1".to_string(), TopupStatus::READY)
+    );
+
+    backend.set_topup_status("topup-1", "SETTLED", None);
+    assert_eq!(
+        next_update(&mut updates).await,
+        ("topup-1".to_string(), TopupStatus::SETTLED)
+    );
+
+    backend.add_topup(&wallet_pubkey_id, topup("topup-2", now));
+    assert_eq!(
+        next_update(&mut updates).await,
+        ("topup-2".to_string(), TopupStatus::READY)
+    );
+
+    // Changes during network loss are caught up on without repeating earlier ones.
+    backend.disconnect_subscriptions();
+    backend.set_topup_status("topup-2", "FAILED", Some("no_route".to_string()));
+    assert_eq!(
+        next_update(&mut updates).await,
+        ("topup-2".to_string(), TopupStatus::FAILED)
+    );
+    assert_eq!(backend.request_count("TopupUpdates"), 2);
+
+    // Topups completed during network loss are queried once.
+    backend.disconnect_subscriptions();
+    backend.set_topup_status("topup-2", "REFUND_HIDDEN", None);
+    assert_eq!(
+        next_update(&mut updates).await,
+        ("topup-2".to_string(), TopupStatus::REFUND_HIDDEN)
+    );
+    assert_eq!(backend.request_count("GetTopup"), 2);
+
+    // The topup stream resumes from its cursor, topups created during network loss are
+    // yielded even if they completed already.
+    backend.disconnect_subscriptions();
+    backend.add_topup(&wallet_pubkey_id, Topup::new("topup-3", "SETTLED"));
+    assert_eq!(
+        next_update(&mut updates).await,
+        ("topup-3".to_string(), TopupStatus::SETTLED)
+    );
+    assert_eq!(backend.request_count("TopupStream"), 4);
+
+    // New topups are yielded once, although both the topup stream and the live query see them.
+    backend.add_topup(&wallet_pubkey_id, Topup::new("topup-4", "READY"));
+    assert_eq!(
+        next_update(&mut updates).await,
+        ("topup-4".to_string(), TopupStatus::READY)
+    );
+    backend.set_topup_status("topup-4", "SETTLED", None);
+    assert_eq!(
+        next_update(&mut updates).await,
+        ("topup-4".to_string(), TopupStatus::SETTLED)
+    );
+}
+
+#[tokio::test]
+async fn test_topup_history() {
+    let backend = MockBackend::start();
+    let (manager, wallet_pubkey_id) = build_async_offer_manager_for(backend.url());
+    let now = SystemTime::now();
+    let days_ago = |days: u64| now - Duration::from_secs(days * 24 * 60 * 60);
+    let topups = [
+        ("topup-1", "READY", "eur", days_ago(3), None),
+        ("topup-2", "SETTLED", "eur", days_ago(2), None),
+        (
+            "topup-3",
+            "REFUND_HIDDEN",
+            "chf",
+            days_ago(1),
+            Some("customer_requested"),
+        ),
+        ("topup-4", "FAILED", "eur", now, Some("no_route")),
+    ];
+    for (id, status, user_currency, created_at, additional_info) in topups {
+        let topup = Topup {
+            user_currency: user_currency.to_string(),
+            created_at,
+            additional_info: additional_info.map(String::from),
+            ..Topup::new(id, status)
+        };
+        backend.add_topup(&wallet_pubkey_id, topup);
+    }
+    let ids = |topups: Vec<TopupInfo>| topups.into_iter().map(|t| t.id).collect::<Vec<_>>();
+
+    let history = manager
+        .query_topup_history(TopupFilter::default(), TopupOrder::NewestFirst, None, 0)
+        .await
+        .unwrap();
+    assert_eq!(
+        ids(history.clone()),
+        ["topup-4", "topup-3", "topup-2", "topup-1"]
+    );
+    assert_eq!(history[1].status, TopupStatus::REFUND_HIDDEN);
+    assert_eq!(history[1].exchange_rate.currency_code, "CHF");
+    assert_eq!(
+        history[1].error,
+        Some(TopupError::PermanentFailure {
+            code: PermanentFailureCode::CustomerRequested
+        })
+    );
+
+    let filter = TopupFilter {
+        statuses: vec![TopupStatus::SETTLED, TopupStatus::REFUND_HIDDEN],
+        ..TopupFilter::default()
+    };
+    let history = manager
+        .query_topup_history(filter, TopupOrder::OldestFirst, None, 0)
+        .await
+        .unwrap();
+    assert_eq!(ids(history), ["topup-2", "topup-3"]);
+
+    let filter = TopupFilter {
+        created_from: Some(days_ago(2)),
+        created_until: Some(now),
+        ..TopupFilter::default()
+    };
+    let history = manager
+        .query_topup_history(filter, TopupOrder::OldestFirst, None, 0)
+        .await
+        .unwrap();
+    assert_eq!(ids(history), ["topup-2", "topup-3"]);
+
+    let filter = TopupFilter {
+        currency_code: Some("CHF".to_string()),
+        ..TopupFilter::default()
+    };
+    let history = manager
+        .query_topup_history(filter, TopupOrder::NewestFirst, None, 0)
+        .await
+        .unwrap();
+    assert_eq!(ids(history), ["topup-3"]);
+
+    // The currency code is matched exactly, not as a pattern.
+    let filter = TopupFilter {
+        currency_code: Some("%".to_string()),
+        ..TopupFilter::default()
+    };
+    let history = manager
+        .query_topup_history(filter, TopupOrder::NewestFirst, None, 0)
+        .await
+        .unwrap();
+    assert!(history.is_empty());
+
+    let history = manager
+        .query_topup_history(TopupFilter::default(), TopupOrder::NewestFirst, Some(2), 1)
+        .await
+        .unwrap();
+    assert_eq!(ids(history), ["topup-3", "topup-2"]);
+    let history = manager
+        .query_topup_history(TopupFilter::default(), TopupOrder::NewestFirst, Some(2), 4)
+        .await
+        .unwrap();
+    assert!(history.is_empty());
+}
+
+#[test]
+fn test_query_topup_history() {
+    let manager = build_offer_manager();
+    let filter = TopupFilter {
+        statuses: vec![TopupStatus::SETTLED],
+        created_from: Some(SystemTime::UNIX_EPOCH),
+        currency_code: Some("EUR".to_string()),
+        ..TopupFilter::default()
+    };
+    let history = manager
+        .query_topup_history(filter, TopupOrder::NewestFirst, Some(10), 0)
+        .unwrap();
+    assert!(history.iter().all(|t| t.status == TopupStatus::SETTLED));
+}
+
 fn build_offer_manager() -> OfferManager {
+    build_offer_manager_for(get_backend_url())
+}
+
+fn build_offer_manager_for(backend_url: String) -> OfferManager {
     println!("Generating keys ...");
-    let mnemonic = generate_mnemonic();
+    let mnemonic = generate_mnemonic(24).unwrap();
     println!("mnemonic: {mnemonic:?}");
-    let wallet_keys = derive_keys(Network::Testnet, mnemonic).wallet_keypair;
-    let auth_keys = generate_keypair();
+    let wallet_keys = derive_keys(
+        Network::Testnet,
+        mnemonic,
+        None,
+        &DerivationScheme::LegacyMasterKey,
+    )
+    .unwrap()
+    .wallet_keypair;
+    let auth_keys = generate_keypair().unwrap();
 
-    let auth = Auth::new(
-        get_backend_url(),
+    let client = BackendClient::new(backend_url, BackendClientConfig::default()).unwrap();
+    let auth = Auth::with_client(
+        client.clone(),
         AuthLevel::Pseudonymous,
         wallet_keys,
         auth_keys,
+    );
+    OfferManager::with_client(client.with_auth(Arc::new(auth)))
+}
+
+fn build_async_offer_manager_for(backend_url: String) -> (asynchronous::OfferManager, String) {
+    let mnemonic = generate_mnemonic(24).unwrap();
+    let wallet_keys = derive_keys(
+        Network::Testnet,
+        mnemonic,
+        None,
+        &DerivationScheme::LegacyMasterKey,
     )
-    .unwrap();
+    .unwrap()
+    .wallet_keypair;
+    let wallet_pubkey_id = mockingbird::wallet_pubkey_id(&wallet_keys.public_key);
+    let auth_keys = generate_keypair().unwrap();
+
+    let client =
+        graphql::asynchronous::BackendClient::new(backend_url, BackendClientConfig::default())
+            .unwrap();
+    let auth = honeybadger::asynchronous::Auth::with_client(
+        client.clone(),
+        AuthLevel::Pseudonymous,
+        wallet_keys,
+        auth_keys,
+    );
+    let manager = asynchronous::OfferManager::with_client(client.with_auth(Arc::new(auth)));
+    (manager, wallet_pubkey_id)
+}
 
-    OfferManager::new(get_backend_url(), Arc::new(auth))
+async fn next_update(updates: &mut asynchronous::TopupStatusUpdates) -> (String, TopupStatus) {
+    let update = tokio::time::timeout(Duration::from_secs(10), updates.next()).await;
+    let topup = update.unwrap().unwrap().unwrap();
+    (topup.id, topup.status)
 }
 
 fn get_backend_url() -> String {
-    env::var("GRAPHQL_API_URL").expect("GRAPHQL_API_URL environment variable is not set")
+    mockingbird::backend_url()
 }
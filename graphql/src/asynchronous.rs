@@ -0,0 +1,201 @@
+use crate::errors::*;
+use crate::retry::Attempts;
+use crate::subscription::subscribe;
+use crate::{ensure_not_502_status, get_response_data, map_transport_error, BackendClientConfig};
+use async_trait::async_trait;
+use graphql_client::{GraphQLQuery, QueryBody};
+use log::warn;
+use perro::MapToError;
+use reqwest::Client;
+use std::sync::Arc;
+
+pub use crate::subscription::Subscription;
+
+impl BackendClientConfig {
+    pub(crate) fn build_async_client(&self) -> Result<Client> {
+        let mut builder = Client::builder()
+            .user_agent(&self.user_agent)
+            .timeout(self.timeout)
+            .default_headers(self.build_default_headers()?);
+        if let Some(proxy) = self.build_proxy()? {
+            builder = builder.proxy(proxy);
+        }
+        builder
+            .build()
+            .map_to_permanent_failure("Failed to build a async reqwest client")
+    }
+}
+
+/// Provides access tokens for the requests of a [`BackendClient`].
+///
+/// Implemented by `honeybadger::asynchronous::Auth`.
+#[async_trait]
+pub trait AccessTokenProvider: Send + Sync {
+    async fn query_token(&self) -> Result<String>;
+
+    /// Gets a new access token because the backend rejected the given one.
+    ///
+    /// Concurrent requests may report the same rejected token, it should only be refreshed once.
+    async fn refresh_token(&self, rejected_token: &str) -> Result<String>;
+}
+
+/// Async client for the backend GraphQL API.
+///
+/// Cloning is cheap and clones share the connection pool.
+#[derive(Clone)]
+pub struct BackendClient {
+    backend_url: String,
+    client: Client,
+    auth: Option<Arc<dyn AccessTokenProvider>>,
+    /// Kept to open websockets for subscriptions with the same settings as the reqwest client.
+    config: BackendClientConfig,
+}
+
+impl BackendClient {
+    /// Builds a client without an access token provider, see [`BackendClient::with_auth`].
+    pub fn new(backend_url: String, config: BackendClientConfig) -> Result<Self> {
+        Ok(Self {
+            backend_url,
+            client: config.build_async_client()?,
+            auth: None,
+            config,
+        })
+    }
+
+    /// Builds a client with the default config, used by the deprecated constructors taking a url.
+    ///
+    /// # Panics
+    ///
+    /// Like [`reqwest::Client::new`] if the TLS backend cannot be initialized.
+    pub fn with_default_config(backend_url: String) -> Self {
+        Self::new(backend_url, BackendClientConfig::default())
+            .expect("Failed to build a client with the default config")
+    }
+
+    /// Wraps a reqwest client built by the caller, used by the deprecated [`crate::post`].
+    pub(crate) fn from_client(backend_url: String, client: Client) -> Self {
+        Self {
+            backend_url,
+            client,
+            auth: None,
+            config: BackendClientConfig::default(),
+        }
+    }
+
+    /// Returns a client sharing the connection pool which authenticates its requests.
+    pub fn with_auth(&self, auth: Arc<dyn AccessTokenProvider>) -> Self {
+        Self {
+            auth: Some(auth),
+            ..self.clone()
+        }
+    }
+
+    pub fn backend_url(&self) -> &str {
+        &self.backend_url
+    }
+
+    /// Posts the query, authenticated with a token of the access token provider if there is one.
+    ///
+    /// If the backend rejects the token, the token gets refreshed and the query replayed once.
+    pub async fn post<Query: GraphQLQuery>(
+        &self,
+        variables: Query::Variables,
+    ) -> Result<Query::ResponseData> {
+        let body = Query::build_query(variables);
+        let auth = match &self.auth {
+            Some(auth) => auth,
+            None => return self.post_body::<Query>(&body, None).await,
+        };
+        let access_token = auth.query_token().await?;
+        match self.post_body::<Query>(&body, Some(&access_token)).await {
+            Err(Error::RuntimeError {
+                code: GraphQlRuntimeErrorCode::AuthServiceError,
+                msg,
+            }) => {
+                warn!(
+                    "{} was rejected: {msg}, refreshing the access token",
+                    body.operation_name
+                );
+                let access_token = auth.refresh_token(&access_token).await?;
+                self.post_body::<Query>(&body, Some(&access_token)).await
+            }
+            result => result,
+        }
+    }
+
+    /// Posts the query, authenticated with the given token instead of the access token provider.
+    pub async fn post_with_token<Query: GraphQLQuery>(
+        &self,
+        access_token: Option<&str>,
+        variables: Query::Variables,
+    ) -> Result<Query::ResponseData> {
+        self.post_body::<Query>(&Query::build_query(variables), access_token)
+            .await
+    }
+
+    /// Subscribes to the query over a websocket using the graphql-transport-ws protocol,
+    /// authenticated with a token of the access token provider if there is one.
+    ///
+    /// If the backend rejects the token, the token gets refreshed and subscribing retried once.
+    pub async fn subscribe<Query: GraphQLQuery>(
+        &self,
+        variables: Query::Variables,
+    ) -> Result<Subscription<Query::ResponseData>>
+    where
+        Query::ResponseData: Send + 'static,
+    {
+        let body = Query::build_query(variables);
+        let auth = match &self.auth {
+            Some(auth) => auth,
+            None => return subscribe(&self.backend_url, None, &body, &self.config).await,
+        };
+        let access_token = auth.query_token().await?;
+        match subscribe(&self.backend_url, Some(&access_token), &body, &self.config).await {
+            Err(Error::RuntimeError {
+                code: GraphQlRuntimeErrorCode::AuthServiceError,
+                msg,
+            }) => {
+                warn!(
+                    "Subscription to {} was rejected: {msg}, refreshing the access token",
+                    body.operation_name
+                );
+                let access_token = auth.refresh_token(&access_token).await?;
+                subscribe(&self.backend_url, Some(&access_token), &body, &self.config).await
+            }
+            result => result,
+        }
+    }
+
+    async fn post_body<Query: GraphQLQuery>(
+        &self,
+        body: &QueryBody<Query::Variables>,
+        access_token: Option<&str>,
+    ) -> Result<Query::ResponseData> {
+        let mut attempts =
+            Attempts::new(&self.config.retry_policy, body.query, body.operation_name);
+        loop {
+            match self.send::<Query>(body, access_token).await {
+                Err(e) => match attempts.backoff_after(&e) {
+                    Some(backoff) => tokio::time::sleep(backoff).await,
+                    None => return Err(e),
+                },
+                result => return result,
+            }
+        }
+    }
+
+    async fn send<Query: GraphQLQuery>(
+        &self,
+        body: &QueryBody<Query::Variables>,
+        access_token: Option<&str>,
+    ) -> Result<Query::ResponseData> {
+        let mut request = self.client.post(&self.backend_url).json(body);
+        if let Some(access_token) = access_token {
+            request = request.bearer_auth(access_token);
+        }
+        let response = request.send().await.map_err(map_transport_error)?;
+        ensure_not_502_status(response.status())?;
+        let response = response.json().await.map_err(map_transport_error)?;
+        get_response_data(response, &self.backend_url)
+    }
+}
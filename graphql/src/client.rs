@@ -0,0 +1,238 @@
+use crate::errors::*;
+use crate::retry::Attempts;
+use crate::{ensure_not_502_status, get_response_data, map_transport_error, RetryPolicy};
+use graphql_client::{GraphQLQuery, QueryBody};
+use log::warn;
+use perro::{invalid_input, MapToError};
+use reqwest::blocking::Client;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use reqwest::Proxy;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Provides access tokens for the requests of a [`BackendClient`].
+///
+/// Implemented by `honeybadger::Auth`.
+pub trait AccessTokenProvider: Send + Sync {
+    fn query_token(&self) -> Result<String>;
+
+    /// Gets a new access token because the backend rejected the given one.
+    ///
+    /// Concurrent requests may report the same rejected token, it should only be refreshed once.
+    fn refresh_token(&self, rejected_token: &str) -> Result<String>;
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct BackendClientConfig {
+    pub user_agent: String,
+    pub timeout: Duration,
+    /// Url of a proxy all requests are sent through.
+    pub proxy: Option<String>,
+    pub default_headers: Vec<(String, String)>,
+    /// Set once for all requests of the client, by default requests are not retried.
+    pub retry_policy: RetryPolicy,
+}
+
+impl Default for BackendClientConfig {
+    fn default() -> Self {
+        Self {
+            user_agent: "graphql-rust/0.12.0".to_string(),
+            timeout: Duration::from_secs(20),
+            proxy: None,
+            default_headers: Vec::new(),
+            retry_policy: RetryPolicy::no_retry(),
+        }
+    }
+}
+
+impl BackendClientConfig {
+    pub(crate) fn build_default_headers(&self) -> Result<HeaderMap> {
+        let mut headers = HeaderMap::new();
+        for (name, value) in &self.default_headers {
+            let name = HeaderName::from_bytes(name.as_bytes())
+                .map_to_invalid_input(format!("Invalid header name: {name}"))?;
+            let value = HeaderValue::from_str(value)
+                .map_to_invalid_input(format!("Invalid value of header {name}"))?;
+            headers.insert(name, value);
+        }
+        Ok(headers)
+    }
+
+    pub(crate) fn build_client(&self) -> Result<Client> {
+        let mut builder = Client::builder()
+            .user_agent(&self.user_agent)
+            .timeout(self.timeout)
+            .default_headers(self.build_default_headers()?);
+        if let Some(proxy) = self.build_proxy()? {
+            builder = builder.proxy(proxy);
+        }
+        builder
+            .build()
+            .map_to_permanent_failure("Failed to build a reqwest client")
+    }
+
+    pub(crate) fn build_proxy(&self) -> Result<Option<Proxy>> {
+        match &self.proxy {
+            None => Ok(None),
+            Some(url) => match Proxy::all(url) {
+                Ok(proxy) => Ok(Some(proxy)),
+                Err(e) => invalid_input!("Invalid proxy url {url}: {e}"),
+            },
+        }
+    }
+}
+
+/// Blocking client for the backend GraphQL API.
+///
+/// Cloning is cheap and clones share the connection pool.
+#[derive(Clone)]
+pub struct BackendClient {
+    backend_url: String,
+    client: Client,
+    auth: Option<Arc<dyn AccessTokenProvider>>,
+    retry_policy: RetryPolicy,
+}
+
+impl BackendClient {
+    /// Builds a client without an access token provider, see [`BackendClient::with_auth`].
+    pub fn new(backend_url: String, config: BackendClientConfig) -> Result<Self> {
+        Ok(Self {
+            backend_url,
+            client: config.build_client()?,
+            auth: None,
+            retry_policy: config.retry_policy,
+        })
+    }
+
+    /// Builds a client with the default config, used by the deprecated constructors taking a url.
+    ///
+    /// # Panics
+    ///
+    /// Like [`reqwest::blocking::Client::new`] if the TLS backend cannot be initialized.
+    pub fn with_default_config(backend_url: String) -> Self {
+        Self::new(backend_url, BackendClientConfig::default())
+            .expect("Failed to build a client with the default config")
+    }
+
+    /// Wraps a reqwest client built by the caller, used by the deprecated [`crate::post_blocking`].
+    pub(crate) fn from_client(backend_url: String, client: Client) -> Self {
+        Self {
+            backend_url,
+            client,
+            auth: None,
+            retry_policy: RetryPolicy::no_retry(),
+        }
+    }
+
+    /// Returns a client sharing the connection pool which authenticates its requests.
+    pub fn with_auth(&self, auth: Arc<dyn AccessTokenProvider>) -> Self {
+        Self {
+            auth: Some(auth),
+            ..self.clone()
+        }
+    }
+
+    pub fn backend_url(&self) -> &str {
+        &self.backend_url
+    }
+
+    /// Posts the query, authenticated with a token of the access token provider if there is one.
+    ///
+    /// If the backend rejects the token, the token gets refreshed and the query replayed once.
+    pub fn post<Query: GraphQLQuery>(
+        &self,
+        variables: Query::Variables,
+    ) -> Result<Query::ResponseData> {
+        let body = Query::build_query(variables);
+        let auth = match &self.auth {
+            Some(auth) => auth,
+            None => return self.post_body::<Query>(&body, None),
+        };
+        let access_token = auth.query_token()?;
+        match self.post_body::<Query>(&body, Some(&access_token)) {
+            Err(Error::RuntimeError {
+                code: GraphQlRuntimeErrorCode::AuthServiceError,
+                msg,
+            }) => {
+                warn!(
+                    "{} was rejected: {msg}, refreshing the access token",
+                    body.operation_name
+                );
+                let access_token = auth.refresh_token(&access_token)?;
+                self.post_body::<Query>(&body, Some(&access_token))
+            }
+            result => result,
+        }
+    }
+
+    /// Posts the query, authenticated with the given token instead of the access token provider.
+    pub fn post_with_token<Query: GraphQLQuery>(
+        &self,
+        access_token: Option<&str>,
+        variables: Query::Variables,
+    ) -> Result<Query::ResponseData> {
+        self.post_body::<Query>(&Query::build_query(variables), access_token)
+    }
+
+    fn post_body<Query: GraphQLQuery>(
+        &self,
+        body: &QueryBody<Query::Variables>,
+        access_token: Option<&str>,
+    ) -> Result<Query::ResponseData> {
+        let mut attempts = Attempts::new(&self.retry_policy, body.query, body.operation_name);
+        loop {
+            match self.send::<Query>(body, access_token) {
+                Err(e) => match attempts.backoff_after(&e) {
+                    Some(backoff) => std::thread::sleep(backoff),
+                    None => return Err(e),
+                },
+                result => return result,
+            }
+        }
+    }
+
+    fn send<Query: GraphQLQuery>(
+        &self,
+        body: &QueryBody<Query::Variables>,
+        access_token: Option<&str>,
+    ) -> Result<Query::ResponseData> {
+        let mut request = self.client.post(&self.backend_url).json(body);
+        if let Some(access_token) = access_token {
+            request = request.bearer_auth(access_token);
+        }
+        let response = request.send().map_err(map_transport_error)?;
+        ensure_not_502_status(response.status())?;
+        let response = response.json().map_err(map_transport_error)?;
+        get_response_data(response, &self.backend_url)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_invalid_config() {
+        let url = "http://localhost:8080/v1/graphql".to_string();
+        let config = BackendClientConfig {
+            default_headers: vec![("X-Lipa-Client".to_string(), "wild".to_string())],
+            proxy: Some("http://localhost:3128".to_string()),
+            ..BackendClientConfig::default()
+        };
+        assert!(BackendClient::new(url.clone(), config).is_ok());
+
+        let config = BackendClientConfig {
+            default_headers: vec![("X Lipa Client".to_string(), "wild".to_string())],
+            ..BackendClientConfig::default()
+        };
+        let result = BackendClient::new(url.clone(), config);
+        assert!(matches!(result, Err(Error::InvalidInput { .. })));
+
+        let config = BackendClientConfig {
+            proxy: Some("not a url".to_string()),
+            ..BackendClientConfig::default()
+        };
+        let result = BackendClient::new(url, config);
+        assert!(matches!(result, Err(Error::InvalidInput { .. })));
+    }
+}
@@ -1,19 +1,22 @@
+pub mod asynchronous;
+mod client;
 pub mod errors;
+mod retry;
 pub mod schema;
+mod subscription;
 
+pub use crate::client::{AccessTokenProvider, BackendClient, BackendClientConfig};
 pub use crate::errors::*;
+pub use crate::retry::RetryPolicy;
 
 pub use perro;
 pub use reqwest;
 
 use chrono::{DateTime, Utc};
-use graphql_client::reqwest::{post_graphql, post_graphql_blocking};
-use graphql_client::Response;
+use graphql_client::{GraphQLQuery, Response};
 use perro::{invalid_input, permanent_failure, runtime_error, MapToError, OptionToError};
-use reqwest::blocking::Client;
-use reqwest::header::{HeaderValue, AUTHORIZATION};
 use reqwest::StatusCode;
-use std::time::{Duration, SystemTime};
+use std::time::SystemTime;
 
 #[derive(PartialEq, Eq, Debug, Clone)]
 pub struct ExchangeRate {
@@ -22,88 +25,47 @@ pub struct ExchangeRate {
     pub updated_at: SystemTime,
 }
 
-pub fn build_client(access_token: Option<&str>) -> Result<Client> {
-    let user_agent = "graphql-rust/0.12.0";
-    let timeout = Some(Duration::from_secs(20));
-
-    let mut builder = Client::builder().user_agent(user_agent).timeout(timeout);
-    if let Some(access_token) = access_token {
-        let value = HeaderValue::from_str(&format!("Bearer {access_token}"))
-            .map_to_permanent_failure("Failed to build header value from str")?;
-        builder = builder.default_headers(std::iter::once((AUTHORIZATION, value)).collect());
-    }
-
-    let client = builder
-        .build()
-        .map_to_permanent_failure("Failed to build a reqwest client")?;
-    Ok(client)
+#[deprecated(note = "Use BackendClient, which keeps its connection pool between requests")]
+pub fn build_client(access_token: Option<&str>) -> Result<reqwest::blocking::Client> {
+    bearer_config(access_token).build_client()
 }
 
+#[deprecated(
+    note = "Use asynchronous::BackendClient, which keeps its connection pool between requests"
+)]
 pub fn build_async_client(access_token: Option<&str>) -> Result<reqwest::Client> {
-    let user_agent = "graphql-rust/0.12.0";
-    let timeout = Duration::from_secs(20);
-
-    let mut builder = reqwest::Client::builder()
-        .user_agent(user_agent)
-        .timeout(timeout);
-    if let Some(access_token) = access_token {
-        let value = HeaderValue::from_str(&format!("Bearer {access_token}"))
-            .map_to_permanent_failure("Failed to build header value from str")?;
-        builder = builder.default_headers(std::iter::once((AUTHORIZATION, value)).collect());
-    }
-
-    let client = builder
-        .build()
-        .map_to_permanent_failure("Failed to build a async reqwest client")?;
-    Ok(client)
+    bearer_config(access_token).build_async_client()
 }
 
-pub fn post_blocking<Query: graphql_client::GraphQLQuery>(
-    client: &Client,
+#[deprecated(note = "Use BackendClient::post")]
+pub fn post_blocking<Query: GraphQLQuery>(
+    client: &reqwest::blocking::Client,
     backend_url: &str,
     variables: Query::Variables,
 ) -> Result<Query::ResponseData> {
-    let response = match post_graphql_blocking::<Query, _>(client, backend_url, variables) {
-        Ok(r) => r,
-        Err(e) => {
-            if is_502_status(e.status()) || e.to_string().contains("502") {
-                // checking for the error containing 502 because reqwest is unexpectedly returning a decode error instead of status error
-                runtime_error!(
-                    GraphQlRuntimeErrorCode::RemoteServiceUnavailable,
-                    "The remote server returned status 502",
-                );
-            }
-            runtime_error!(
-                GraphQlRuntimeErrorCode::NetworkError,
-                "Failed to execute the query",
-            );
-        }
-    };
-    get_response_data(response, backend_url)
+    BackendClient::from_client(backend_url.to_string(), client.clone()).post::<Query>(variables)
 }
 
-pub async fn post<Query: graphql_client::GraphQLQuery>(
+#[deprecated(note = "Use asynchronous::BackendClient::post")]
+pub async fn post<Query: GraphQLQuery>(
     client: &reqwest::Client,
     backend_url: &str,
     variables: Query::Variables,
 ) -> Result<Query::ResponseData> {
-    let response = match post_graphql::<Query, _>(client, backend_url, variables).await {
-        Ok(r) => r,
-        Err(e) => {
-            if is_502_status(e.status()) || e.to_string().contains("502") {
-                // checking for the error containing 502 because reqwest is unexpectedly returning a decode error instead of status error
-                runtime_error!(
-                    GraphQlRuntimeErrorCode::RemoteServiceUnavailable,
-                    "The remote server returned status 502",
-                );
-            }
-            runtime_error!(
-                GraphQlRuntimeErrorCode::NetworkError,
-                "Failed to execute the query",
-            );
-        }
-    };
-    get_response_data(response, backend_url)
+    asynchronous::BackendClient::from_client(backend_url.to_string(), client.clone())
+        .post::<Query>(variables)
+        .await
+}
+
+fn bearer_config(access_token: Option<&str>) -> BackendClientConfig {
+    let default_headers = access_token
+        .map(|token| ("Authorization".to_string(), format!("Bearer {token}")))
+        .into_iter()
+        .collect();
+    BackendClientConfig {
+        default_headers,
+        ..BackendClientConfig::default()
+    }
 }
 
 pub fn parse_from_rfc3339(rfc3339: &str) -> Result<SystemTime> {
@@ -121,7 +83,35 @@ fn is_502_status(status: Option<StatusCode>) -> bool {
     }
 }
 
-fn get_response_data<Data>(response: Response<Data>, backend_url: &str) -> Result<Data> {
+/// Requests are sent with reqwest directly instead of `graphql_client::reqwest::post_graphql*`,
+/// which decodes the body before looking at the status. A 502 from a proxy or load balancer
+/// has no JSON body and surfaced as a decode error, i.e. as `NetworkError`. Sending directly also
+/// allows setting the bearer token per request on a shared client.
+pub(crate) fn ensure_not_502_status(status: StatusCode) -> Result<()> {
+    if is_502_status(Some(status)) {
+        runtime_error!(
+            GraphQlRuntimeErrorCode::RemoteServiceUnavailable,
+            "The remote server returned status 502",
+        );
+    }
+    Ok(())
+}
+
+pub(crate) fn map_transport_error(e: reqwest::Error) -> Error {
+    if is_502_status(e.status()) || e.to_string().contains("502") {
+        // checking for the error containing 502 because reqwest is unexpectedly returning a decode error instead of status error
+        return runtime_error(
+            GraphQlRuntimeErrorCode::RemoteServiceUnavailable,
+            "The remote server returned status 502",
+        );
+    }
+    runtime_error(
+        GraphQlRuntimeErrorCode::NetworkError,
+        "Failed to execute the query",
+    )
+}
+
+pub(crate) fn get_response_data<Data>(response: Response<Data>, backend_url: &str) -> Result<Data> {
     if let Some(errors) = response.errors {
         let error = errors
             .first()
@@ -191,7 +181,7 @@ fn map_error_code(code: &str) -> Error {
 mod tests {
     use std::time::SystemTime;
 
-    use crate::parse_from_rfc3339;
+    use crate::{bearer_config, parse_from_rfc3339};
 
     #[test]
     fn test_parse_from_rfc3339() {
@@ -216,4 +206,16 @@ mod tests {
             .as_secs();
         assert_eq!(timestamp, 1695314361 + 2 * 3600);
     }
+
+    #[test]
+    #[allow(deprecated)]
+    fn test_deprecated_client_builders() {
+        assert_eq!(
+            bearer_config(Some("token")).default_headers,
+            vec![("Authorization".to_string(), "Bearer token".to_string())]
+        );
+        assert!(bearer_config(None).default_headers.is_empty());
+        assert!(crate::build_client(Some("token")).is_ok());
+        assert!(crate::build_async_client(None).is_ok());
+    }
 }
@@ -0,0 +1,236 @@
+use crate::errors::*;
+use log::warn;
+use rand::Rng;
+use std::collections::HashMap;
+use std::time::Duration;
+
+/// Policy for retrying requests which failed with a transient error.
+///
+/// Operations which are not idempotent are never retried, by default queries
+/// are considered idempotent and mutations are not.
+///
+/// Configured on a [`BackendClient`](crate::BackendClient) through
+/// [`BackendClientConfig::retry_policy`](crate::BackendClientConfig::retry_policy).
+#[derive(Clone, Debug, PartialEq)]
+pub struct RetryPolicy {
+    /// Maximum number of attempts including the first one.
+    pub max_attempts: u32,
+    /// Backoff before the first retry, doubled for every subsequent retry.
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    /// Randomize the backoff between zero and its computed value.
+    pub jitter: bool,
+    pub retryable_error_codes: Vec<GraphQlRuntimeErrorCode>,
+    /// Overrides the idempotency of operations by operation name (e.g. `HideTopup`).
+    pub idempotent_operations: HashMap<String, bool>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            initial_backoff: Duration::from_millis(250),
+            max_backoff: Duration::from_secs(5),
+            jitter: true,
+            retryable_error_codes: vec![
+                GraphQlRuntimeErrorCode::NetworkError,
+                GraphQlRuntimeErrorCode::RemoteServiceUnavailable,
+            ],
+            idempotent_operations: HashMap::new(),
+        }
+    }
+}
+
+impl RetryPolicy {
+    pub fn no_retry() -> Self {
+        Self {
+            max_attempts: 1,
+            ..Self::default()
+        }
+    }
+
+    pub fn with_idempotency(mut self, operation_name: &str, idempotent: bool) -> Self {
+        self.idempotent_operations
+            .insert(operation_name.to_string(), idempotent);
+        self
+    }
+
+    pub(crate) fn is_idempotent(&self, query: &str, operation_name: &str) -> bool {
+        match self.idempotent_operations.get(operation_name) {
+            Some(idempotent) => *idempotent,
+            None => !is_mutation(query, operation_name),
+        }
+    }
+
+    /// Returns the backoff to wait before the next attempt or `None` if the
+    /// failed attempt must not be retried.
+    pub(crate) fn backoff_before_retry(
+        &self,
+        error: &Error,
+        attempt: u32,
+        idempotent: bool,
+        operation_name: &str,
+    ) -> Option<Duration> {
+        let code = match error {
+            Error::RuntimeError { code, .. } => code,
+            _ => return None,
+        };
+        let retryable = idempotent && self.retryable_error_codes.contains(code);
+        if !retryable || attempt >= self.max_attempts {
+            return None;
+        }
+
+        let backoff = self
+            .initial_backoff
+            .saturating_mul(2u32.saturating_pow(attempt - 1))
+            .min(self.max_backoff);
+        let backoff = if self.jitter {
+            rand::thread_rng().gen_range(Duration::ZERO..=backoff)
+        } else {
+            backoff
+        };
+        warn!(
+            "Attempt {attempt}/{} of {operation_name} failed: {error}, retrying in {backoff:?}",
+            self.max_attempts
+        );
+        Some(backoff)
+    }
+}
+
+/// Counts the attempts of a request and decides whether and when to retry it.
+pub(crate) struct Attempts<'a> {
+    policy: &'a RetryPolicy,
+    operation_name: &'a str,
+    idempotent: bool,
+    attempt: u32,
+}
+
+impl<'a> Attempts<'a> {
+    pub(crate) fn new(policy: &'a RetryPolicy, query: &str, operation_name: &'a str) -> Self {
+        Self {
+            policy,
+            operation_name,
+            idempotent: policy.is_idempotent(query, operation_name),
+            attempt: 1,
+        }
+    }
+
+    /// Returns the backoff to wait before retrying after the failed attempt or `None`
+    /// if the request must not be retried.
+    pub(crate) fn backoff_after(&mut self, error: &Error) -> Option<Duration> {
+        let backoff = self.policy.backoff_before_retry(
+            error,
+            self.attempt,
+            self.idempotent,
+            self.operation_name,
+        );
+        self.attempt += 1;
+        backoff
+    }
+}
+
+// The query string may contain the whole GraphQL document, not just the operation.
+fn is_mutation(query: &str, operation_name: &str) -> bool {
+    let definition = format!("mutation {operation_name}");
+    query.match_indices(&definition).any(|(i, _)| {
+        !query[i + definition.len()..].starts_with(|c: char| c.is_ascii_alphanumeric() || c == '_')
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use perro::{permanent_failure, runtime_error};
+
+    const DOCUMENT: &str = "query HideTopupStatus { a }\n\nmutation HideTopup($id: String!) { b }\n\nquery ListTopups { c }";
+
+    #[test]
+    fn test_idempotency() {
+        let policy = RetryPolicy::default();
+        assert!(!policy.is_idempotent(DOCUMENT, "HideTopup"));
+        assert!(policy.is_idempotent(DOCUMENT, "HideTopupStatus"));
+        assert!(policy.is_idempotent(DOCUMENT, "ListTopups"));
+
+        let policy = policy
+            .with_idempotency("HideTopup", true)
+            .with_idempotency("ListTopups", false);
+        assert!(policy.is_idempotent(DOCUMENT, "HideTopup"));
+        assert!(!policy.is_idempotent(DOCUMENT, "ListTopups"));
+    }
+
+    #[test]
+    fn test_backoff_before_retry() {
+        let policy = RetryPolicy {
+            jitter: false,
+            ..RetryPolicy::default()
+        };
+        let network_error = runtime_error(GraphQlRuntimeErrorCode::NetworkError, "");
+
+        assert_eq!(
+            policy.backoff_before_retry(&network_error, 1, true, "Op"),
+            Some(Duration::from_millis(250))
+        );
+        assert_eq!(
+            policy.backoff_before_retry(&network_error, 2, true, "Op"),
+            Some(Duration::from_millis(500))
+        );
+        assert_eq!(
+            policy.backoff_before_retry(&network_error, 3, true, "Op"),
+            None
+        );
+        assert_eq!(
+            policy.backoff_before_retry(&network_error, 1, false, "Op"),
+            None
+        );
+
+        let auth_error = runtime_error(GraphQlRuntimeErrorCode::AuthServiceError, "");
+        assert_eq!(
+            policy.backoff_before_retry(&auth_error, 1, true, "Op"),
+            None
+        );
+        assert_eq!(
+            policy.backoff_before_retry(&permanent_failure(""), 1, true, "Op"),
+            None
+        );
+
+        let policy = RetryPolicy {
+            max_attempts: 10,
+            ..RetryPolicy::default()
+        };
+        for attempt in 1..10 {
+            let backoff = policy
+                .backoff_before_retry(&network_error, attempt, true, "Op")
+                .unwrap();
+            assert!(backoff <= policy.max_backoff);
+        }
+
+        let policy = RetryPolicy::no_retry();
+        assert_eq!(
+            policy.backoff_before_retry(&network_error, 1, true, "Op"),
+            None
+        );
+    }
+
+    #[test]
+    fn test_attempts() {
+        let policy = RetryPolicy {
+            jitter: false,
+            ..RetryPolicy::default()
+        };
+        let network_error = runtime_error(GraphQlRuntimeErrorCode::NetworkError, "");
+
+        let mut attempts = Attempts::new(&policy, DOCUMENT, "ListTopups");
+        assert_eq!(
+            attempts.backoff_after(&network_error),
+            Some(Duration::from_millis(250))
+        );
+        assert_eq!(
+            attempts.backoff_after(&network_error),
+            Some(Duration::from_millis(500))
+        );
+        assert_eq!(attempts.backoff_after(&network_error), None);
+
+        let mut attempts = Attempts::new(&policy, DOCUMENT, "HideTopup");
+        assert_eq!(attempts.backoff_after(&network_error), None);
+    }
+}
@@ -2,6 +2,8 @@ use graphql_client::GraphQLQuery;
 
 type DateTime = String;
 #[allow(non_camel_case_types)]
+type bpchar = String;
+#[allow(non_camel_case_types)]
 type numeric = float8;
 #[allow(non_camel_case_types)]
 type timestamptz = String;
@@ -121,6 +123,30 @@ pub struct GetAllExchangeRates;
 )]
 pub struct ListCurrencyCodes;
 
+#[derive(GraphQLQuery)]
+#[graphql(
+    schema_path = "schemas/schema_wallet_read.graphql",
+    query_path = "schemas/operations.graphql",
+    response_derives = "Debug"
+)]
+pub struct CurrencyStream;
+
+#[derive(GraphQLQuery)]
+#[graphql(
+    schema_path = "schemas/schema_wallet_read.graphql",
+    query_path = "schemas/operations.graphql",
+    response_derives = "Debug"
+)]
+pub struct ListCountries;
+
+#[derive(GraphQLQuery)]
+#[graphql(
+    schema_path = "schemas/schema_wallet_read.graphql",
+    query_path = "schemas/operations.graphql",
+    response_derives = "Debug"
+)]
+pub struct GetCountry;
+
 #[allow(non_camel_case_types)]
 type bigint = u64;
 type BigInteger = bigint;
@@ -135,6 +161,40 @@ type float8 = f64;
 )]
 pub struct ListUncompletedTopups;
 
+#[derive(GraphQLQuery)]
+#[graphql(
+    schema_path = "schemas/schema_wallet_read.graphql",
+    query_path = "schemas/operations.graphql",
+    response_derives = "Debug",
+    variables_derives = "Default",
+    skip_serializing_none
+)]
+pub struct ListTopups;
+
+#[derive(GraphQLQuery)]
+#[graphql(
+    schema_path = "schemas/schema_wallet_read.graphql",
+    query_path = "schemas/operations.graphql",
+    response_derives = "Debug"
+)]
+pub struct GetTopup;
+
+#[derive(GraphQLQuery)]
+#[graphql(
+    schema_path = "schemas/schema_wallet_read.graphql",
+    query_path = "schemas/operations.graphql",
+    response_derives = "Debug"
+)]
+pub struct TopupUpdates;
+
+#[derive(GraphQLQuery)]
+#[graphql(
+    schema_path = "schemas/schema_wallet_read.graphql",
+    query_path = "schemas/operations.graphql",
+    response_derives = "Debug"
+)]
+pub struct TopupStream;
+
 #[derive(GraphQLQuery)]
 #[graphql(
     schema_path = "schemas/schema_wallet_read.graphql",
@@ -0,0 +1,333 @@
+use crate::errors::*;
+use crate::{get_response_data, BackendClientConfig};
+use base64::Engine;
+use futures_util::stream::{self, BoxStream};
+use futures_util::{SinkExt, StreamExt};
+use graphql_client::{QueryBody, Response};
+use perro::{ensure, invalid_input, permanent_failure, runtime_error, MapToError};
+use reqwest::Url;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::http::header::{SEC_WEBSOCKET_PROTOCOL, USER_AGENT};
+use tokio_tungstenite::tungstenite::http::{HeaderValue, Uri};
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::{client_async_tls, connect_async, MaybeTlsStream, WebSocketStream};
+
+/// Subprotocol of <https://github.com/enisdenjo/graphql-ws/blob/master/PROTOCOL.md>.
+const PROTOCOL: &str = "graphql-transport-ws";
+/// Only one subscription is multiplexed over a connection.
+const SUBSCRIPTION_ID: &str = "1";
+/// Close code the server uses if the connection init was rejected.
+const FORBIDDEN_CLOSE_CODE: u16 = 4403;
+const UNAUTHORIZED_CLOSE_CODE: u16 = 4401;
+/// Limit of the status line and headers the proxy answers a CONNECT request with.
+const MAX_PROXY_RESPONSE_LENGTH: usize = 8 * 1024;
+
+/// Stream of the results of a GraphQL subscription.
+///
+/// The stream ends after the first error or when the backend completes the subscription.
+/// Dropping it closes the underlying websocket.
+pub type Subscription<Data> = BoxStream<'static, Result<Data>>;
+
+type Socket = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ClientMessage<'a, Variables> {
+    ConnectionInit {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        payload: Option<Value>,
+    },
+    Pong,
+    Subscribe {
+        id: &'a str,
+        payload: &'a QueryBody<Variables>,
+    },
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ServerMessage {
+    ConnectionAck,
+    Ping,
+    Pong,
+    Next { payload: Value },
+    Error { payload: Value },
+    Complete,
+}
+
+/// Opens a websocket to the backend and subscribes to the query.
+pub(crate) async fn subscribe<Variables: Serialize, Data: DeserializeOwned + Send + 'static>(
+    backend_url: &str,
+    access_token: Option<&str>,
+    body: &QueryBody<Variables>,
+    config: &BackendClientConfig,
+) -> Result<Subscription<Data>> {
+    let connecting = connect(backend_url, access_token, config);
+    let mut socket = match tokio::time::timeout(config.timeout, connecting).await {
+        Ok(socket) => socket?,
+        Err(_) => runtime_error!(
+            GraphQlRuntimeErrorCode::NetworkError,
+            "Timed out connecting to the backend websocket"
+        ),
+    };
+    send(
+        &mut socket,
+        &ClientMessage::Subscribe {
+            id: SUBSCRIPTION_ID,
+            payload: body,
+        },
+    )
+    .await?;
+
+    let backend_url = backend_url.to_string();
+    let results = stream::unfold(Some(socket), move |socket| {
+        let backend_url = backend_url.clone();
+        async move {
+            let mut socket = socket?;
+            let result = match receive(&mut socket).await {
+                Ok(ServerMessage::Next { payload }) => {
+                    match serde_json::from_value::<Response<Data>>(payload) {
+                        Ok(response) => get_response_data(response, &backend_url),
+                        Err(e) => Err(runtime_error(
+                            GraphQlRuntimeErrorCode::CorruptData,
+                            format!("Invalid subscription payload: {e}"),
+                        )),
+                    }
+                }
+                Ok(ServerMessage::Error { payload }) => Err(to_error(payload, &backend_url)),
+                Ok(ServerMessage::Complete) => return None,
+                Ok(_) => Err(runtime_error(
+                    GraphQlRuntimeErrorCode::CorruptData,
+                    "Unexpected message on an established subscription",
+                )),
+                Err(e) => Err(e),
+            };
+            match result {
+                Ok(data) => Some((Ok(data), Some(socket))),
+                Err(e) => {
+                    let _ = socket.close(None).await;
+                    Some((Err(e), None))
+                }
+            }
+        }
+    });
+    Ok(results.boxed())
+}
+
+async fn connect(
+    backend_url: &str,
+    access_token: Option<&str>,
+    config: &BackendClientConfig,
+) -> Result<Socket> {
+    let mut request = to_websocket_url(backend_url)?
+        .into_client_request()
+        .map_to_invalid_input("Invalid backend url")?;
+    let headers = request.headers_mut();
+    headers.extend(config.build_default_headers()?);
+    let user_agent = HeaderValue::from_str(&config.user_agent)
+        .map_to_invalid_input(format!("Invalid user agent: {}", config.user_agent))?;
+    headers.insert(USER_AGENT, user_agent);
+    headers.insert(SEC_WEBSOCKET_PROTOCOL, HeaderValue::from_static(PROTOCOL));
+
+    let connected = match &config.proxy {
+        Some(proxy) => {
+            let stream = connect_through_proxy(proxy, request.uri()).await?;
+            client_async_tls(request, stream).await
+        }
+        None => connect_async(request).await,
+    };
+    let (mut socket, _) = connected.map_to_runtime_error(
+        GraphQlRuntimeErrorCode::NetworkError,
+        "Failed to connect to the backend websocket",
+    )?;
+
+    let payload =
+        access_token.map(|t| json!({ "headers": { "Authorization": format!("Bearer {t}") } }));
+    let message: ClientMessage<()> = ClientMessage::ConnectionInit { payload };
+    send(&mut socket, &message).await?;
+    match receive(&mut socket).await? {
+        ServerMessage::ConnectionAck => Ok(socket),
+        _ => runtime_error!(
+            GraphQlRuntimeErrorCode::CorruptData,
+            "Backend did not acknowledge the websocket connection"
+        ),
+    }
+}
+
+/// Opens a tunnel to the backend through an HTTP proxy using the CONNECT method.
+async fn connect_through_proxy(proxy: &str, uri: &Uri) -> Result<TcpStream> {
+    let proxy = Url::parse(proxy).map_to_invalid_input(format!("Invalid proxy url {proxy}"))?;
+    ensure!(
+        proxy.scheme() == "http",
+        invalid_input(format!("Unsupported proxy scheme {}", proxy.scheme()))
+    );
+    let proxy_address = match (proxy.host_str(), proxy.port_or_known_default()) {
+        (Some(host), Some(port)) => format!("{host}:{port}"),
+        _ => invalid_input!("Invalid proxy url {proxy}"),
+    };
+    let target = match (uri.host(), uri.port_u16(), uri.scheme_str()) {
+        (Some(host), Some(port), _) => format!("{host}:{port}"),
+        (Some(host), None, Some("wss")) => format!("{host}:443"),
+        (Some(host), None, _) => format!("{host}:80"),
+        (None, _, _) => invalid_input!("Invalid backend url {uri}"),
+    };
+
+    let mut stream = TcpStream::connect(&proxy_address)
+        .await
+        .map_to_runtime_error(
+            GraphQlRuntimeErrorCode::NetworkError,
+            format!("Failed to connect to the proxy {proxy_address}"),
+        )?;
+    let mut connect = format!("CONNECT {target} HTTP/1.1\r\nHost: {target}\r\n");
+    if !proxy.username().is_empty() {
+        let credentials = format!(
+            "{}:{}",
+            proxy.username(),
+            proxy.password().unwrap_or_default()
+        );
+        let credentials = base64::engine::general_purpose::STANDARD.encode(credentials);
+        connect.push_str(&format!("Proxy-Authorization: Basic {credentials}\r\n"));
+    }
+    connect.push_str("\r\n");
+    stream
+        .write_all(connect.as_bytes())
+        .await
+        .map_to_runtime_error(
+            GraphQlRuntimeErrorCode::NetworkError,
+            "Failed to send to the proxy",
+        )?;
+
+    // Read byte by byte to not consume anything the backend sends through the tunnel.
+    let mut response = Vec::new();
+    while !response.ends_with(b"\r\n\r\n") {
+        ensure!(
+            response.len() < MAX_PROXY_RESPONSE_LENGTH,
+            runtime_error(
+                GraphQlRuntimeErrorCode::NetworkError,
+                "The proxy response is too long",
+            )
+        );
+        let byte = stream.read_u8().await.map_to_runtime_error(
+            GraphQlRuntimeErrorCode::NetworkError,
+            "Failed to receive from the proxy",
+        )?;
+        response.push(byte);
+    }
+    let response = String::from_utf8_lossy(&response);
+    let status_line = response.lines().next().unwrap_or_default();
+    ensure!(
+        status_line.split_whitespace().nth(1) == Some("200"),
+        runtime_error(
+            GraphQlRuntimeErrorCode::NetworkError,
+            format!("The proxy refused to connect to {target}: {status_line}"),
+        )
+    );
+    Ok(stream)
+}
+
+async fn send<Variables: Serialize>(
+    socket: &mut Socket,
+    message: &ClientMessage<'_, Variables>,
+) -> Result<()> {
+    let message = serde_json::to_string(message)
+        .map_to_permanent_failure("Failed to serialize a websocket message")?;
+    socket
+        .send(Message::Text(message))
+        .await
+        .map_to_runtime_error(
+            GraphQlRuntimeErrorCode::NetworkError,
+            "Failed to send to the backend websocket",
+        )
+}
+
+/// Receives the next protocol message, answering pings in the meantime.
+async fn receive(socket: &mut Socket) -> Result<ServerMessage> {
+    loop {
+        let message = match socket.next().await {
+            Some(message) => message.map_to_runtime_error(
+                GraphQlRuntimeErrorCode::NetworkError,
+                "Failed to receive from the backend websocket",
+            )?,
+            None => runtime_error!(
+                GraphQlRuntimeErrorCode::NetworkError,
+                "The backend websocket is closed"
+            ),
+        };
+        match message {
+            Message::Text(text) => match serde_json::from_str(&text) {
+                Ok(ServerMessage::Ping) => send(socket, &ClientMessage::<'_, ()>::Pong).await?,
+                Ok(ServerMessage::Pong) => {}
+                Ok(message) => return Ok(message),
+                Err(e) => runtime_error!(
+                    GraphQlRuntimeErrorCode::CorruptData,
+                    "Invalid websocket message from the backend: {e}"
+                ),
+            },
+            Message::Close(frame) => {
+                let code = frame.as_ref().map(|f| u16::from(f.code));
+                let reason = frame.map(|f| f.reason.to_string()).unwrap_or_default();
+                match code {
+                    Some(FORBIDDEN_CLOSE_CODE | UNAUTHORIZED_CLOSE_CODE) => runtime_error!(
+                        GraphQlRuntimeErrorCode::AuthServiceError,
+                        "The backend rejected the websocket connection: {reason}"
+                    ),
+                    _ => runtime_error!(
+                        GraphQlRuntimeErrorCode::NetworkError,
+                        "The backend closed the websocket ({code:?}): {reason}"
+                    ),
+                }
+            }
+            // Websocket pings are answered by tungstenite.
+            _ => {}
+        }
+    }
+}
+
+fn to_error(errors: Value, backend_url: &str) -> Error {
+    match serde_json::from_value::<Response<Value>>(json!({ "errors": errors })) {
+        Ok(response) => match get_response_data(response, backend_url) {
+            Ok(_) => permanent_failure("Unexpected backend response: errors empty"),
+            Err(e) => e,
+        },
+        Err(e) => runtime_error(
+            GraphQlRuntimeErrorCode::CorruptData,
+            format!("Invalid subscription error: {e}"),
+        ),
+    }
+}
+
+fn to_websocket_url(backend_url: &str) -> Result<String> {
+    if let Some(rest) = backend_url.strip_prefix("https://") {
+        Ok(format!("wss://{rest}"))
+    } else if let Some(rest) = backend_url.strip_prefix("http://") {
+        Ok(format!("ws://{rest}"))
+    } else {
+        invalid_input!("Backend url is not an http(s) url: {backend_url}")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_to_websocket_url() {
+        assert_eq!(
+            to_websocket_url("https://backend.example.com/v1/graphql").unwrap(),
+            "wss://backend.example.com/v1/graphql"
+        );
+        assert_eq!(
+            to_websocket_url("http://localhost:8080/v1/graphql").unwrap(),
+            "ws://localhost:8080/v1/graphql"
+        );
+        assert!(matches!(
+            to_websocket_url("localhost:8080"),
+            Err(Error::InvalidInput { .. })
+        ));
+    }
+}
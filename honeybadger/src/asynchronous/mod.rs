@@ -4,33 +4,120 @@ pub use graphql;
 
 use crate::asynchronous::provider::AuthProvider;
 use crate::secrets::KeyPair;
-use crate::{adjust_token, AdjustedToken, AuthLevel, TermsAndConditions};
+use crate::session::{Session, SessionStore};
+use crate::signer::Signer;
+use crate::{adjust_token, AdjustedToken, AuthLevel, TermsAndConditions, WalletKeyMigration};
+use async_trait::async_trait;
+use graphql::asynchronous::{AccessTokenProvider, BackendClient};
 pub use graphql::errors::{GraphQlRuntimeErrorCode, Result};
-use graphql::perro::OptionToError;
+use graphql::perro::{MapToError, OptionToError};
+use graphql::BackendClientConfig;
+use log::warn;
+use std::sync::Arc;
 use std::time::SystemTime;
 use tokio::sync::Mutex;
+use tokio::task::spawn_blocking;
 
 pub struct Auth {
     provider: Mutex<AuthProvider>,
     token: Mutex<AdjustedToken>,
+    session_store: Option<Arc<dyn SessionStore>>,
 }
 
 impl Auth {
+    #[deprecated(note = "Use Auth::with_client, which shares the client with the other crates")]
     pub fn new(
         backend_url: String,
         auth_level: AuthLevel,
         wallet_keypair: KeyPair,
         auth_keypair: KeyPair,
     ) -> Result<Self> {
-        let provider = AuthProvider::new(backend_url, auth_level, wallet_keypair, auth_keypair)?;
+        let backend_client = BackendClient::new(backend_url, BackendClientConfig::default())?;
+        Ok(Self::with_client(
+            backend_client,
+            auth_level,
+            wallet_keypair,
+            auth_keypair,
+        ))
+    }
+
+    /// Authenticates through the client, which may be shared with the crates using this `Auth`.
+    pub fn with_client(
+        backend_client: BackendClient,
+        auth_level: AuthLevel,
+        wallet_keypair: KeyPair,
+        auth_keypair: KeyPair,
+    ) -> Self {
+        Self::new_with_signers(
+            backend_client,
+            auth_level,
+            Box::new(wallet_keypair),
+            Box::new(auth_keypair),
+        )
+    }
+
+    /// Same as [`Auth::with_client`], but signs with the signers instead of key pairs in memory,
+    /// e.g. to keep the wallet key on a hardware wallet.
+    pub fn new_with_signers(
+        backend_client: BackendClient,
+        auth_level: AuthLevel,
+        wallet_signer: Box<dyn Signer>,
+        auth_signer: Box<dyn Signer>,
+    ) -> Self {
+        let provider = AuthProvider::new(backend_client, auth_level, wallet_signer, auth_signer);
         let expired_token = AdjustedToken {
             raw: String::new(),
             expires_at: SystemTime::UNIX_EPOCH,
         };
-        Ok(Auth {
+        Auth {
             provider: Mutex::new(provider),
             token: Mutex::new(expired_token),
-        })
+            session_store: None,
+        }
+    }
+
+    /// Same as [`Auth::with_client`], but resumes the session persisted in the store
+    /// (if any) and keeps the store up to date on every token refresh.
+    ///
+    /// The store is accessed on tokio's blocking thread pool.
+    pub async fn new_with_session_store(
+        backend_client: BackendClient,
+        auth_level: AuthLevel,
+        wallet_keypair: KeyPair,
+        auth_keypair: KeyPair,
+        session_store: Box<dyn SessionStore>,
+    ) -> Result<Self> {
+        Self::new_with_signers_and_session_store(
+            backend_client,
+            auth_level,
+            Box::new(wallet_keypair),
+            Box::new(auth_keypair),
+            session_store,
+        )
+        .await
+    }
+
+    /// Same as [`Auth::new_with_session_store`], but signs with the signers,
+    /// see [`Auth::new_with_signers`].
+    pub async fn new_with_signers_and_session_store(
+        backend_client: BackendClient,
+        auth_level: AuthLevel,
+        wallet_signer: Box<dyn Signer>,
+        auth_signer: Box<dyn Signer>,
+        session_store: Box<dyn SessionStore>,
+    ) -> Result<Self> {
+        let session_store: Arc<dyn SessionStore> = Arc::from(session_store);
+        let store = Arc::clone(&session_store);
+        let session = spawn_blocking(move || store.load())
+            .await
+            .map_to_permanent_failure("Failed to load session")??;
+        let mut auth =
+            Self::new_with_signers(backend_client, auth_level, wallet_signer, auth_signer);
+        auth.session_store = Some(session_store);
+        if let Some(session) = session {
+            auth.import_session(session).await;
+        }
+        Ok(auth)
     }
 
     pub async fn query_token(&self) -> Result<String> {
@@ -46,6 +133,7 @@ impl Auth {
 
         let token = adjust_token(provider.query_token().await?)?;
         *self.token.lock().await = token;
+        self.persist_session(&provider).await;
         self.get_token_if_valid()
             .await
             .ok_or_permanent_failure("Newly refreshed token is not valid long enough")
@@ -55,14 +143,79 @@ impl Auth {
         self.provider.lock().await.get_wallet_pubkey_id()
     }
 
-    // Not exposed in UDL, used in tests.
+    /// Discards the cached access token and refreshes the session.
     pub async fn refresh_token(&self) -> Result<String> {
+        // Prevent others from using the discarded token while refreshing.
+        self.token.lock().await.expires_at = SystemTime::UNIX_EPOCH;
         let mut provider = self.provider.lock().await;
-        let token = adjust_token(provider.query_token().await?)?;
-        *self.token.lock().await = token;
-        self.get_token_if_valid()
+        self.refresh_session(&mut provider).await
+    }
+
+    /// Refreshes the session because the backend rejected the access token,
+    /// unless someone else replaced the token in the meantime.
+    pub async fn refresh_rejected_token(&self, rejected_token: &str) -> Result<String> {
+        let mut provider = self.provider.lock().await;
+        if let Some(token) = self.get_token_if_valid().await {
+            if token != rejected_token {
+                return Ok(token);
+            }
+        }
+        // Prevent others from using the rejected token while refreshing.
+        self.token.lock().await.expires_at = SystemTime::UNIX_EPOCH;
+        self.refresh_session(&mut provider).await
+    }
+
+    /// Returns a snapshot of the current session or `None` if not authenticated yet.
+    pub async fn export_session(&self) -> Option<Session> {
+        let provider = self.provider.lock().await;
+        build_session(&provider, &*self.token.lock().await)
+    }
+
+    /// Resumes a previously exported session.
+    ///
+    /// The access token is used as long as it is valid, afterwards the session
+    /// gets refreshed with the refresh token.
+    pub async fn import_session(&self, session: Session) {
+        let mut provider = self.provider.lock().await;
+        provider.restore_session(session.refresh_token, session.wallet_pubkey_id);
+        *self.token.lock().await = AdjustedToken {
+            raw: session.access_token,
+            expires_at: session.access_token_expires_at,
+        };
+    }
+
+    /// Switches to a new wallet key pair, see [`crate::Auth::migrate_wallet_keypair`].
+    pub async fn migrate_wallet_keypair(
+        &self,
+        new_wallet_keypair: KeyPair,
+    ) -> Result<WalletKeyMigration> {
+        self.migrate_wallet_signer(Box::new(new_wallet_keypair))
             .await
-            .ok_or_permanent_failure("Newly refreshed token is not valid long enough")
+    }
+
+    /// Same as [`Auth::migrate_wallet_keypair`], but for a wallet key held by a signer.
+    pub async fn migrate_wallet_signer(
+        &self,
+        new_wallet_signer: Box<dyn Signer>,
+    ) -> Result<WalletKeyMigration> {
+        self.query_token().await?;
+
+        // Prevent others from using the token of the old wallet while switching.
+        self.token.lock().await.expires_at = SystemTime::UNIX_EPOCH;
+        let mut provider = self.provider.lock().await;
+        let old_wallet_pubkey_id = provider
+            .get_wallet_pubkey_id()
+            .ok_or_permanent_failure("Failed to get pubkey id for an authenticated wallet")?;
+        let token = adjust_token(provider.switch_wallet_signer(new_wallet_signer).await?)?;
+        *self.token.lock().await = token;
+        self.persist_session(&provider).await;
+        let new_wallet_pubkey_id = provider
+            .get_wallet_pubkey_id()
+            .ok_or_permanent_failure("Failed to get pubkey id for an authenticated wallet")?;
+        Ok(WalletKeyMigration {
+            old_wallet_pubkey_id,
+            new_wallet_pubkey_id,
+        })
     }
 
     pub async fn accept_terms_and_conditions(
@@ -87,4 +240,47 @@ impl Auth {
             None
         }
     }
+
+    async fn refresh_session(&self, provider: &mut AuthProvider) -> Result<String> {
+        let token = adjust_token(provider.query_token().await?)?;
+        *self.token.lock().await = token;
+        self.persist_session(provider).await;
+        self.get_token_if_valid()
+            .await
+            .ok_or_permanent_failure("Newly refreshed token is not valid long enough")
+    }
+
+    async fn persist_session(&self, provider: &AuthProvider) {
+        if let Some(session_store) = self.session_store.clone() {
+            // The token lock is released before writing to the store.
+            let session = build_session(provider, &*self.token.lock().await);
+            if let Some(session) = session {
+                match spawn_blocking(move || session_store.store(&session)).await {
+                    Ok(Ok(())) => {}
+                    Ok(Err(e)) => warn!("Failed to persist session: {e}"),
+                    Err(e) => warn!("Failed to persist session: {e}"),
+                }
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl AccessTokenProvider for Auth {
+    async fn query_token(&self) -> Result<String> {
+        Auth::query_token(self).await
+    }
+
+    async fn refresh_token(&self, rejected_token: &str) -> Result<String> {
+        Auth::refresh_rejected_token(self, rejected_token).await
+    }
+}
+
+fn build_session(provider: &AuthProvider, token: &AdjustedToken) -> Option<Session> {
+    Some(Session {
+        refresh_token: provider.get_refresh_token()?,
+        access_token: token.raw.clone(),
+        access_token_expires_at: token.expires_at,
+        wallet_pubkey_id: provider.get_wallet_pubkey_id()?,
+    })
 }
@@ -1,43 +1,38 @@
-use crate::secrets::KeyPair;
-use crate::signing::sign;
+use crate::signer::Signer;
+use crate::signing::{add_bitcoin_message_prefix, add_hex_prefix};
 
-use crate::provider::{add_bitcoin_message_prefix, add_hex_prefix};
 use crate::{AuthLevel, TermsAndConditions};
+use graphql::asynchronous::BackendClient;
 use graphql::perro::{ensure, invalid_input, permanent_failure, runtime_error, OptionToError};
-use graphql::reqwest::Client;
 use graphql::schema::*;
-use graphql::{build_async_client, post};
 use graphql::{errors::*, parse_from_rfc3339};
 use log::info;
 use std::time::SystemTime;
 
 pub(crate) struct AuthProvider {
-    backend_url: String,
     auth_level: AuthLevel,
-    wallet_keypair: KeyPair,
-    auth_keypair: KeyPair,
-    client: Client,
+    wallet_signer: Box<dyn Signer>,
+    auth_signer: Box<dyn Signer>,
+    client: BackendClient,
     refresh_token: Option<String>,
     wallet_pubkey_id: Option<String>,
 }
 
 impl AuthProvider {
     pub fn new(
-        backend_url: String,
+        client: BackendClient,
         auth_level: AuthLevel,
-        wallet_keypair: KeyPair,
-        auth_keypair: KeyPair,
-    ) -> Result<Self> {
-        let client = build_async_client(None)?;
-        Ok(AuthProvider {
-            backend_url,
+        wallet_signer: Box<dyn Signer>,
+        auth_signer: Box<dyn Signer>,
+    ) -> Self {
+        AuthProvider {
             auth_level,
-            wallet_keypair,
-            auth_keypair,
+            wallet_signer,
+            auth_signer,
             client,
             refresh_token: None,
             wallet_pubkey_id: None,
-        })
+        }
     }
 
     pub async fn query_token(&mut self) -> Result<String> {
@@ -62,6 +57,33 @@ impl AuthProvider {
         self.wallet_pubkey_id.clone()
     }
 
+    pub fn get_refresh_token(&self) -> Option<String> {
+        self.refresh_token.clone()
+    }
+
+    pub fn restore_session(&mut self, refresh_token: String, wallet_pubkey_id: String) {
+        self.refresh_token = Some(refresh_token);
+        self.wallet_pubkey_id = Some(wallet_pubkey_id);
+    }
+
+    /// Runs the auth flow with the wallet signer and keeps using it if that succeeds,
+    /// otherwise the previous signer and session stay in use.
+    pub async fn switch_wallet_signer(&mut self, wallet_signer: Box<dyn Signer>) -> Result<String> {
+        let previous_signer = std::mem::replace(&mut self.wallet_signer, wallet_signer);
+        let previous_wallet_pubkey_id = self.wallet_pubkey_id.take();
+        match self.run_auth_flow().await {
+            Ok((access_token, refresh_token)) => {
+                self.refresh_token = Some(refresh_token);
+                Ok(access_token)
+            }
+            Err(e) => {
+                self.wallet_signer = previous_signer;
+                self.wallet_pubkey_id = previous_wallet_pubkey_id;
+                Err(e)
+            }
+        }
+    }
+
     pub async fn accept_terms_and_conditions(
         &self,
         access_token: String,
@@ -80,9 +102,10 @@ impl AuthProvider {
             service: Some(terms.into()),
             version,
         };
-        let client = build_async_client(Some(&access_token))?;
-        let data =
-            post::<AcceptTermsAndConditionsV2>(&client, &self.backend_url, variables).await?;
+        let data = self
+            .client
+            .post_with_token::<AcceptTermsAndConditionsV2>(Some(&access_token), variables)
+            .await?;
         ensure!(
             matches!(
                 data.accept_terms_conditions_v2,
@@ -120,24 +143,29 @@ impl AuthProvider {
         let challenge = self.request_challenge().await?;
 
         let challenge_with_prefix = add_bitcoin_message_prefix(&challenge);
-        let challenge_signature = sign(challenge_with_prefix, self.auth_keypair.secret_key.clone());
+        let challenge_signature = self.auth_signer.sign_message(challenge_with_prefix).await?;
 
-        let auth_pub_key_with_prefix = add_hex_prefix(&self.auth_keypair.public_key);
-        let signed_auth_pub_key = sign(
-            auth_pub_key_with_prefix,
-            self.wallet_keypair.secret_key.clone(),
-        );
+        let auth_pub_key = self.auth_signer.public_key().await?;
+        let wallet_pub_key = self.wallet_signer.public_key().await?;
+        let auth_pub_key_with_prefix = add_hex_prefix(&auth_pub_key);
+        let signed_auth_pub_key = self
+            .wallet_signer
+            .sign_message(auth_pub_key_with_prefix)
+            .await?;
 
         info!("Starting session ...");
         let variables = start_session::Variables {
-            auth_pub_key: add_hex_prefix(&self.auth_keypair.public_key),
+            auth_pub_key: add_hex_prefix(&auth_pub_key),
             challenge,
             challenge_signature: add_hex_prefix(&challenge_signature),
-            wallet_pub_key: add_hex_prefix(&self.wallet_keypair.public_key),
+            wallet_pub_key: add_hex_prefix(&wallet_pub_key),
             signed_auth_pub_key: add_hex_prefix(&signed_auth_pub_key),
         };
 
-        let data = post::<StartSession>(&self.client, &self.backend_url, variables).await?;
+        let data = self
+            .client
+            .post_with_token::<StartSession>(None, variables)
+            .await?;
 
         let session_permit = data.start_session_v2.ok_or_permanent_failure(
             "Response to start_session request doesn't have the expected structure",
@@ -167,10 +195,10 @@ impl AuthProvider {
         let challenge = self.request_challenge().await?;
 
         let challenge_with_prefix = add_bitcoin_message_prefix(&challenge);
-        let challenge_signature = sign(
-            challenge_with_prefix,
-            self.wallet_keypair.secret_key.clone(),
-        );
+        let challenge_signature = self
+            .wallet_signer
+            .sign_message(challenge_with_prefix)
+            .await?;
 
         info!("Preparing wallet session ...");
         let variables = prepare_wallet_session::Variables {
@@ -179,8 +207,10 @@ impl AuthProvider {
             signed_challenge: add_hex_prefix(&challenge_signature),
         };
 
-        let client = build_async_client(Some(&access_token))?;
-        let data = post::<PrepareWalletSession>(&client, &self.backend_url, variables).await?;
+        let data = self
+            .client
+            .post_with_token::<PrepareWalletSession>(Some(&access_token), variables)
+            .await?;
 
         let prepared_permission_token = data.prepare_wallet_session.ok_or_permanent_failure(
             "Response to prepare_wallet_session request doesn't have the expected structure",
@@ -192,7 +222,10 @@ impl AuthProvider {
             challenge_signature: add_hex_prefix(&challenge_signature),
             prepared_permission_token,
         };
-        let data = post::<UnlockWallet>(&client, &self.backend_url, variables).await?;
+        let data = self
+            .client
+            .post_with_token::<UnlockWallet>(Some(&access_token), variables)
+            .await?;
 
         let session_permit = data.start_prepared_session.ok_or_permanent_failure(
             "Response to unlock_wallet request doesn't have the expected structure",
@@ -221,8 +254,10 @@ impl AuthProvider {
         let variables = get_business_owner::Variables {
             owner_wallet_pub_key_id: wallet_pub_key_id,
         };
-        let client = build_async_client(Some(&access_token))?;
-        let data = post::<GetBusinessOwner>(&client, &self.backend_url, variables).await?;
+        let data = self
+            .client
+            .post_with_token::<GetBusinessOwner>(Some(&access_token), variables)
+            .await?;
 
         let result = data
             .wallet_acl
@@ -244,7 +279,10 @@ impl AuthProvider {
         // Refresh session.
         info!("Refreshing session ...");
         let variables = refresh_session::Variables { refresh_token };
-        let data = post::<RefreshSession>(&self.client, &self.backend_url, variables).await?;
+        let data = self
+            .client
+            .post_with_token::<RefreshSession>(None, variables)
+            .await?;
 
         let session_permit = data.refresh_session.ok_or_permanent_failure(
             "Response to refresh_session request doesn't have the expected structure",
@@ -267,7 +305,10 @@ impl AuthProvider {
     async fn request_challenge(&self) -> Result<String> {
         info!("Requesting challenge ...");
         let variables = request_challenge::Variables {};
-        let data = post::<RequestChallenge>(&self.client, &self.backend_url, variables).await?;
+        let data = self
+            .client
+            .post_with_token::<RequestChallenge>(None, variables)
+            .await?;
 
         let challenge = data
             .auth_challenge
@@ -2,7 +2,9 @@ pub mod asynchronous;
 mod jwt;
 mod provider;
 pub mod secrets;
-mod signing;
+pub mod session;
+pub mod signer;
+pub mod signing;
 
 pub use graphql;
 
@@ -11,9 +13,13 @@ pub use crate::provider::{AuthLevel, TermsAndConditions};
 use crate::jwt::parse_token;
 use crate::provider::AuthProvider;
 use crate::secrets::KeyPair;
+use crate::session::{Session, SessionStore};
+use crate::signer::BlockingSigner;
 
 pub use graphql::errors::{GraphQlRuntimeErrorCode, Result};
 use graphql::perro::{MapToError, OptionToError};
+use graphql::{AccessTokenProvider, BackendClient, BackendClientConfig};
+use log::warn;
 use std::cmp::{max, min};
 use std::sync::Mutex;
 use std::time::{Duration, SystemTime};
@@ -27,6 +33,7 @@ struct AdjustedToken {
 pub struct Auth {
     provider: Mutex<AuthProvider>,
     token: Mutex<AdjustedToken>,
+    session_store: Option<Box<dyn SessionStore>>,
 }
 
 #[derive(Debug, PartialEq)]
@@ -36,22 +43,100 @@ pub struct TermsAndConditionsStatus {
     pub version: i64,
 }
 
+/// The wallet public key ids before and after [`Auth::migrate_wallet_keypair`].
+#[derive(Debug, PartialEq, Eq)]
+pub struct WalletKeyMigration {
+    pub old_wallet_pubkey_id: String,
+    pub new_wallet_pubkey_id: String,
+}
+
 impl Auth {
+    #[deprecated(note = "Use Auth::with_client, which shares the client with the other crates")]
     pub fn new(
         backend_url: String,
         auth_level: AuthLevel,
         wallet_keypair: KeyPair,
         auth_keypair: KeyPair,
     ) -> Result<Self> {
-        let provider = AuthProvider::new(backend_url, auth_level, wallet_keypair, auth_keypair)?;
+        let backend_client = BackendClient::new(backend_url, BackendClientConfig::default())?;
+        Ok(Self::with_client(
+            backend_client,
+            auth_level,
+            wallet_keypair,
+            auth_keypair,
+        ))
+    }
+
+    /// Authenticates through the client, which may be shared with the crates using this `Auth`.
+    pub fn with_client(
+        backend_client: BackendClient,
+        auth_level: AuthLevel,
+        wallet_keypair: KeyPair,
+        auth_keypair: KeyPair,
+    ) -> Self {
+        Self::new_with_signers(
+            backend_client,
+            auth_level,
+            Box::new(wallet_keypair),
+            Box::new(auth_keypair),
+        )
+    }
+
+    /// Same as [`Auth::with_client`], but signs with the signers instead of key pairs in memory,
+    /// e.g. to keep the wallet key on a hardware wallet.
+    pub fn new_with_signers(
+        backend_client: BackendClient,
+        auth_level: AuthLevel,
+        wallet_signer: Box<dyn BlockingSigner>,
+        auth_signer: Box<dyn BlockingSigner>,
+    ) -> Self {
+        let provider = AuthProvider::new(backend_client, auth_level, wallet_signer, auth_signer);
         let expired_token = AdjustedToken {
             raw: String::new(),
             expires_at: SystemTime::UNIX_EPOCH,
         };
-        Ok(Auth {
+        Auth {
             provider: Mutex::new(provider),
             token: Mutex::new(expired_token),
-        })
+            session_store: None,
+        }
+    }
+
+    /// Same as [`Auth::with_client`], but resumes the session persisted in the store
+    /// (if any) and keeps the store up to date on every token refresh.
+    pub fn new_with_session_store(
+        backend_client: BackendClient,
+        auth_level: AuthLevel,
+        wallet_keypair: KeyPair,
+        auth_keypair: KeyPair,
+        session_store: Box<dyn SessionStore>,
+    ) -> Result<Self> {
+        Self::new_with_signers_and_session_store(
+            backend_client,
+            auth_level,
+            Box::new(wallet_keypair),
+            Box::new(auth_keypair),
+            session_store,
+        )
+    }
+
+    /// Same as [`Auth::new_with_session_store`], but signs with the signers,
+    /// see [`Auth::new_with_signers`].
+    pub fn new_with_signers_and_session_store(
+        backend_client: BackendClient,
+        auth_level: AuthLevel,
+        wallet_signer: Box<dyn BlockingSigner>,
+        auth_signer: Box<dyn BlockingSigner>,
+        session_store: Box<dyn SessionStore>,
+    ) -> Result<Self> {
+        let session = session_store.load()?;
+        let mut auth =
+            Self::new_with_signers(backend_client, auth_level, wallet_signer, auth_signer);
+        auth.session_store = Some(session_store);
+        if let Some(session) = session {
+            auth.import_session(session);
+        }
+        Ok(auth)
     }
 
     pub fn query_token(&self) -> Result<String> {
@@ -67,6 +152,7 @@ impl Auth {
 
         let token = adjust_token(provider.query_token()?)?;
         *self.token.lock().unwrap() = token;
+        self.persist_session(&provider);
         self.get_token_if_valid()
             .ok_or_permanent_failure("Newly refreshed token is not valid long enough")
     }
@@ -86,13 +172,84 @@ impl Auth {
         }
     }
 
-    // Not exposed in UDL, used in tests.
+    /// Discards the cached access token and refreshes the session.
     pub fn refresh_token(&self) -> Result<String> {
+        // Prevent others from using the discarded token while refreshing.
+        self.token.lock().unwrap().expires_at = SystemTime::UNIX_EPOCH;
         let mut provider = self.provider.lock().unwrap();
-        let token = adjust_token(provider.query_token()?)?;
+        self.refresh_session(&mut provider)
+    }
+
+    /// Refreshes the session because the backend rejected the access token,
+    /// unless someone else replaced the token in the meantime.
+    pub fn refresh_rejected_token(&self, rejected_token: &str) -> Result<String> {
+        let mut provider = self.provider.lock().unwrap();
+        if let Some(token) = self.get_token_if_valid() {
+            if token != rejected_token {
+                return Ok(token);
+            }
+        }
+        // Prevent others from using the rejected token while refreshing.
+        self.token.lock().unwrap().expires_at = SystemTime::UNIX_EPOCH;
+        self.refresh_session(&mut provider)
+    }
+
+    /// Returns a snapshot of the current session or `None` if not authenticated yet.
+    pub fn export_session(&self) -> Option<Session> {
+        let provider = self.provider.lock().unwrap();
+        build_session(&provider, &self.token.lock().unwrap())
+    }
+
+    /// Resumes a previously exported session.
+    ///
+    /// The access token is used as long as it is valid, afterwards the session
+    /// gets refreshed with the refresh token.
+    pub fn import_session(&self, session: Session) {
+        let mut provider = self.provider.lock().unwrap();
+        provider.restore_session(session.refresh_token, session.wallet_pubkey_id);
+        *self.token.lock().unwrap() = AdjustedToken {
+            raw: session.access_token,
+            expires_at: session.access_token_expires_at,
+        };
+    }
+
+    /// Switches to a new wallet key pair, e.g. one derived with another
+    /// [`DerivationScheme`](secrets::DerivationScheme).
+    ///
+    /// Authenticates with the current key pair, then with the new one, which registers it with
+    /// the backend. If that fails, the current key pair stays in use, so only persist the new
+    /// derivation scheme after a successful migration.
+    ///
+    /// The backend does not link the two wallet public keys, data stored for the old one
+    /// (e.g. backups) has to be moved by the caller.
+    pub fn migrate_wallet_keypair(
+        &self,
+        new_wallet_keypair: KeyPair,
+    ) -> Result<WalletKeyMigration> {
+        self.migrate_wallet_signer(Box::new(new_wallet_keypair))
+    }
+
+    /// Same as [`Auth::migrate_wallet_keypair`], but for a wallet key held by a signer.
+    pub fn migrate_wallet_signer(
+        &self,
+        new_wallet_signer: Box<dyn BlockingSigner>,
+    ) -> Result<WalletKeyMigration> {
+        self.query_token()?;
+        let old_wallet_pubkey_id = self.get_wallet_pubkey_id()?;
+
+        // Prevent others from using the token of the old wallet while switching.
+        self.token.lock().unwrap().expires_at = SystemTime::UNIX_EPOCH;
+        let mut provider = self.provider.lock().unwrap();
+        let token = adjust_token(provider.switch_wallet_signer(new_wallet_signer)?)?;
         *self.token.lock().unwrap() = token;
-        self.get_token_if_valid()
-            .ok_or_permanent_failure("Newly refreshed token is not valid long enough")
+        self.persist_session(&provider);
+        let new_wallet_pubkey_id = provider
+            .get_wallet_pubkey_id()
+            .ok_or_permanent_failure("Failed to get pubkey id for an authenticated wallet")?;
+        Ok(WalletKeyMigration {
+            old_wallet_pubkey_id,
+            new_wallet_pubkey_id,
+        })
     }
 
     pub fn accept_terms_and_conditions(
@@ -124,6 +281,43 @@ impl Auth {
             None
         }
     }
+
+    fn refresh_session(&self, provider: &mut AuthProvider) -> Result<String> {
+        let token = adjust_token(provider.query_token()?)?;
+        *self.token.lock().unwrap() = token;
+        self.persist_session(provider);
+        self.get_token_if_valid()
+            .ok_or_permanent_failure("Newly refreshed token is not valid long enough")
+    }
+
+    fn persist_session(&self, provider: &AuthProvider) {
+        if let Some(session_store) = self.session_store.as_ref() {
+            if let Some(session) = build_session(provider, &self.token.lock().unwrap()) {
+                if let Err(e) = session_store.store(&session) {
+                    warn!("Failed to persist session: {e}");
+                }
+            }
+        }
+    }
+}
+
+impl AccessTokenProvider for Auth {
+    fn query_token(&self) -> Result<String> {
+        Auth::query_token(self)
+    }
+
+    fn refresh_token(&self, rejected_token: &str) -> Result<String> {
+        Auth::refresh_rejected_token(self, rejected_token)
+    }
+}
+
+fn build_session(provider: &AuthProvider, token: &AdjustedToken) -> Option<Session> {
+    Some(Session {
+        refresh_token: provider.get_refresh_token()?,
+        access_token: token.raw.clone(),
+        access_token_expires_at: token.expires_at,
+        wallet_pubkey_id: provider.get_wallet_pubkey_id()?,
+    })
 }
 
 pub(crate) fn adjust_token(raw_token: String) -> Result<AdjustedToken> {
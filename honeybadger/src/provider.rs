@@ -1,14 +1,13 @@
-use crate::secrets::KeyPair;
-use crate::signing::sign;
+use crate::signer::BlockingSigner;
+use crate::signing::{add_bitcoin_message_prefix, add_hex_prefix};
 
 use crate::TermsAndConditionsStatus;
 use graphql::perro::{ensure, invalid_input, permanent_failure, runtime_error, OptionToError};
-use graphql::reqwest::blocking::Client;
 use graphql::schema::accept_terms_and_conditions_v2::Service;
 use graphql::schema::get_terms_and_conditions_status::ServiceProviderEnum;
 use graphql::schema::*;
-use graphql::{build_client, perro, post_blocking};
 use graphql::{errors::*, parse_from_rfc3339};
+use graphql::{perro, BackendClient};
 use log::info;
 use std::time::SystemTime;
 
@@ -59,32 +58,29 @@ impl TryInto<TermsAndConditions> for ServiceProviderEnum {
 }
 
 pub(crate) struct AuthProvider {
-    backend_url: String,
     auth_level: AuthLevel,
-    wallet_keypair: KeyPair,
-    auth_keypair: KeyPair,
-    client: Client,
+    wallet_signer: Box<dyn BlockingSigner>,
+    auth_signer: Box<dyn BlockingSigner>,
+    client: BackendClient,
     refresh_token: Option<String>,
     wallet_pubkey_id: Option<String>,
 }
 
 impl AuthProvider {
     pub fn new(
-        backend_url: String,
+        client: BackendClient,
         auth_level: AuthLevel,
-        wallet_keypair: KeyPair,
-        auth_keypair: KeyPair,
-    ) -> Result<Self> {
-        let client = build_client(None)?;
-        Ok(AuthProvider {
-            backend_url,
+        wallet_signer: Box<dyn BlockingSigner>,
+        auth_signer: Box<dyn BlockingSigner>,
+    ) -> Self {
+        AuthProvider {
             auth_level,
-            wallet_keypair,
-            auth_keypair,
+            wallet_signer,
+            auth_signer,
             client,
             refresh_token: None,
             wallet_pubkey_id: None,
-        })
+        }
     }
 
     pub fn query_token(&mut self) -> Result<String> {
@@ -109,6 +105,36 @@ impl AuthProvider {
         self.wallet_pubkey_id.clone()
     }
 
+    pub fn get_refresh_token(&self) -> Option<String> {
+        self.refresh_token.clone()
+    }
+
+    pub fn restore_session(&mut self, refresh_token: String, wallet_pubkey_id: String) {
+        self.refresh_token = Some(refresh_token);
+        self.wallet_pubkey_id = Some(wallet_pubkey_id);
+    }
+
+    /// Runs the auth flow with the wallet signer and keeps using it if that succeeds,
+    /// otherwise the previous signer and session stay in use.
+    pub fn switch_wallet_signer(
+        &mut self,
+        wallet_signer: Box<dyn BlockingSigner>,
+    ) -> Result<String> {
+        let previous_signer = std::mem::replace(&mut self.wallet_signer, wallet_signer);
+        let previous_wallet_pubkey_id = self.wallet_pubkey_id.take();
+        match self.run_auth_flow() {
+            Ok((access_token, refresh_token)) => {
+                self.refresh_token = Some(refresh_token);
+                Ok(access_token)
+            }
+            Err(e) => {
+                self.wallet_signer = previous_signer;
+                self.wallet_pubkey_id = previous_wallet_pubkey_id;
+                Err(e)
+            }
+        }
+    }
+
     pub fn accept_terms_and_conditions(
         &self,
         access_token: String,
@@ -127,9 +153,9 @@ impl AuthProvider {
             service: Some(terms.into()),
             version,
         };
-        let client = build_client(Some(&access_token))?;
-        let data =
-            post_blocking::<AcceptTermsAndConditionsV2>(&client, &self.backend_url, variables)?;
+        let data = self
+            .client
+            .post_with_token::<AcceptTermsAndConditionsV2>(Some(&access_token), variables)?;
         ensure!(
             matches!(
                 data.accept_terms_conditions_v2,
@@ -159,9 +185,9 @@ impl AuthProvider {
         let variables = get_terms_and_conditions_status::Variables {
             service_provider: terms.clone().into(),
         };
-        let client = build_client(Some(&access_token))?;
-        let data =
-            post_blocking::<GetTermsAndConditionsStatus>(&client, &self.backend_url, variables)?;
+        let data = self
+            .client
+            .post_with_token::<GetTermsAndConditionsStatus>(Some(&access_token), variables)?;
 
         let terms_status = data.get_terms_conditions_status.ok_or_runtime_error(
             GraphQlRuntimeErrorCode::RemoteServiceUnavailable,
@@ -214,24 +240,25 @@ impl AuthProvider {
         let challenge = self.request_challenge()?;
 
         let challenge_with_prefix = add_bitcoin_message_prefix(&challenge);
-        let challenge_signature = sign(challenge_with_prefix, self.auth_keypair.secret_key.clone());
+        let challenge_signature = self.auth_signer.sign_message(challenge_with_prefix)?;
 
-        let auth_pub_key_with_prefix = add_hex_prefix(&self.auth_keypair.public_key);
-        let signed_auth_pub_key = sign(
-            auth_pub_key_with_prefix,
-            self.wallet_keypair.secret_key.clone(),
-        );
+        let auth_pub_key = self.auth_signer.public_key()?;
+        let wallet_pub_key = self.wallet_signer.public_key()?;
+        let auth_pub_key_with_prefix = add_hex_prefix(&auth_pub_key);
+        let signed_auth_pub_key = self.wallet_signer.sign_message(auth_pub_key_with_prefix)?;
 
         info!("Starting session ...");
         let variables = start_session::Variables {
-            auth_pub_key: add_hex_prefix(&self.auth_keypair.public_key),
+            auth_pub_key: add_hex_prefix(&auth_pub_key),
             challenge,
             challenge_signature: add_hex_prefix(&challenge_signature),
-            wallet_pub_key: add_hex_prefix(&self.wallet_keypair.public_key),
+            wallet_pub_key: add_hex_prefix(&wallet_pub_key),
             signed_auth_pub_key: add_hex_prefix(&signed_auth_pub_key),
         };
 
-        let data = post_blocking::<StartSession>(&self.client, &self.backend_url, variables)?;
+        let data = self
+            .client
+            .post_with_token::<StartSession>(None, variables)?;
 
         let session_permit = data.start_session_v2.ok_or_permanent_failure(
             "Response to start_session request doesn't have the expected structure",
@@ -261,10 +288,7 @@ impl AuthProvider {
         let challenge = self.request_challenge()?;
 
         let challenge_with_prefix = add_bitcoin_message_prefix(&challenge);
-        let challenge_signature = sign(
-            challenge_with_prefix,
-            self.wallet_keypair.secret_key.clone(),
-        );
+        let challenge_signature = self.wallet_signer.sign_message(challenge_with_prefix)?;
 
         info!("Preparing wallet session ...");
         let variables = prepare_wallet_session::Variables {
@@ -273,8 +297,9 @@ impl AuthProvider {
             signed_challenge: add_hex_prefix(&challenge_signature),
         };
 
-        let client = build_client(Some(&access_token))?;
-        let data = post_blocking::<PrepareWalletSession>(&client, &self.backend_url, variables)?;
+        let data = self
+            .client
+            .post_with_token::<PrepareWalletSession>(Some(&access_token), variables)?;
 
         let prepared_permission_token = data.prepare_wallet_session.ok_or_permanent_failure(
             "Response to prepare_wallet_session request doesn't have the expected structure",
@@ -286,7 +311,9 @@ impl AuthProvider {
             challenge_signature: add_hex_prefix(&challenge_signature),
             prepared_permission_token,
         };
-        let data = post_blocking::<UnlockWallet>(&client, &self.backend_url, variables)?;
+        let data = self
+            .client
+            .post_with_token::<UnlockWallet>(Some(&access_token), variables)?;
 
         let session_permit = data.start_prepared_session.ok_or_permanent_failure(
             "Response to unlock_wallet request doesn't have the expected structure",
@@ -315,8 +342,9 @@ impl AuthProvider {
         let variables = get_business_owner::Variables {
             owner_wallet_pub_key_id: wallet_pub_key_id,
         };
-        let client = build_client(Some(&access_token))?;
-        let data = post_blocking::<GetBusinessOwner>(&client, &self.backend_url, variables)?;
+        let data = self
+            .client
+            .post_with_token::<GetBusinessOwner>(Some(&access_token), variables)?;
 
         let result = data
             .wallet_acl
@@ -338,7 +366,9 @@ impl AuthProvider {
         // Refresh session.
         info!("Refreshing session ...");
         let variables = refresh_session::Variables { refresh_token };
-        let data = post_blocking::<RefreshSession>(&self.client, &self.backend_url, variables)?;
+        let data = self
+            .client
+            .post_with_token::<RefreshSession>(None, variables)?;
 
         let session_permit = data.refresh_session.ok_or_permanent_failure(
             "Response to refresh_session request doesn't have the expected structure",
@@ -361,7 +391,9 @@ impl AuthProvider {
     fn request_challenge(&self) -> Result<String> {
         info!("Requesting challenge ...");
         let variables = request_challenge::Variables {};
-        let data = post_blocking::<RequestChallenge>(&self.client, &self.backend_url, variables)?;
+        let data = self
+            .client
+            .post_with_token::<RequestChallenge>(None, variables)?;
 
         let challenge = data
             .auth_challenge
@@ -372,11 +404,3 @@ impl AuthProvider {
         Ok(challenge)
     }
 }
-
-pub(crate) fn add_hex_prefix(string: &str) -> String {
-    ["\\x", string].concat()
-}
-
-pub(crate) fn add_bitcoin_message_prefix(string: &str) -> String {
-    ["\\x18Bitcoin Signed Message:", string].concat()
-}
@@ -1,37 +1,193 @@
 use bdk::bitcoin::bip32::{DerivationPath, ExtendedPrivKey};
-use bdk::bitcoin::secp256k1::PublicKey;
+use bdk::bitcoin::secp256k1::{self, PublicKey};
 use bdk::bitcoin::Network;
-use bdk::keys::bip39::Mnemonic;
+use bdk::keys::bip39::{self, Language, Mnemonic};
 use bdk::keys::{DerivableKey, ExtendedKey};
 use bdk::miniscript::ToPublicKey;
 use rand::rngs::OsRng;
 use rand::RngCore;
 use secp256k1::SECP256K1;
+use std::fmt::{Debug, Display, Formatter};
 use std::str::FromStr;
+use zeroize::Zeroizing;
 
-// In the near future we want to migrate to the following keys for backend auth
-//const BACKEND_AUTH_DERIVATION_PATH: &str = "m/76738065'/0'/0";
-// For now, we use the master key pair
-const BACKEND_AUTH_DERIVATION_PATH: &str = "m";
+const LEGACY_DERIVATION_PATH: &str = "m";
+const LIPA_PURPOSE_DERIVATION_PATH: &str = "m/76738065'/0'/0";
 
-pub fn generate_mnemonic() -> Vec<String> {
-    let entropy = generate_random_bytes();
-    let mnemonic = Mnemonic::from_entropy(&entropy).unwrap();
+/// Word counts of the mnemonics [`generate_mnemonic`] can generate.
+pub const MNEMONIC_WORD_COUNTS: [usize; 5] = [12, 15, 18, 21, 24];
+
+/// Which key derived from the mnemonic authenticates the wallet to the backend.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum DerivationScheme {
+    /// The master key, used by wallets created so far.
+    #[default]
+    LegacyMasterKey,
+    /// The key at `m/76738065'/0'/0`, wallets get migrated to it,
+    /// see [`Auth::migrate_wallet_keypair`](crate::Auth::migrate_wallet_keypair).
+    LipaPurpose,
+    /// The key at a BIP32 derivation path, e.g. `m/0'/1`.
+    Custom { path: String },
+}
+
+impl DerivationScheme {
+    pub fn path(&self) -> &str {
+        match self {
+            Self::LegacyMasterKey => LEGACY_DERIVATION_PATH,
+            Self::LipaPurpose => LIPA_PURPOSE_DERIVATION_PATH,
+            Self::Custom { path } => path,
+        }
+    }
+}
+
+/// Why a secret could not be generated, parsed or used.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SecretsError {
+    /// Mnemonics have 12, 15, 18, 21 or 24 words.
+    InvalidWordCount {
+        count: usize,
+    },
+    /// The word is not in the BIP39 English word list, `index` is its zero based position.
+    UnknownWord {
+        index: usize,
+        word: String,
+    },
+    /// All words are known but do not form a mnemonic, most likely one of them is mistyped
+    /// or they are in the wrong order.
+    InvalidChecksum,
+    InvalidDerivationPath {
+        path: String,
+    },
+    /// The secret key is not a hex string.
+    InvalidHex,
+    /// The secret key is not a valid secp256k1 secret key, e.g. it has the wrong length.
+    InvalidSecretKey,
+    /// Getting randomness from the OS or deriving a key failed.
+    Unexpected {
+        msg: String,
+    },
+}
+
+impl Display for SecretsError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::InvalidWordCount { count } => {
+                write!(f, "The mnemonic has {count} words instead of 12 to 24")
+            }
+            Self::UnknownWord { index, word } => {
+                write!(f, "Word {} of the mnemonic is unknown: {word}", index + 1)
+            }
+            Self::InvalidChecksum => write!(f, "The mnemonic has an invalid checksum"),
+            Self::InvalidDerivationPath { path } => {
+                write!(f, "Invalid derivation path: {path}")
+            }
+            Self::InvalidHex => write!(f, "The secret key is not a hex string"),
+            Self::InvalidSecretKey => write!(f, "The secret key is invalid"),
+            Self::Unexpected { msg } => write!(f, "Unexpected error: {msg}"),
+        }
+    }
+}
+
+impl std::error::Error for SecretsError {}
+
+impl From<SecretsError> for graphql::errors::Error {
+    fn from(error: SecretsError) -> Self {
+        match error {
+            SecretsError::Unexpected { .. } => Self::PermanentFailure {
+                msg: error.to_string(),
+            },
+            error => Self::InvalidInput {
+                msg: error.to_string(),
+            },
+        }
+    }
+}
+
+/// Generates a mnemonic with one of the [`MNEMONIC_WORD_COUNTS`].
+pub fn generate_mnemonic(word_count: usize) -> Result<Vec<String>, SecretsError> {
+    if !MNEMONIC_WORD_COUNTS.contains(&word_count) {
+        return Err(SecretsError::InvalidWordCount { count: word_count });
+    }
+    // Every word encodes 11 bits, 1 bit out of 33 is checksum.
+    let entropy_length = word_count * 4 / 3;
+    let entropy = generate_random_bytes()?;
+    let mnemonic = Mnemonic::from_entropy(&entropy[..entropy_length]).map_err(to_unexpected)?;
 
     let mnemonic: Vec<String> = mnemonic.words().map(|s| s.to_string()).collect();
 
-    mnemonic
+    Ok(mnemonic)
 }
 
-fn generate_random_bytes() -> [u8; 32] {
-    let mut bytes = [0u8; 32];
-    OsRng.try_fill_bytes(&mut bytes).unwrap();
-    bytes
+fn generate_random_bytes() -> Result<Zeroizing<[u8; 32]>, SecretsError> {
+    let mut bytes = Zeroizing::new([0u8; 32]);
+    OsRng.try_fill_bytes(&mut *bytes).map_err(to_unexpected)?;
+    Ok(bytes)
 }
 
-#[derive(Clone)]
+/// A secp256k1 secret key which is overwritten when dropped.
+///
+/// It can neither be cloned nor printed. Use [`SecretKey::export_hex`] to persist it
+/// and [`SecretKey::from_hex`] to load it again.
+pub struct SecretKey {
+    secret_key: secp256k1::SecretKey,
+}
+
+impl SecretKey {
+    pub fn from_hex(secret_key: &str) -> Result<Self, SecretsError> {
+        let mut bytes = Zeroizing::new([0u8; 32]);
+        match hex::decode_to_slice(secret_key, &mut *bytes) {
+            Ok(()) => Self::from_bytes(&*bytes),
+            Err(hex::FromHexError::InvalidStringLength) => Err(SecretsError::InvalidSecretKey),
+            Err(_) => Err(SecretsError::InvalidHex),
+        }
+    }
+
+    /// Returns the key as a hex string, e.g. to persist it.
+    ///
+    /// The returned copy is not erased, drop it as soon as possible.
+    pub fn export_hex(&self) -> String {
+        hex::encode(Zeroizing::new(self.secret_key.secret_bytes()))
+    }
+
+    /// The compressed public key as a hex string.
+    pub fn public_key(&self) -> String {
+        hex::encode(PublicKey::from_secret_key(SECP256K1, &self.secret_key).serialize())
+    }
+
+    /// Borrows the key to sign with it without copying it.
+    pub(crate) fn as_secp256k1(&self) -> &secp256k1::SecretKey {
+        &self.secret_key
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Result<Self, SecretsError> {
+        let secret_key =
+            secp256k1::SecretKey::from_slice(bytes).map_err(|_| SecretsError::InvalidSecretKey)?;
+        Ok(Self { secret_key })
+    }
+}
+
+impl Drop for SecretKey {
+    fn drop(&mut self) {
+        // secp256k1 keys do not implement `Zeroize`, this overwrites them the same way.
+        self.secret_key.non_secure_erase();
+    }
+}
+
+impl Debug for SecretKey {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "SecretKey(<redacted>)")
+    }
+}
+
+impl Display for SecretKey {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "<redacted>")
+    }
+}
+
+#[derive(Debug)]
 pub struct KeyPair {
-    pub secret_key: String,
+    pub secret_key: SecretKey,
     pub public_key: String,
 }
 
@@ -39,49 +195,348 @@ pub struct WalletKeys {
     pub wallet_keypair: KeyPair,
 }
 
-pub fn derive_keys(network: Network, mnemonic_string: Vec<String>) -> WalletKeys {
-    let mnemonic = Mnemonic::from_str(mnemonic_string.join(" ").as_str()).unwrap();
+/// Whether the word is in the BIP39 English word list, ignoring case and surrounding whitespace.
+pub fn is_mnemonic_word(word: &str) -> bool {
+    Language::English.find_word(&normalize_word(word)).is_some()
+}
+
+/// The words of the BIP39 English word list starting with the prefix, in alphabetical order.
+///
+/// Returns no words for an empty prefix.
+pub fn suggest_mnemonic_words(prefix: &str) -> Vec<String> {
+    let prefix = normalize_word(prefix);
+    if prefix.is_empty() {
+        return Vec::new();
+    }
+    Language::English
+        .words_by_prefix(&prefix)
+        .iter()
+        .map(|word| word.to_string())
+        .collect()
+}
+
+/// Checks that the words form a valid mnemonic, including its checksum.
+pub fn validate_mnemonic(mnemonic_string: &[String]) -> Result<(), SecretsError> {
+    parse_mnemonic(mnemonic_string).map(|_| ())
+}
+
+/// Derives the keys from the mnemonic and the optional BIP39 passphrase.
+///
+/// No passphrase and an empty one derive the same keys.
+pub fn derive_keys(
+    network: Network,
+    mnemonic_string: Vec<String>,
+    passphrase: Option<String>,
+    derivation_scheme: &DerivationScheme,
+) -> Result<WalletKeys, SecretsError> {
+    let mnemonic = parse_mnemonic(&mnemonic_string)?;
 
-    let master_xpriv = get_master_xpriv(network, mnemonic);
+    let master_xpriv = get_master_xpriv(network, mnemonic, passphrase)?;
 
-    let auth_keypair = derive_auth_keypair(master_xpriv);
+    let auth_keypair = derive_auth_keypair(master_xpriv, derivation_scheme)?;
 
-    WalletKeys {
+    Ok(WalletKeys {
         wallet_keypair: auth_keypair,
+    })
+}
+
+fn parse_mnemonic(words: &[String]) -> Result<Mnemonic, SecretsError> {
+    // Reserved upfront, so no copy of the phrase is left behind when the string grows.
+    let capacity = words.iter().map(|word| word.len() + 1).sum();
+    let mut phrase = Zeroizing::new(String::with_capacity(capacity));
+    for word in words {
+        if !phrase.is_empty() {
+            phrase.push(' ');
+        }
+        phrase.push_str(&Zeroizing::new(normalize_word(word)));
     }
+    Mnemonic::parse_in(Language::English, phrase.as_str()).map_err(|e| match e {
+        bip39::Error::BadWordCount(count) => SecretsError::InvalidWordCount { count },
+        bip39::Error::UnknownWord(index) => SecretsError::UnknownWord {
+            index,
+            word: phrase
+                .split_whitespace()
+                .nth(index)
+                .unwrap_or_default()
+                .to_string(),
+        },
+        bip39::Error::InvalidChecksum => SecretsError::InvalidChecksum,
+        e => to_unexpected(e),
+    })
 }
 
-fn derive_auth_keypair(master_xpriv: ExtendedPrivKey) -> KeyPair {
-    let lipa_purpose_path = DerivationPath::from_str(BACKEND_AUTH_DERIVATION_PATH).unwrap();
+fn normalize_word(word: &str) -> String {
+    word.trim().to_lowercase()
+}
 
-    let auth_xpriv = master_xpriv
-        .derive_priv(SECP256K1, &lipa_purpose_path)
-        .unwrap();
+/// Erases the master key and the intermediate keys.
+fn derive_auth_keypair(
+    mut master_xpriv: ExtendedPrivKey,
+    derivation_scheme: &DerivationScheme,
+) -> Result<KeyPair, SecretsError> {
+    let path = derivation_scheme.path();
+    let derivation_path =
+        DerivationPath::from_str(path).map_err(|_| SecretsError::InvalidDerivationPath {
+            path: path.to_string(),
+        })?;
 
-    let auth_priv_key = auth_xpriv.private_key.secret_bytes().to_vec();
+    let auth_xpriv = master_xpriv.derive_priv(SECP256K1, &derivation_path);
+    master_xpriv.private_key.non_secure_erase();
+    let mut auth_xpriv = auth_xpriv.map_err(to_unexpected)?;
 
     let auth_pub_key = PublicKey::from_secret_key(SECP256K1, &auth_xpriv.private_key)
         .to_public_key()
         .to_bytes();
+    let secret_key = SecretKey {
+        secret_key: auth_xpriv.private_key,
+    };
+    auth_xpriv.private_key.non_secure_erase();
 
-    KeyPair {
-        secret_key: hex::encode(auth_priv_key),
+    Ok(KeyPair {
+        secret_key,
         public_key: hex::encode(auth_pub_key),
-    }
+    })
 }
 
-fn get_master_xpriv(network: Network, mnemonic: Mnemonic) -> ExtendedPrivKey {
-    let master_extended_key: ExtendedKey = mnemonic.into_extended_key().unwrap();
-    master_extended_key.into_xprv(network).unwrap()
+fn get_master_xpriv(
+    network: Network,
+    mnemonic: Mnemonic,
+    passphrase: Option<String>,
+) -> Result<ExtendedPrivKey, SecretsError> {
+    let master_extended_key: ExtendedKey = (mnemonic, passphrase)
+        .into_extended_key()
+        .map_err(to_unexpected)?;
+    master_extended_key
+        .into_xprv(network)
+        .ok_or_else(|| SecretsError::Unexpected {
+            msg: "The master key is not a private key".to_string(),
+        })
 }
 
-pub fn generate_keypair() -> KeyPair {
-    let mut rng = rand::rngs::OsRng;
+pub fn generate_keypair() -> Result<KeyPair, SecretsError> {
+    let bytes = generate_random_bytes()?;
+    let secret_key = SecretKey::from_bytes(&*bytes)?;
+
+    Ok(KeyPair {
+        public_key: secret_key.public_key(),
+        secret_key,
+    })
+}
+
+fn to_unexpected<E: Display>(error: E) -> SecretsError {
+    SecretsError::Unexpected {
+        msg: error.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MNEMONIC: &str = "abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon about";
+
+    #[test]
+    fn test_derive_keys() {
+        let keys = derive_keys(
+            Network::Testnet,
+            generate_mnemonic(24).unwrap(),
+            None,
+            &DerivationScheme::LegacyMasterKey,
+        )
+        .unwrap();
+        assert_eq!(keys.wallet_keypair.secret_key.export_hex().len(), 64);
+        assert_eq!(keys.wallet_keypair.public_key.len(), 66);
+
+        let keypair = derive(MNEMONIC, None, DerivationScheme::LegacyMasterKey).unwrap();
+        assert_eq!(
+            keypair.secret_key.export_hex(),
+            "1837c1be8e2995ec11cda2b066151be2cfb48adf9e47b151d46adab3a21cdf67"
+        );
+        assert_eq!(
+            keypair.public_key,
+            "03d902f35f560e0470c63313c7369168d9d7df2d49bf295fd9fb7cb109ccee0494"
+        );
+
+        let scheme = DerivationScheme::LegacyMasterKey;
+        assert_eq!(
+            derive(&MNEMONIC.replace("about", "abandon"), None, scheme.clone()).err(),
+            Some(SecretsError::InvalidChecksum)
+        );
+        assert_eq!(
+            derive(
+                &MNEMONIC.replacen("abandon", "abandn", 1),
+                None,
+                scheme.clone()
+            )
+            .err(),
+            Some(SecretsError::UnknownWord {
+                index: 0,
+                word: "abandn".to_string()
+            })
+        );
+        assert_eq!(
+            derive("abandon about", None, scheme).err(),
+            Some(SecretsError::InvalidWordCount { count: 2 })
+        );
+    }
 
-    let (secret_key, public_key) = SECP256K1.generate_keypair(&mut rng);
+    #[test]
+    fn test_derivation_schemes() {
+        let legacy = derive(MNEMONIC, None, DerivationScheme::LegacyMasterKey).unwrap();
+        let lipa_purpose = derive(MNEMONIC, None, DerivationScheme::LipaPurpose).unwrap();
+        assert_ne!(legacy.public_key, lipa_purpose.public_key);
+
+        let custom = |path: &str| {
+            derive(
+                MNEMONIC,
+                None,
+                DerivationScheme::Custom {
+                    path: path.to_string(),
+                },
+            )
+        };
+        assert_eq!(custom("m").unwrap().public_key, legacy.public_key);
+        assert_eq!(
+            custom("m/76738065'/0'/0").unwrap().public_key,
+            lipa_purpose.public_key
+        );
+        assert_ne!(
+            custom("m/76738065'/0'/1").unwrap().public_key,
+            lipa_purpose.public_key
+        );
+        assert_eq!(
+            custom("m/x").err(),
+            Some(SecretsError::InvalidDerivationPath {
+                path: "m/x".to_string()
+            })
+        );
+    }
+
+    #[test]
+    fn test_secret_key() {
+        let keypair = generate_keypair().unwrap();
+        let exported = keypair.secret_key.export_hex();
+        let secret_key = SecretKey::from_hex(&exported).unwrap();
+        assert_eq!(secret_key.export_hex(), exported);
+        assert_eq!(secret_key.public_key(), keypair.public_key);
+
+        assert_eq!(format!("{secret_key}"), "<redacted>");
+        assert!(!format!("{keypair:?}").contains(&exported));
+
+        assert_eq!(
+            SecretKey::from_hex("not hex").err(),
+            Some(SecretsError::InvalidHex)
+        );
+        assert_eq!(
+            SecretKey::from_hex(&"zz".repeat(32)).err(),
+            Some(SecretsError::InvalidHex)
+        );
+        assert_eq!(
+            SecretKey::from_hex("969063eb").err(),
+            Some(SecretsError::InvalidSecretKey)
+        );
+        assert_eq!(
+            SecretKey::from_hex(&"00".repeat(32)).err(),
+            Some(SecretsError::InvalidSecretKey)
+        );
+    }
+
+    #[test]
+    fn test_generate_mnemonic() {
+        for word_count in MNEMONIC_WORD_COUNTS {
+            let mnemonic = generate_mnemonic(word_count).unwrap();
+            assert_eq!(mnemonic.len(), word_count);
+            assert_eq!(validate_mnemonic(&mnemonic), Ok(()));
+        }
+        assert_eq!(
+            generate_mnemonic(13),
+            Err(SecretsError::InvalidWordCount { count: 13 })
+        );
+    }
+
+    #[test]
+    fn test_mnemonic_words() {
+        assert!(is_mnemonic_word("abandon"));
+        assert!(is_mnemonic_word(" Zoo "));
+        assert!(!is_mnemonic_word("abandn"));
+        assert!(!is_mnemonic_word(""));
+
+        assert_eq!(suggest_mnemonic_words("zo"), vec!["zone", "zoo"]);
+        assert_eq!(suggest_mnemonic_words("Abo"), vec!["about", "above"]);
+        assert_eq!(suggest_mnemonic_words("abandon"), vec!["abandon"]);
+        assert!(suggest_mnemonic_words("xyz").is_empty());
+        assert!(suggest_mnemonic_words("").is_empty());
+    }
+
+    #[test]
+    fn test_validate_mnemonic() {
+        assert_eq!(validate_mnemonic(&words(MNEMONIC)), Ok(()));
+        assert_eq!(validate_mnemonic(&words(&MNEMONIC.to_uppercase())), Ok(()));
+        assert_eq!(
+            validate_mnemonic(&words(&MNEMONIC.replace("about", "above"))),
+            Err(SecretsError::InvalidChecksum)
+        );
+        assert_eq!(
+            validate_mnemonic(&words(&MNEMONIC.replace("about", "abot"))),
+            Err(SecretsError::UnknownWord {
+                index: 11,
+                word: "abot".to_string()
+            })
+        );
+    }
+
+    #[test]
+    fn test_secrets_error_into_graphql_error() {
+        let error = graphql::errors::Error::from(SecretsError::InvalidChecksum);
+        assert!(matches!(error, graphql::errors::Error::InvalidInput { .. }));
+        let error = graphql::errors::Error::from(SecretsError::Unexpected {
+            msg: "No randomness".to_string(),
+        });
+        assert!(matches!(
+            error,
+            graphql::errors::Error::PermanentFailure { .. }
+        ));
+    }
+
+    #[test]
+    fn test_derive_keys_with_passphrase() {
+        let scheme = DerivationScheme::LegacyMasterKey;
+        let keypair = derive(MNEMONIC, None, scheme.clone()).unwrap();
+        let with_empty_passphrase = derive(MNEMONIC, Some(""), scheme.clone()).unwrap();
+        let with_passphrase = derive(MNEMONIC, Some("TREZOR"), scheme).unwrap();
+        assert_eq!(keypair.public_key, with_empty_passphrase.public_key);
+        assert_ne!(keypair.public_key, with_passphrase.public_key);
+
+        // First test vector of BIP39, with the legacy scheme the master key is the wallet key.
+        let mnemonic = parse_mnemonic(&words(MNEMONIC)).unwrap();
+        let master_xpriv =
+            get_master_xpriv(Network::Bitcoin, mnemonic, Some("TREZOR".to_string())).unwrap();
+        assert_eq!(
+            master_xpriv.to_string(),
+            "xprv9s21ZrQH143K3h3fDYiay8mocZ3afhfULfb5GX8kCBdno77K4HiA15Tg23wpbeF1pLfs1c5SPmYHrEpTuuRhxMwvKDwqdKiGJS9XFKzUsAF"
+        );
+        assert_eq!(
+            with_passphrase.secret_key.export_hex(),
+            hex::encode(master_xpriv.private_key.secret_bytes())
+        );
+    }
+
+    fn derive(
+        phrase: &str,
+        passphrase: Option<&str>,
+        derivation_scheme: DerivationScheme,
+    ) -> Result<KeyPair, SecretsError> {
+        let passphrase = passphrase.map(String::from);
+        derive_keys(
+            Network::Testnet,
+            words(phrase),
+            passphrase,
+            &derivation_scheme,
+        )
+        .map(|keys| keys.wallet_keypair)
+    }
 
-    KeyPair {
-        secret_key: hex::encode(secret_key.secret_bytes()),
-        public_key: hex::encode(public_key.serialize()),
+    fn words(phrase: &str) -> Vec<String> {
+        phrase.split(' ').map(String::from).collect()
     }
 }
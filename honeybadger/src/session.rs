@@ -0,0 +1,111 @@
+use graphql::errors::*;
+use graphql::perro::MapToError;
+use serde::{Deserialize, Serialize};
+use std::fmt::{Debug, Formatter};
+use std::fs;
+use std::path::PathBuf;
+use std::time::SystemTime;
+
+/// Snapshot of an authenticated session.
+///
+/// Allows to resume a session after a restart by refreshing it instead of
+/// running the full authentication flow again.
+#[derive(Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Session {
+    pub refresh_token: String,
+    pub access_token: String,
+    /// Expiry of the access token with the leeway already subtracted.
+    pub access_token_expires_at: SystemTime,
+    pub wallet_pubkey_id: String,
+}
+
+impl Debug for Session {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Session")
+            .field("refresh_token", &"<redacted>")
+            .field("access_token", &"<redacted>")
+            .field("access_token_expires_at", &self.access_token_expires_at)
+            .field("wallet_pubkey_id", &self.wallet_pubkey_id)
+            .finish()
+    }
+}
+
+pub trait SessionStore: Send + Sync {
+    fn load(&self) -> Result<Option<Session>>;
+    fn store(&self, session: &Session) -> Result<()>;
+}
+
+/// Stores the session as JSON in a file.
+pub struct FileSessionStore {
+    path: PathBuf,
+}
+
+impl FileSessionStore {
+    pub fn new(path: PathBuf) -> Self {
+        Self { path }
+    }
+}
+
+impl SessionStore for FileSessionStore {
+    fn load(&self) -> Result<Option<Session>> {
+        if !self.path.exists() {
+            return Ok(None);
+        }
+        let content = fs::read_to_string(&self.path)
+            .map_to_permanent_failure("Failed to read session file")?;
+        let session = serde_json::from_str(&content).map_to_runtime_error(
+            GraphQlRuntimeErrorCode::CorruptData,
+            "Failed to parse session file",
+        )?;
+        Ok(Some(session))
+    }
+
+    fn store(&self, session: &Session) -> Result<()> {
+        let content = serde_json::to_string(session)
+            .map_to_permanent_failure("Failed to serialize session")?;
+        // Write to a temporary file first to not leave a corrupted session behind.
+        let tmp_path = self.path.with_extension("tmp");
+        fs::write(&tmp_path, content).map_to_permanent_failure("Failed to write session file")?;
+        fs::rename(&tmp_path, &self.path).map_to_permanent_failure("Failed to move session file")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_file_session_store() {
+        let path = std::env::temp_dir().join(format!(
+            "honeybadger_session_{}.json",
+            rand::random::<u64>()
+        ));
+        let store = FileSessionStore::new(path.clone());
+        assert_eq!(store.load().unwrap(), None);
+
+        let session = Session {
+            refresh_token: "refresh".to_string(),
+            access_token: "access".to_string(),
+            access_token_expires_at: SystemTime::UNIX_EPOCH + Duration::from_secs(1695314361),
+            wallet_pubkey_id: "2b3b3f34-1c04-5f6d-9fd5-ae89a9c454be".to_string(),
+        };
+        store.store(&session).unwrap();
+        assert_eq!(store.load().unwrap(), Some(session.clone()));
+
+        let debug = format!("{session:?}");
+        assert!(!debug.contains("refresh\""));
+        assert!(!debug.contains("access\""));
+        assert!(debug.contains(&session.wallet_pubkey_id));
+
+        fs::write(&path, "not a session").unwrap();
+        assert!(matches!(
+            store.load(),
+            Err(Error::RuntimeError {
+                code: GraphQlRuntimeErrorCode::CorruptData,
+                ..
+            })
+        ));
+        fs::remove_file(path).unwrap();
+    }
+}
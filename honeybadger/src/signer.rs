@@ -0,0 +1,48 @@
+use crate::secrets::KeyPair;
+use crate::signing::sign;
+use async_trait::async_trait;
+use graphql::errors::Result;
+
+/// Holds a secp256k1 key pair and signs with it, e.g. in memory, on a hardware wallet
+/// or on a remote signing service. Used by [`asynchronous::Auth`](crate::asynchronous::Auth).
+#[async_trait]
+pub trait Signer: Send + Sync {
+    /// The compressed public key as a hex string.
+    async fn public_key(&self) -> Result<String>;
+
+    /// Signs the sha256 hash of the message and returns the DER encoded signature as hex.
+    async fn sign_message(&self, message: String) -> Result<String>;
+}
+
+/// Same as [`Signer`], but blocks the calling thread while signing.
+/// Used by the blocking [`Auth`](crate::Auth).
+pub trait BlockingSigner: Send + Sync {
+    /// The compressed public key as a hex string.
+    fn public_key(&self) -> Result<String>;
+
+    /// Signs the sha256 hash of the message and returns the DER encoded signature as hex.
+    fn sign_message(&self, message: String) -> Result<String>;
+}
+
+/// Signs in memory.
+#[async_trait]
+impl Signer for KeyPair {
+    async fn public_key(&self) -> Result<String> {
+        Ok(self.public_key.clone())
+    }
+
+    async fn sign_message(&self, message: String) -> Result<String> {
+        Ok(sign(message, &self.secret_key)?)
+    }
+}
+
+/// Signs in memory.
+impl BlockingSigner for KeyPair {
+    fn public_key(&self) -> Result<String> {
+        Ok(self.public_key.clone())
+    }
+
+    fn sign_message(&self, message: String) -> Result<String> {
+        Ok(sign(message, &self.secret_key)?)
+    }
+}
@@ -1,36 +1,31 @@
-//use crate::errors::{LipaResult, MapToLipaError};
-use bdk::bitcoin::hashes::hex::FromHex;
+use crate::secrets::{SecretKey, SecretsError};
 use bdk::bitcoin::hashes::sha256;
 use bdk::bitcoin::secp256k1::Message;
-use bdk::bitcoin::secp256k1::SecretKey;
 use secp256k1::SECP256K1;
 
-/*pub fn sign(message: String, private_key: String) -> LipaResult<String> {
+/// Signs the sha256 hash of the message and returns the DER encoded signature as hex.
+pub fn sign(message: String, secret_key: &SecretKey) -> Result<String, SecretsError> {
     let message = Message::from_hashed_data::<sha256::Hash>(message.as_bytes());
-    let secret_key_bytes =
-        Vec::from_hex(&private_key).map_to_invalid_input("Invalid private key string")?;
-    let secret_key = SecretKey::from_slice(secret_key_bytes.as_slice())
-        .map_to_invalid_input("Invalid private key string")?;
 
-    let sig = SECP256K1.sign_ecdsa(&message, &secret_key);
+    let sig = SECP256K1.sign_ecdsa(&message, secret_key.as_secp256k1());
 
     Ok(sig.serialize_der().to_string())
-}*/
-
-pub fn sign(message: String, private_key: String) -> String {
-    let message = Message::from_hashed_data::<sha256::Hash>(message.as_bytes());
-    let secret_key_bytes = Vec::from_hex(&private_key).unwrap();
-    let secret_key = SecretKey::from_slice(secret_key_bytes.as_slice()).unwrap();
+}
 
-    let sig = SECP256K1.sign_ecdsa(&message, &secret_key);
+/// Prefixes hex encoded data the way the backend expects it.
+pub fn add_hex_prefix(string: &str) -> String {
+    ["\\x", string].concat()
+}
 
-    sig.serialize_der().to_string()
+/// Prefixes a message the backend expects to be signed as a bitcoin message.
+pub fn add_bitcoin_message_prefix(string: &str) -> String {
+    ["\\x18Bitcoin Signed Message:", string].concat()
 }
-/*
+
 #[cfg(test)]
 mod tests {
+    use crate::secrets::{derive_keys, generate_mnemonic, DerivationScheme, SecretKey};
     use crate::signing::sign;
-    use crate::{derive_keys, generate_mnemonic};
     use bdk::bitcoin::hashes::hex::FromHex;
     use bdk::bitcoin::hashes::sha256;
     use bdk::bitcoin::secp256k1::ecdsa::Signature;
@@ -68,22 +63,28 @@ mod tests {
 
     #[test]
     fn test_sign_message() {
-        let mnemonic_string = generate_mnemonic().unwrap();
-        let keys = derive_keys(NETWORK, mnemonic_string).unwrap();
+        let mnemonic_string = generate_mnemonic(24).unwrap();
+        let keys = derive_keys(
+            NETWORK,
+            mnemonic_string,
+            None,
+            &DerivationScheme::LegacyMasterKey,
+        )
+        .unwrap();
 
         let message = String::from(MESSAGE_STR);
 
-        let sig = sign(message.clone(), keys.wallet_keypair.secret_key.clone()).unwrap();
+        let sig = sign(message.clone(), &keys.wallet_keypair.secret_key).unwrap();
 
         verify_sig(message, sig, keys.wallet_keypair.public_key).unwrap()
     }
 
     #[test]
     fn test_sign_message_precomputed_value() {
-        let private_key = EC_PRIVATE_KEY_HEX.to_string();
+        let private_key = SecretKey::from_hex(EC_PRIVATE_KEY_HEX).unwrap();
         let public_key = EC_PUBLIC_KEY_HEX.to_string();
 
-        let sig = sign(MESSAGE_STR.to_string(), private_key).unwrap();
+        let sig = sign(MESSAGE_STR.to_string(), &private_key).unwrap();
 
         verify_sig(MESSAGE_STR.to_string(), sig.clone(), public_key).unwrap();
         assert_eq!(sig, SIG_GOLDEN.to_string());
@@ -91,13 +92,12 @@ mod tests {
 
     #[test]
     fn test_sign_challenge_precomputed_value() {
-        let private_key = AUTH_PRIVATE_KEY_HEX.to_string();
+        let private_key = SecretKey::from_hex(AUTH_PRIVATE_KEY_HEX).unwrap();
         let public_key = AUTH_PUB_KEY_HEX.to_string();
 
-        let sig = sign(CHALLENGE_WITH_PREFIX.to_string(), private_key).unwrap();
+        let sig = sign(CHALLENGE_WITH_PREFIX.to_string(), &private_key).unwrap();
 
         verify_sig(CHALLENGE_WITH_PREFIX.to_string(), sig.clone(), public_key).unwrap();
         assert_eq!(sig, SIGNED_CHALLENGE_GOLDEN.to_string());
     }
 }
-*/
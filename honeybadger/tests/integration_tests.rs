@@ -1,10 +1,20 @@
+use async_trait::async_trait;
 use bdk::bitcoin::Network;
 use graphql::errors::{Error, GraphQlRuntimeErrorCode};
-use honeybadger::secrets::{derive_keys, generate_keypair, generate_mnemonic, KeyPair};
+use graphql::perro::runtime_error;
+use graphql::{BackendClient, BackendClientConfig};
+use honeybadger::secrets::{
+    derive_keys, generate_keypair, generate_mnemonic, DerivationScheme, KeyPair, SecretKey,
+};
+use honeybadger::session::{FileSessionStore, SessionStore};
+use honeybadger::signer::{BlockingSigner, Signer};
 use honeybadger::{Auth, AuthLevel, TermsAndConditions};
+use mockingbird::{wallet_pubkey_id, Failure, MockBackend};
 use simplelog::TestLogger;
 use std::env;
-use std::sync::Once;
+use std::fs;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Once};
 use std::thread::sleep;
 use std::time::Duration;
 
@@ -22,13 +32,12 @@ fn init() {
 fn test_invalid_url() {
     let (wallet_keypair, auth_keypair) = generate_keys();
 
-    let auth = Auth::new(
-        "localhost:9".to_string(),
+    let auth = Auth::with_client(
+        build_client("localhost:9".to_string()),
         AuthLevel::Pseudonymous,
         wallet_keypair,
         auth_keypair,
-    )
-    .unwrap();
+    );
 
     let result = auth.get_wallet_pubkey_id();
     assert!(matches!(
@@ -50,17 +59,34 @@ fn test_invalid_url() {
 }
 
 #[test]
-fn test_502_return() {
+#[allow(deprecated)]
+fn test_deprecated_constructor() {
     let (wallet_keypair, auth_keypair) = generate_keys();
 
     let auth = Auth::new(
-        "https://httpstat.us/502".to_string(),
+        get_backend_url(),
         AuthLevel::Pseudonymous,
         wallet_keypair,
         auth_keypair,
     )
     .unwrap();
 
+    auth.query_token().unwrap();
+}
+
+#[test]
+fn test_502_return() {
+    let (wallet_keypair, auth_keypair) = generate_keys();
+    let backend = MockBackend::start();
+    backend.fail_all(Failure::BadGateway);
+
+    let auth = Auth::with_client(
+        build_client(backend.url()),
+        AuthLevel::Pseudonymous,
+        wallet_keypair,
+        auth_keypair,
+    );
+
     let result = auth.get_wallet_pubkey_id();
     assert!(matches!(
         result,
@@ -80,17 +106,44 @@ fn test_502_return() {
     ));
 }
 
+#[test]
+fn test_auth_uses_client_config() {
+    let (wallet_keypair, auth_keypair) = generate_keys();
+    let backend = MockBackend::start();
+    let config = BackendClientConfig {
+        proxy: Some("http://localhost:9".to_string()),
+        ..BackendClientConfig::default()
+    };
+    let client = BackendClient::new(backend.url(), config).unwrap();
+
+    let auth = Auth::with_client(
+        client,
+        AuthLevel::Pseudonymous,
+        wallet_keypair,
+        auth_keypair,
+    );
+
+    let result = auth.query_token();
+    assert!(matches!(
+        result,
+        Err(Error::RuntimeError {
+            code: GraphQlRuntimeErrorCode::NetworkError,
+            ..
+        })
+    ));
+    assert_eq!(backend.request_count("RequestChallenge"), 0);
+}
+
 #[test]
 fn test_basic_auth() {
     let (wallet_keypair, auth_keypair) = generate_keys();
 
-    let auth = Auth::new(
-        get_backend_url(),
+    let auth = Auth::with_client(
+        build_client(get_backend_url()),
         AuthLevel::Pseudonymous,
         wallet_keypair,
         auth_keypair,
-    )
-    .unwrap();
+    );
 
     let _id = auth.get_wallet_pubkey_id().unwrap();
 
@@ -111,13 +164,12 @@ fn test_basic_auth() {
 fn test_owner_auth() {
     let (wallet_keypair, auth_keypair) = generate_keys();
 
-    let auth = Auth::new(
-        get_backend_url(),
+    let auth = Auth::with_client(
+        build_client(get_backend_url()),
         AuthLevel::Owner,
         wallet_keypair,
         auth_keypair,
-    )
-    .unwrap();
+    );
 
     let _id = auth.get_wallet_pubkey_id().unwrap();
 
@@ -135,16 +187,175 @@ fn test_owner_auth() {
 }
 
 #[test]
-fn test_employee_with_no_owner_auth() {
+fn test_concurrent_refresh_of_rejected_token() {
+    let backend = MockBackend::start();
     let (wallet_keypair, auth_keypair) = generate_keys();
+    let auth = Arc::new(Auth::with_client(
+        build_client(backend.url()),
+        AuthLevel::Pseudonymous,
+        wallet_keypair,
+        auth_keypair,
+    ));
+    let rejected_token = auth.query_token().unwrap();
 
-    let auth = Auth::new(
-        get_backend_url(),
-        AuthLevel::Employee,
+    sleep(Duration::from_secs(1));
+    let refreshed_tokens: Vec<String> = (0..4)
+        .map(|_| {
+            let auth = Arc::clone(&auth);
+            let rejected_token = rejected_token.clone();
+            std::thread::spawn(move || auth.refresh_rejected_token(&rejected_token).unwrap())
+        })
+        .collect::<Vec<_>>()
+        .into_iter()
+        .map(|handle| handle.join().unwrap())
+        .collect();
+
+    assert_eq!(backend.request_count("RefreshSession"), 1);
+    assert_ne!(refreshed_tokens[0], rejected_token);
+    assert!(refreshed_tokens.iter().all(|t| *t == refreshed_tokens[0]));
+}
+
+#[test]
+fn test_session_resumption() {
+    let (wallet_keypair, auth_keypair) = generate_keys();
+    let path = env::temp_dir().join(format!("session_{}.json", wallet_keypair.public_key));
+
+    let auth = Auth::new_with_session_store(
+        build_client(get_backend_url()),
+        AuthLevel::Pseudonymous,
+        copy_keypair(&wallet_keypair),
+        copy_keypair(&auth_keypair),
+        Box::new(FileSessionStore::new(path.clone())),
+    )
+    .unwrap();
+    assert!(auth.export_session().is_none());
+
+    let token = auth.query_token().unwrap();
+    let id = auth.get_wallet_pubkey_id().unwrap();
+    let session = auth.export_session().unwrap();
+    assert_eq!(session.access_token, token);
+    assert_eq!(session.wallet_pubkey_id, id);
+    drop(auth);
+
+    // Simulate a restart.
+    let auth = Auth::new_with_session_store(
+        build_client(get_backend_url()),
+        AuthLevel::Pseudonymous,
         wallet_keypair,
         auth_keypair,
+        Box::new(FileSessionStore::new(path.clone())),
     )
     .unwrap();
+    assert_eq!(auth.export_session(), Some(session));
+    assert_eq!(auth.query_token().unwrap(), token);
+    assert_eq!(auth.get_wallet_pubkey_id().unwrap(), id);
+
+    sleep(Duration::from_secs(1));
+    let refreshed_token = auth.refresh_token().unwrap();
+    assert_ne!(token, refreshed_token);
+    assert_eq!(auth.get_wallet_pubkey_id().unwrap(), id);
+
+    let stored_session = FileSessionStore::new(path.clone()).load().unwrap().unwrap();
+    assert_eq!(stored_session.access_token, refreshed_token);
+    fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn test_session_resumption_with_signers() {
+    let backend = MockBackend::start();
+    let (wallet_keypair, auth_keypair) = generate_keys();
+    let path = env::temp_dir().join(format!("session_{}.json", wallet_keypair.public_key));
+    let wallet_signer = CountingSigner::new(wallet_keypair);
+    let auth_signer = CountingSigner::new(auth_keypair);
+    let new_auth = || {
+        Auth::new_with_signers_and_session_store(
+            build_client(backend.url()),
+            AuthLevel::Pseudonymous,
+            Box::new(wallet_signer.clone()),
+            Box::new(auth_signer.clone()),
+            Box::new(FileSessionStore::new(path.clone())),
+        )
+        .unwrap()
+    };
+
+    let auth = new_auth();
+    let token = auth.query_token().unwrap();
+    assert_eq!(auth_signer.signatures.load(Ordering::SeqCst), 1);
+    drop(auth);
+
+    // After a restart the stored session is used without signing again.
+    let auth = new_auth();
+    assert_eq!(auth.query_token().unwrap(), token);
+    assert_eq!(auth_signer.signatures.load(Ordering::SeqCst), 1);
+    assert_eq!(backend.request_count("StartSession"), 1);
+    fs::remove_file(path).unwrap();
+}
+
+#[tokio::test(flavor = "current_thread")]
+async fn test_async_session_resumption() {
+    let backend = MockBackend::start();
+    let (wallet_keypair, auth_keypair) = generate_keys();
+    let path = env::temp_dir().join(format!("session_{}.json", wallet_keypair.public_key));
+    let new_auth = |wallet_keypair, auth_keypair| {
+        honeybadger::asynchronous::Auth::new_with_session_store(
+            graphql::asynchronous::BackendClient::new(
+                backend.url(),
+                BackendClientConfig::default(),
+            )
+            .unwrap(),
+            AuthLevel::Pseudonymous,
+            wallet_keypair,
+            auth_keypair,
+            Box::new(FileSessionStore::new(path.clone())),
+        )
+    };
+
+    let auth = new_auth(copy_keypair(&wallet_keypair), copy_keypair(&auth_keypair))
+        .await
+        .unwrap();
+    let token = auth.query_token().await.unwrap();
+    let stored_session = FileSessionStore::new(path.clone()).load().unwrap().unwrap();
+    assert_eq!(stored_session.access_token, token);
+    drop(auth);
+
+    // Simulate a restart.
+    let auth = new_auth(wallet_keypair, auth_keypair).await.unwrap();
+    assert_eq!(auth.query_token().await.unwrap(), token);
+    assert_eq!(backend.request_count("StartSession"), 1);
+    fs::remove_file(path).unwrap();
+}
+
+#[tokio::test(flavor = "current_thread")]
+async fn test_async_auth_with_signers() {
+    let backend = MockBackend::start();
+    let (wallet_keypair, auth_keypair) = generate_keys();
+    let wallet_id = wallet_pubkey_id(&wallet_keypair.public_key);
+    let auth = honeybadger::asynchronous::Auth::new_with_signers(
+        graphql::asynchronous::BackendClient::new(backend.url(), BackendClientConfig::default())
+            .unwrap(),
+        AuthLevel::Owner,
+        Box::new(DelayedSigner {
+            keypair: wallet_keypair,
+        }),
+        Box::new(DelayedSigner {
+            keypair: auth_keypair,
+        }),
+    );
+
+    auth.query_token().await.unwrap();
+    assert_eq!(auth.get_wallet_pubkey_id().await.unwrap(), wallet_id);
+}
+
+#[test]
+fn test_employee_with_no_owner_auth() {
+    let (wallet_keypair, auth_keypair) = generate_keys();
+
+    let auth = Auth::with_client(
+        build_client(get_backend_url()),
+        AuthLevel::Employee,
+        wallet_keypair,
+        auth_keypair,
+    );
 
     let result = auth.get_wallet_pubkey_id();
     assert!(matches!(result, Err(Error::InvalidInput { .. })));
@@ -159,13 +370,12 @@ fn test_employee_with_no_owner_auth() {
 fn test_employee_auth() {
     let (wallet_keypair, auth_keypair) = generate_keys();
 
-    let auth = Auth::new(
-        get_backend_url(),
+    let auth = Auth::with_client(
+        build_client(get_backend_url()),
         AuthLevel::Employee,
         wallet_keypair,
         auth_keypair,
-    )
-    .unwrap();
+    );
 
     let _id = auth.get_wallet_pubkey_id().unwrap();
 
@@ -182,16 +392,129 @@ fn test_employee_auth() {
     assert_eq!(auth.get_wallet_pubkey_id().unwrap(), id);
 }
 
+#[test]
+fn test_employee_auth_with_mock_backend() {
+    let backend = MockBackend::start();
+    let (owner_wallet_keypair, owner_auth_keypair) = generate_keys();
+    let owner_auth = Auth::with_client(
+        build_client(backend.url()),
+        AuthLevel::Owner,
+        owner_wallet_keypair,
+        owner_auth_keypair,
+    );
+    let owner_id = owner_auth.get_wallet_pubkey_id().unwrap();
+
+    let (wallet_keypair, auth_keypair) = generate_keys();
+    let employee_id = wallet_pubkey_id(&wallet_keypair.public_key);
+    backend.add_employee(&owner_id, &employee_id, None);
+    let auth = Auth::with_client(
+        build_client(backend.url()),
+        AuthLevel::Employee,
+        wallet_keypair,
+        auth_keypair,
+    );
+
+    let token = auth.query_token().unwrap();
+    assert_eq!(auth.get_wallet_pubkey_id().unwrap(), employee_id);
+    assert_eq!(backend.request_count("UnlockWallet"), 2);
+
+    sleep(Duration::from_secs(1));
+    let refreshed_token = auth.refresh_token().unwrap();
+    assert_ne!(token, refreshed_token);
+    assert_eq!(auth.get_wallet_pubkey_id().unwrap(), employee_id);
+}
+
+#[test]
+fn test_wallet_key_migration() {
+    let backend = MockBackend::start();
+    let mnemonic = generate_mnemonic(12).unwrap();
+    let derive = |derivation_scheme| {
+        derive_keys(Network::Testnet, mnemonic.clone(), None, &derivation_scheme)
+            .unwrap()
+            .wallet_keypair
+    };
+    let legacy_id = wallet_pubkey_id(&derive(DerivationScheme::LegacyMasterKey).public_key);
+    let new_id = wallet_pubkey_id(&derive(DerivationScheme::LipaPurpose).public_key);
+    let auth = Auth::with_client(
+        build_client(backend.url()),
+        AuthLevel::Owner,
+        derive(DerivationScheme::LegacyMasterKey),
+        generate_keypair().unwrap(),
+    );
+    let token = auth.query_token().unwrap();
+
+    backend.fail_next(
+        "StartSession",
+        Failure::ErrorCode("authentication-exception".to_string()),
+    );
+    let result = auth.migrate_wallet_keypair(derive(DerivationScheme::LipaPurpose));
+    assert!(result.is_err());
+    assert_eq!(auth.get_wallet_pubkey_id().unwrap(), legacy_id);
+
+    let migration = auth
+        .migrate_wallet_keypair(derive(DerivationScheme::LipaPurpose))
+        .unwrap();
+    assert_eq!(migration.old_wallet_pubkey_id, legacy_id);
+    assert_eq!(migration.new_wallet_pubkey_id, new_id);
+    assert_eq!(auth.get_wallet_pubkey_id().unwrap(), new_id);
+    assert_ne!(auth.query_token().unwrap(), token);
+}
+
+#[test]
+fn test_auth_with_signers() {
+    let backend = MockBackend::start();
+    let (wallet_keypair, auth_keypair) = generate_keys();
+    let wallet_id = wallet_pubkey_id(&wallet_keypair.public_key);
+    let wallet_signer = CountingSigner::new(wallet_keypair);
+    let auth_signer = CountingSigner::new(auth_keypair);
+    let auth = Auth::new_with_signers(
+        build_client(backend.url()),
+        AuthLevel::Owner,
+        Box::new(wallet_signer.clone()),
+        Box::new(auth_signer.clone()),
+    );
+
+    auth.query_token().unwrap();
+    assert_eq!(auth.get_wallet_pubkey_id().unwrap(), wallet_id);
+    // The wallet key signs the auth public key and the challenge of the owner session.
+    assert_eq!(wallet_signer.signatures.load(Ordering::SeqCst), 2);
+    assert_eq!(auth_signer.signatures.load(Ordering::SeqCst), 1);
+
+    // Signing again is only needed for a new session.
+    auth_signer.unavailable.store(true, Ordering::SeqCst);
+    auth.refresh_token().unwrap();
+    let new_wallet_signer = CountingSigner::new(generate_keys().0);
+    let result = auth.migrate_wallet_signer(Box::new(new_wallet_signer.clone()));
+    assert!(matches!(
+        result,
+        Err(Error::RuntimeError {
+            code: GraphQlRuntimeErrorCode::AuthServiceError,
+            ..
+        })
+    ));
+    assert_eq!(auth.get_wallet_pubkey_id().unwrap(), wallet_id);
+
+    auth_signer.unavailable.store(false, Ordering::SeqCst);
+    let migration = auth
+        .migrate_wallet_signer(Box::new(new_wallet_signer.clone()))
+        .unwrap();
+    assert_eq!(migration.old_wallet_pubkey_id, wallet_id);
+    assert_eq!(
+        auth.get_wallet_pubkey_id().unwrap(),
+        migration.new_wallet_pubkey_id
+    );
+    assert_eq!(new_wallet_signer.signatures.load(Ordering::SeqCst), 2);
+}
+
 #[test]
 fn test_accept_terms_and_conditions() {
     let (wallet_keypair, auth_keypair) = generate_keys();
-    let auth = Auth::new(
-        get_backend_url(),
+    let auth = Auth::with_client(
+        build_client(get_backend_url()),
         AuthLevel::Pseudonymous,
         wallet_keypair,
         auth_keypair,
-    )
-    .unwrap();
+    );
     auth.accept_terms_and_conditions(
         TermsAndConditions::Lipa,
         3,
@@ -206,13 +529,12 @@ fn test_accept_terms_and_conditions() {
     );
 
     let (wallet_keypair, auth_keypair) = generate_keys();
-    let auth = Auth::new(
-        get_backend_url(),
+    let auth = Auth::with_client(
+        build_client(get_backend_url()),
         AuthLevel::Owner,
         wallet_keypair,
         auth_keypair,
-    )
-    .unwrap();
+    );
     let result =
         auth.accept_terms_and_conditions(TermsAndConditions::Lipa, 3, "fingerprint2".into());
     assert!(
@@ -220,13 +542,12 @@ fn test_accept_terms_and_conditions() {
     );
 
     let (wallet_keypair, auth_keypair) = generate_keys();
-    let auth = Auth::new(
-        get_backend_url(),
+    let auth = Auth::with_client(
+        build_client(get_backend_url()),
         AuthLevel::Pseudonymous,
         wallet_keypair,
         auth_keypair,
-    )
-    .unwrap();
+    );
     let result =
         auth.accept_terms_and_conditions(TermsAndConditions::Pocket, 3, "fingerprint3".into());
     assert!(
@@ -234,13 +555,12 @@ fn test_accept_terms_and_conditions() {
     );
 
     let (wallet_keypair, auth_keypair) = generate_keys();
-    let auth = Auth::new(
-        get_backend_url(),
+    let auth = Auth::with_client(
+        build_client(get_backend_url()),
         AuthLevel::Owner,
         wallet_keypair,
         auth_keypair,
-    )
-    .unwrap();
+    );
     let result =
         auth.accept_terms_and_conditions(TermsAndConditions::Pocket, 4, "fingerprint4".into());
     assert!(
@@ -250,14 +570,86 @@ fn test_accept_terms_and_conditions() {
 
 fn generate_keys() -> (KeyPair, KeyPair) {
     println!("Generating keys ...");
-    let mnemonic = generate_mnemonic();
+    let mnemonic = generate_mnemonic(24).unwrap();
     println!("mnemonic: {mnemonic:?}");
-    let wallet_keys = derive_keys(Network::Testnet, mnemonic).wallet_keypair;
-    let auth_keys = generate_keypair();
+    let wallet_keys = derive_keys(
+        Network::Testnet,
+        mnemonic,
+        None,
+        &DerivationScheme::LegacyMasterKey,
+    )
+    .unwrap()
+    .wallet_keypair;
+    let auth_keys = generate_keypair().unwrap();
 
     (wallet_keys, auth_keys)
 }
 
+fn copy_keypair(keypair: &KeyPair) -> KeyPair {
+    KeyPair {
+        secret_key: SecretKey::from_hex(&keypair.secret_key.export_hex()).unwrap(),
+        public_key: keypair.public_key.clone(),
+    }
+}
+
+/// Signs in memory and counts the signatures, like a hardware wallet would ask the user to
+/// confirm each of them.
+#[derive(Clone)]
+struct CountingSigner {
+    keypair: Arc<KeyPair>,
+    signatures: Arc<AtomicUsize>,
+    unavailable: Arc<AtomicBool>,
+}
+
+impl CountingSigner {
+    fn new(keypair: KeyPair) -> Self {
+        Self {
+            keypair: Arc::new(keypair),
+            signatures: Arc::new(AtomicUsize::new(0)),
+            unavailable: Arc::new(AtomicBool::new(false)),
+        }
+    }
+}
+
+impl BlockingSigner for CountingSigner {
+    fn public_key(&self) -> graphql::errors::Result<String> {
+        BlockingSigner::public_key(self.keypair.as_ref())
+    }
+
+    fn sign_message(&self, message: String) -> graphql::errors::Result<String> {
+        if self.unavailable.load(Ordering::SeqCst) {
+            return Err(runtime_error(
+                GraphQlRuntimeErrorCode::AuthServiceError,
+                "Signer is unavailable",
+            ));
+        }
+        self.signatures.fetch_add(1, Ordering::SeqCst);
+        BlockingSigner::sign_message(self.keypair.as_ref(), message)
+    }
+}
+
+/// Signs in memory after a delay, like a remote signing service would.
+struct DelayedSigner {
+    keypair: KeyPair,
+}
+
+#[async_trait]
+impl Signer for DelayedSigner {
+    async fn public_key(&self) -> graphql::errors::Result<String> {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        Signer::public_key(&self.keypair).await
+    }
+
+    async fn sign_message(&self, message: String) -> graphql::errors::Result<String> {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        Signer::sign_message(&self.keypair, message).await
+    }
+}
+
+fn build_client(backend_url: String) -> BackendClient {
+    BackendClient::new(backend_url, BackendClientConfig::default()).unwrap()
+}
+
 fn get_backend_url() -> String {
-    env::var("GRAPHQL_API_URL").expect("GRAPHQL_API_URL environment variable is not set")
+    mockingbird::backend_url()
 }
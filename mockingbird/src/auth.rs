@@ -0,0 +1,128 @@
+use base64::{engine::general_purpose, Engine as _};
+use rand::RngCore;
+use secp256k1::ecdsa::Signature;
+use secp256k1::hashes::{sha256, Hash};
+use secp256k1::{Message, PublicKey, SECP256K1};
+use serde_json::json;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The wallet public key id the mock backend assigns to a wallet public key.
+///
+/// It is derived deterministically, so tests can know it before authenticating.
+pub fn wallet_pubkey_id(wallet_pub_key: &str) -> String {
+    let wallet_pub_key = strip_hex_prefix(wallet_pub_key).to_lowercase();
+    let hash = sha256::Hash::hash(wallet_pub_key.as_bytes()).to_byte_array();
+    let hex = hex::encode(&hash[..16]);
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..]
+    )
+}
+
+/// Verifies a hex encoded DER signature of the message the way the backend does.
+pub(crate) fn verify_signature(message: &str, signature: &str, public_key: &str) -> bool {
+    let message = Message::from_hashed_data::<sha256::Hash>(message.as_bytes());
+    let signature = match hex::decode(strip_hex_prefix(signature))
+        .ok()
+        .and_then(|s| Signature::from_der(&s).ok())
+    {
+        Some(signature) => signature,
+        None => return false,
+    };
+    let public_key = match hex::decode(strip_hex_prefix(public_key))
+        .ok()
+        .and_then(|p| PublicKey::from_slice(&p).ok())
+    {
+        Some(public_key) => public_key,
+        None => return false,
+    };
+    SECP256K1
+        .verify_ecdsa(&message, &signature, &public_key)
+        .is_ok()
+}
+
+pub(crate) fn add_bitcoin_message_prefix(string: &str) -> String {
+    ["\\x18Bitcoin Signed Message:", string].concat()
+}
+
+pub(crate) fn strip_hex_prefix(string: &str) -> &str {
+    string.strip_prefix("\\x").unwrap_or(string)
+}
+
+/// Issues an unsigned JWT which is good enough for clients parsing the expiry.
+pub(crate) fn issue_jwt(wallet_pubkey_id: &str, expires_at: SystemTime) -> String {
+    let header = json!({"typ": "JWT", "alg": "none"});
+    let expires_at = expires_at
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let body = json!({
+        "https://hasura.io/jwt/claims": {
+            "x-hasura-default-role": "WALLET_READ",
+            "x-hasura-wallet-pub-key-id": wallet_pubkey_id,
+        },
+        "iss": "mockingbird",
+        "jti": random_token(),
+        "exp": expires_at,
+    });
+    [
+        general_purpose::STANDARD_NO_PAD.encode(header.to_string()),
+        general_purpose::STANDARD_NO_PAD.encode(body.to_string()),
+        general_purpose::STANDARD_NO_PAD.encode("mockingbird"),
+    ]
+    .join(".")
+}
+
+pub(crate) fn random_token() -> String {
+    let mut bytes = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    hex::encode(bytes)
+}
+
+pub(crate) fn random_uuid() -> String {
+    wallet_pubkey_id(&random_token())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use secp256k1::SecretKey;
+
+    #[test]
+    fn test_verify_signature() {
+        let secret_key = SecretKey::from_slice(&[0x42; 32]).unwrap();
+        let public_key =
+            hex::encode(PublicKey::from_secret_key(SECP256K1, &secret_key).serialize());
+        let message = add_bitcoin_message_prefix("challenge");
+        let signature = SECP256K1.sign_ecdsa(
+            &Message::from_hashed_data::<sha256::Hash>(message.as_bytes()),
+            &secret_key,
+        );
+        let signature = format!("\\x{}", signature.serialize_der());
+
+        assert!(verify_signature(&message, &signature, &public_key));
+        assert!(verify_signature(
+            &message,
+            &signature,
+            &format!("\\x{public_key}")
+        ));
+        assert!(!verify_signature(
+            "another message",
+            &signature,
+            &public_key
+        ));
+        assert!(!verify_signature(&message, "\\xdeadbeef", &public_key));
+    }
+
+    #[test]
+    fn test_wallet_pubkey_id() {
+        let id = wallet_pubkey_id("\\x02aa");
+        assert_eq!(id, wallet_pubkey_id("02AA"));
+        assert_eq!(id.len(), 36);
+        assert_ne!(id, wallet_pubkey_id("02ab"));
+    }
+}
@@ -0,0 +1,209 @@
+mod auth;
+mod operations;
+mod server;
+mod state;
+mod subscriptions;
+
+pub use crate::auth::wallet_pubkey_id;
+pub use crate::state::Topup;
+
+use crate::state::{State, Update};
+use std::env;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::thread::JoinHandle;
+use std::time::{Duration, SystemTime};
+use tokio::sync::oneshot;
+
+/// The one time password accepted when verifying a phone number.
+pub const PHONE_VERIFICATION_OTP: &str = "123456";
+
+static SHARED_MOCK_BACKEND: OnceLock<MockBackend> = OnceLock::new();
+
+/// The backend url integration tests should run against.
+///
+/// It is the value of `GRAPHQL_API_URL` if the environment variable is set,
+/// otherwise the url of a mock backend shared by all tests of the test binary.
+pub fn backend_url() -> String {
+    match env::var("GRAPHQL_API_URL") {
+        Ok(url) => url,
+        Err(_) => SHARED_MOCK_BACKEND.get_or_init(MockBackend::start).url(),
+    }
+}
+
+/// A failure the mock backend should respond with instead of executing an operation.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Failure {
+    /// Respond with HTTP status 502.
+    BadGateway,
+    /// Respond with a GraphQL error carrying the given code in its extensions,
+    /// e.g. `invalid-jwt` or `authentication-exception`.
+    ErrorCode(String),
+}
+
+/// In-process mock of the Lipa backend GraphQL API.
+///
+/// The server runs on its own thread and runtime, so it can be used from
+/// blocking as well as from async tests. It is shut down on drop.
+pub struct MockBackend {
+    address: SocketAddr,
+    state: Arc<Mutex<State>>,
+    shutdown: Option<oneshot::Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl MockBackend {
+    pub fn start() -> Self {
+        let state = Arc::new(Mutex::new(State::new()));
+        let (shutdown, shutdown_receiver) = oneshot::channel();
+        let (address, thread) = server::spawn(Arc::clone(&state), shutdown_receiver);
+        Self {
+            address,
+            state,
+            shutdown: Some(shutdown),
+            thread: Some(thread),
+        }
+    }
+
+    pub fn url(&self) -> String {
+        format!("http://{}/v1/graphql", self.address)
+    }
+
+    /// Url to use the mock backend as an HTTP proxy, tunneled connections count as `CONNECT` requests.
+    pub fn proxy_url(&self) -> String {
+        format!("http://{}", self.address)
+    }
+
+    /// Fails the next execution of the operation (e.g. `StartSession`).
+    ///
+    /// Calling it multiple times queues up multiple failures.
+    pub fn fail_next(&self, operation: &str, failure: Failure) {
+        self.state
+            .lock()
+            .unwrap()
+            .failures
+            .entry(operation.to_string())
+            .or_default()
+            .push_back(failure);
+    }
+
+    /// Fails every request until [`MockBackend::clear_failures`] is called.
+    pub fn fail_all(&self, failure: Failure) {
+        self.state.lock().unwrap().permanent_failure = Some(failure);
+    }
+
+    pub fn clear_failures(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.failures.clear();
+        state.permanent_failure = None;
+    }
+
+    /// Sets the lifetime of access tokens issued from now on.
+    pub fn set_access_token_lifetime(&self, lifetime: Duration) {
+        self.state.lock().unwrap().access_token_lifetime = lifetime;
+    }
+
+    /// Revokes all issued access tokens, refresh tokens stay valid.
+    pub fn revoke_access_tokens(&self) {
+        self.state.lock().unwrap().access_tokens.clear();
+    }
+
+    /// Revokes all issued access and refresh tokens.
+    pub fn revoke_sessions(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.access_tokens.clear();
+        state.refresh_tokens.clear();
+    }
+
+    pub fn set_exchange_rate(&self, currency_code: &str, sats_per_unit: u32) {
+        self.set_exchange_rate_updated_at(currency_code, sats_per_unit, SystemTime::now());
+    }
+
+    pub fn set_exchange_rate_updated_at(
+        &self,
+        currency_code: &str,
+        sats_per_unit: u32,
+        updated_at: SystemTime,
+    ) {
+        self.state
+            .lock()
+            .unwrap()
+            .set_exchange_rate(currency_code, sats_per_unit, updated_at);
+    }
+
+    pub fn add_topup(&self, wallet_pubkey_id: &str, topup: Topup) {
+        let mut state = self.state.lock().unwrap();
+        state.topups.push((wallet_pubkey_id.to_string(), topup));
+        state.notify(Update::Changed);
+    }
+
+    pub fn set_topup_status(&self, id: &str, status: &str, additional_info: Option<String>) {
+        let mut state = self.state.lock().unwrap();
+        if let Some((_, topup)) = state.topups.iter_mut().find(|(_, t)| t.id == id) {
+            topup.status = status.to_string();
+            topup.additional_info = additional_info;
+        }
+        state.notify(Update::Changed);
+    }
+
+    /// Drops the connections of all open subscriptions.
+    pub fn disconnect_subscriptions(&self) {
+        self.state.lock().unwrap().notify(Update::Disconnect);
+    }
+
+    /// Allows the member wallet to start privileged sessions for the owner wallet.
+    pub fn add_employee(
+        &self,
+        owner_wallet_pubkey_id: &str,
+        member_wallet_pubkey_id: &str,
+        access_expires_at: Option<SystemTime>,
+    ) {
+        self.state
+            .lock()
+            .unwrap()
+            .wallet_acl
+            .push(state::WalletAcl {
+                owner_wallet_pubkey_id: owner_wallet_pubkey_id.to_string(),
+                member_wallet_pubkey_id: member_wallet_pubkey_id.to_string(),
+                access_expires_at,
+            });
+    }
+
+    /// Number of requests received per operation name.
+    pub fn request_count(&self, operation: &str) -> usize {
+        self.state
+            .lock()
+            .unwrap()
+            .request_counts
+            .get(operation)
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// Value of the header in the last websocket upgrade request.
+    pub fn subscription_header(&self, name: &str) -> Option<String> {
+        self.state
+            .lock()
+            .unwrap()
+            .subscription_headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.clone())
+    }
+
+    /// Telemetry events reported so far, as received.
+    pub fn telemetry_events(&self) -> Vec<serde_json::Value> {
+        self.state.lock().unwrap().telemetry_events.clone()
+    }
+}
+
+impl Drop for MockBackend {
+    fn drop(&mut self) {
+        if let Some(shutdown) = self.shutdown.take() {
+            let _ = shutdown.send(());
+        }
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
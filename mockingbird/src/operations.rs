@@ -0,0 +1,945 @@
+use crate::auth::{
+    add_bitcoin_message_prefix, issue_jwt, random_token, random_uuid, strip_hex_prefix,
+    verify_signature, wallet_pubkey_id,
+};
+use crate::state::{
+    AccessToken, Backup, Country, LightningAddress, Permit, PreparedSession, State, Topup,
+    TopupSetup, Update,
+};
+use crate::PHONE_VERIFICATION_OTP;
+use graphql::{parse_from_rfc3339, ToRfc3339};
+use serde_json::{json, Value};
+use std::cmp::Ordering;
+use std::time::SystemTime;
+
+const AUTH_EXCEPTION_CODE: &str = "authentication-exception";
+const INVALID_JWT_ERROR_CODE: &str = "invalid-jwt";
+const INVALID_FINGERPRINT: &str = "invalid-fingerprint";
+const NOT_FOUND_CODE: &str = "not-found";
+const VALIDATION_FAILED_CODE: &str = "validation-failed";
+
+const UNCOMPLETED_TOPUP_STATUSES: [&str; 3] = ["READY", "FAILED", "REFUNDED"];
+
+pub(crate) struct OperationError {
+    pub code: String,
+    pub message: String,
+}
+
+impl OperationError {
+    pub fn new(code: &str, message: &str) -> Self {
+        Self {
+            code: code.to_string(),
+            message: message.to_string(),
+        }
+    }
+}
+
+type OperationResult = Result<Value, OperationError>;
+
+pub(crate) fn execute(
+    state: &mut State,
+    operation: &str,
+    variables: &Value,
+    access_token: Option<&str>,
+) -> OperationResult {
+    match operation {
+        "RequestChallenge" => Ok(request_challenge(state)),
+        "StartSession" => start_session(state, variables),
+        "RefreshSession" => refresh_session(state, variables),
+        _ => {
+            let permit = authorize(state, access_token)?;
+            execute_authorized(state, operation, variables, permit)
+        }
+    }
+}
+
+fn execute_authorized(
+    state: &mut State,
+    operation: &str,
+    variables: &Value,
+    permit: Permit,
+) -> OperationResult {
+    match operation {
+        "PrepareWalletSession" => prepare_wallet_session(state, variables, permit),
+        "UnlockWallet" => unlock_wallet(state, variables, permit),
+        "AcceptTermsAndConditionsV2" => accept_terms_and_conditions(state, variables, permit),
+        "GetTermsAndConditionsStatus" => get_terms_and_conditions_status(state, variables, permit),
+        "GetBusinessOwner" => get_business_owner(state, variables),
+        "GetExchangeRate" => get_exchange_rate(state, variables),
+        "GetAllExchangeRates" => Ok(get_all_exchange_rates(state)),
+        "ListCurrencyCodes" => Ok(list_currency_codes(state)),
+        "ListCountries" => Ok(list_countries(state)),
+        "GetCountry" => get_country(state, variables),
+        "RegisterTopup" => register_topup(state, variables, permit),
+        "RegisterNotificationToken" => register_notification_token(state, variables, permit),
+        "HideTopup" => hide_topup(state, variables, permit),
+        "ListUncompletedTopups" => Ok(list_uncompleted_topups(state, permit)),
+        "ListTopups" => list_topups(state, variables, permit),
+        "GetTopup" => get_topup(state, variables, permit),
+        "MigrationBalance" => Ok(json!({ "migration_balance": { "balanceAmountSat": 0 } })),
+        "MigrateFunds" => Ok(json!({ "migrate_funds": true })),
+        "CreateBackup" => create_backup(state, variables, permit),
+        "RecoverBackup" => recover_backup(state, variables, permit),
+        "ReportPaymentTelemetry" => Ok(report_payment_telemetry(state, variables)),
+        "AssignLightningAddress" => Ok(assign_lightning_address(state, permit)),
+        "SubmitLnurlPayInvoice" => {
+            string(variables, "id")?;
+            Ok(json!({ "submit_lnurl_pay_invoice": null }))
+        }
+        "RequestPhoneNumberVerification" => {
+            request_phone_number_verification(state, variables, permit)
+        }
+        "VerifyPhoneNumber" => verify_phone_number(state, variables, permit),
+        "VerifiedPhoneNumber" => Ok(verified_phone_number(state, permit)),
+        "DisableLightningAddresses" => {
+            set_lightning_addresses_enabled(state, variables, permit, false)
+                .map(|()| json!({ "disable_lightning_addresses": null }))
+        }
+        "EnableLightningAddresses" => {
+            set_lightning_addresses_enabled(state, variables, permit, true)
+                .map(|()| json!({ "enable_lightning_addresses": null }))
+        }
+        "StartTopupSetup" => start_topup_setup(state, variables, permit),
+        "CompleteTopupSetup" => complete_topup_setup(state, variables, permit),
+        _ => Err(OperationError::new(
+            VALIDATION_FAILED_CODE,
+            &format!("Unknown operation: {operation}"),
+        )),
+    }
+}
+
+pub(crate) fn authorize(
+    state: &State,
+    access_token: Option<&str>,
+) -> Result<Permit, OperationError> {
+    let access_token = access_token
+        .and_then(|t| state.access_tokens.get(t))
+        .ok_or_else(|| OperationError::new(INVALID_JWT_ERROR_CODE, "Could not verify JWT"))?;
+    if access_token.expires_at <= SystemTime::now() {
+        return Err(OperationError::new(
+            INVALID_JWT_ERROR_CODE,
+            "Could not verify JWT: JWTExpired",
+        ));
+    }
+    Ok(access_token.permit.clone())
+}
+
+fn issue_session(state: &mut State, permit: Permit) -> (String, String) {
+    let expires_at = SystemTime::now() + state.access_token_lifetime;
+    let access_token = issue_jwt(&permit.wallet_pubkey_id, expires_at);
+    let refresh_token = random_token();
+    state.access_tokens.insert(
+        access_token.clone(),
+        AccessToken {
+            permit: permit.clone(),
+            expires_at,
+        },
+    );
+    state.refresh_tokens.insert(refresh_token.clone(), permit);
+    (access_token, refresh_token)
+}
+
+fn request_challenge(state: &mut State) -> Value {
+    let challenge = random_token();
+    state.challenges.insert(challenge.clone());
+    json!({ "auth_challenge": challenge })
+}
+
+fn start_session(state: &mut State, variables: &Value) -> OperationResult {
+    let auth_pub_key = string(variables, "authPubKey")?;
+    let challenge = string(variables, "challenge")?;
+    let challenge_signature = string(variables, "challengeSignature")?;
+    let wallet_pub_key = string(variables, "walletPubKey")?;
+    let signed_auth_pub_key = string(variables, "signedAuthPubKey")?;
+
+    ensure_auth(
+        state.challenges.remove(&challenge),
+        "Challenge was not issued or already used",
+    )?;
+    ensure_auth(
+        verify_signature(
+            &add_bitcoin_message_prefix(&challenge),
+            &challenge_signature,
+            &auth_pub_key,
+        ),
+        "Invalid challenge signature",
+    )?;
+    ensure_auth(
+        verify_signature(&auth_pub_key, &signed_auth_pub_key, &wallet_pub_key),
+        "Invalid auth public key signature",
+    )?;
+
+    let id = wallet_pubkey_id(&wallet_pub_key);
+    state
+        .wallets
+        .insert(id.clone(), strip_hex_prefix(&wallet_pub_key).to_string());
+    let (access_token, refresh_token) = issue_session(
+        state,
+        Permit {
+            wallet_pubkey_id: id.clone(),
+            caller_wallet_pubkey_id: id.clone(),
+        },
+    );
+    Ok(json!({
+        "start_session_v2": {
+            "accessToken": access_token,
+            "refreshToken": refresh_token,
+            "walletPubKeyId": id,
+        }
+    }))
+}
+
+fn refresh_session(state: &mut State, variables: &Value) -> OperationResult {
+    let refresh_token = string(variables, "refreshToken")?;
+    let permit = state
+        .refresh_tokens
+        .remove(&refresh_token)
+        .ok_or_else(|| OperationError::new(AUTH_EXCEPTION_CODE, "Invalid refresh token"))?;
+    let (access_token, refresh_token) = issue_session(state, permit);
+    Ok(json!({
+        "refresh_session": {
+            "accessToken": access_token,
+            "refreshToken": refresh_token,
+        }
+    }))
+}
+
+fn prepare_wallet_session(state: &mut State, variables: &Value, permit: Permit) -> OperationResult {
+    let challenge = string(variables, "challenge")?;
+    let signed_challenge = string(variables, "signedChallenge")?;
+    let owner_wallet_pubkey_id = string(variables, "walletPubKeyId")?;
+
+    ensure_auth(
+        state.challenges.contains(&challenge),
+        "Challenge was not issued or already used",
+    )?;
+    ensure_auth(
+        verify_caller_signature(state, &permit, &challenge, &signed_challenge),
+        "Invalid challenge signature",
+    )?;
+    let now = SystemTime::now();
+    let is_owner = owner_wallet_pubkey_id == permit.caller_wallet_pubkey_id;
+    let is_employee = state.wallet_acl.iter().any(|acl| {
+        acl.owner_wallet_pubkey_id == owner_wallet_pubkey_id
+            && acl.member_wallet_pubkey_id == permit.caller_wallet_pubkey_id
+            && match acl.access_expires_at {
+                Some(expires_at) => now < expires_at,
+                None => true,
+            }
+    });
+    ensure_auth(
+        is_owner || is_employee,
+        "Not allowed to act on behalf of the wallet",
+    )?;
+
+    let prepared_permission_token = random_token();
+    state.prepared_sessions.insert(
+        prepared_permission_token.clone(),
+        PreparedSession {
+            permit: Permit {
+                wallet_pubkey_id: owner_wallet_pubkey_id,
+                caller_wallet_pubkey_id: permit.caller_wallet_pubkey_id,
+            },
+            challenge,
+        },
+    );
+    Ok(json!({ "prepare_wallet_session": prepared_permission_token }))
+}
+
+fn unlock_wallet(state: &mut State, variables: &Value, permit: Permit) -> OperationResult {
+    let challenge = string(variables, "challenge")?;
+    let challenge_signature = string(variables, "challengeSignature")?;
+    let prepared_permission_token = string(variables, "preparedPermissionToken")?;
+
+    let prepared_session = state
+        .prepared_sessions
+        .remove(&prepared_permission_token)
+        .ok_or_else(|| OperationError::new(AUTH_EXCEPTION_CODE, "Invalid permission token"))?;
+    ensure_auth(
+        prepared_session.challenge == challenge && state.challenges.remove(&challenge),
+        "Challenge was not issued or already used",
+    )?;
+    ensure_auth(
+        verify_caller_signature(state, &permit, &challenge, &challenge_signature),
+        "Invalid challenge signature",
+    )?;
+
+    let (access_token, refresh_token) = issue_session(state, prepared_session.permit);
+    Ok(json!({
+        "start_prepared_session": {
+            "accessToken": access_token,
+            "refreshToken": refresh_token,
+        }
+    }))
+}
+
+fn verify_caller_signature(
+    state: &State,
+    permit: &Permit,
+    challenge: &str,
+    signature: &str,
+) -> bool {
+    match state.wallets.get(&permit.caller_wallet_pubkey_id) {
+        Some(wallet_pub_key) => verify_signature(
+            &add_bitcoin_message_prefix(challenge),
+            signature,
+            wallet_pub_key,
+        ),
+        None => false,
+    }
+}
+
+fn accept_terms_and_conditions(
+    state: &mut State,
+    variables: &Value,
+    permit: Permit,
+) -> OperationResult {
+    let fingerprint = string(variables, "fingerprint")?;
+    let service = optional_string(variables, "service").unwrap_or("LIPA_WALLET".to_string());
+    let version = variables["version"]
+        .as_i64()
+        .ok_or_else(|| missing_variable("version"))?;
+
+    if fingerprint.len() != 64 || hex::decode(&fingerprint).is_err() {
+        return Err(OperationError::new(
+            INVALID_FINGERPRINT,
+            "The provided fingerprint is invalid",
+        ));
+    }
+
+    let accepted_at = SystemTime::now();
+    state.accepted_terms.insert(
+        (permit.wallet_pubkey_id, service.clone()),
+        (version, accepted_at),
+    );
+    Ok(json!({
+        "accept_terms_conditions_v2": {
+            "acceptDate": accepted_at.to_rfc3339(),
+            "accepted": true,
+            "service": service,
+            "version": version,
+        }
+    }))
+}
+
+fn get_terms_and_conditions_status(
+    state: &State,
+    variables: &Value,
+    permit: Permit,
+) -> OperationResult {
+    let service_provider = string(variables, "serviceProvider")?;
+    let accepted = state
+        .accepted_terms
+        .get(&(permit.wallet_pubkey_id, service_provider.clone()));
+    Ok(json!({
+        "get_terms_conditions_status": {
+            "serviceProvider": service_provider,
+            "acceptedTerms": accepted.is_some(),
+            "acceptDate": accepted.map(|(_, accepted_at)| accepted_at.to_rfc3339()),
+            "version": accepted.map(|(version, _)| *version).unwrap_or_default(),
+        }
+    }))
+}
+
+fn get_business_owner(state: &State, variables: &Value) -> OperationResult {
+    let member_wallet_pubkey_id = string(variables, "ownerWalletPubKeyId")?;
+    let wallet_acl: Vec<Value> = state
+        .wallet_acl
+        .iter()
+        .filter(|acl| acl.member_wallet_pubkey_id == member_wallet_pubkey_id)
+        .map(|acl| {
+            json!({
+                "accessExpiresAt": acl.access_expires_at.map(|e| e.to_rfc3339()),
+                "ownerWalletPubKeyId": acl.owner_wallet_pubkey_id,
+            })
+        })
+        .collect();
+    Ok(json!({ "wallet_acl": wallet_acl }))
+}
+
+fn get_exchange_rate(state: &State, variables: &Value) -> OperationResult {
+    let code = string(variables, "code")?;
+    let currency: Vec<Value> = state
+        .currencies
+        .get(&code)
+        .map(|c| json!({ "currencyCode": code, "satsPerUnit": c.sats_per_unit }))
+        .into_iter()
+        .collect();
+    Ok(json!({ "currency": currency }))
+}
+
+fn get_all_exchange_rates(state: &State) -> Value {
+    let currency: Vec<Value> = state
+        .currencies
+        .iter()
+        .map(|(code, c)| {
+            json!({
+                "currencyCode": code,
+                "satsPerUnit": c.sats_per_unit,
+                "conversionRateUpdatedAt": c.updated_at.to_rfc3339(),
+            })
+        })
+        .collect();
+    json!({ "currency": currency })
+}
+
+fn list_currency_codes(state: &State) -> Value {
+    let currency: Vec<Value> = state
+        .currencies
+        .keys()
+        .map(|code| json!({ "currencyCode": code }))
+        .collect();
+    json!({ "currency": currency })
+}
+
+/// How far a subscription got.
+#[derive(Default)]
+pub(crate) struct Cursor {
+    /// Of streaming subscriptions, the value of the cursor column of the last streamed row.
+    streamed_until: Option<SystemTime>,
+    /// Of live queries.
+    last_result: Option<Value>,
+}
+
+/// Executes a subscription.
+///
+/// Streaming subscriptions return the rows after the cursor, live queries
+/// return their result if it changed. Advances the cursor, `None` if there is nothing new.
+pub(crate) fn execute_stream(
+    state: &State,
+    operation: &str,
+    variables: &Value,
+    permit: &Permit,
+    cursor: &mut Cursor,
+) -> Result<Option<Value>, OperationError> {
+    match operation {
+        "CurrencyStream" => currency_stream(state, variables, &mut cursor.streamed_until),
+        "TopupStream" => topup_stream(state, variables, permit, &mut cursor.streamed_until),
+        "TopupUpdates" => Ok(live_query(
+            list_uncompleted_topups_of_wallet(state, permit),
+            &mut cursor.last_result,
+        )),
+        _ => Err(OperationError::new(
+            VALIDATION_FAILED_CODE,
+            &format!("Unknown subscription: {operation}"),
+        )),
+    }
+}
+
+fn currency_stream(
+    state: &State,
+    variables: &Value,
+    cursor: &mut Option<SystemTime>,
+) -> Result<Option<Value>, OperationError> {
+    let updated_after = match cursor {
+        Some(cursor) => *cursor,
+        None => timestamp(variables, "updatedAfter")?,
+    };
+    let batch_size = variables["batchSize"]
+        .as_u64()
+        .ok_or_else(|| missing_variable("batchSize"))?;
+    let mut currencies: Vec<_> = state
+        .currencies
+        .iter()
+        .filter(|(_, c)| c.updated_at > updated_after)
+        .collect();
+    currencies.sort_by_key(|(_, c)| c.updated_at);
+    currencies.truncate(batch_size as usize);
+    let last = match currencies.last() {
+        Some((_, c)) => c.updated_at,
+        None => return Ok(None),
+    };
+    *cursor = Some(last);
+    let currency_stream: Vec<Value> = currencies
+        .into_iter()
+        .map(|(code, c)| {
+            json!({
+                "currencyCode": code,
+                "satsPerUnit": c.sats_per_unit,
+                "conversionRateUpdatedAt": c.updated_at.to_rfc3339(),
+            })
+        })
+        .collect();
+    Ok(Some(json!({ "currency_stream": currency_stream })))
+}
+
+fn topup_stream(
+    state: &State,
+    variables: &Value,
+    permit: &Permit,
+    cursor: &mut Option<SystemTime>,
+) -> Result<Option<Value>, OperationError> {
+    let created_after = match cursor {
+        Some(cursor) => *cursor,
+        None => timestamp(variables, "createdAfter")?,
+    };
+    let batch_size = variables["batchSize"]
+        .as_u64()
+        .ok_or_else(|| missing_variable("batchSize"))?;
+    let mut topups: Vec<&Topup> = state
+        .topups
+        .iter()
+        .filter(|(wallet_pubkey_id, t)| {
+            *wallet_pubkey_id == permit.wallet_pubkey_id && t.created_at > created_after
+        })
+        .map(|(_, t)| t)
+        .collect();
+    topups.sort_by_key(|t| t.created_at);
+    topups.truncate(batch_size as usize);
+    let last = match topups.last() {
+        Some(t) => t.created_at,
+        None => return Ok(None),
+    };
+    *cursor = Some(last);
+    let topup_stream: Vec<Value> = topups.into_iter().map(Topup::to_json).collect();
+    Ok(Some(json!({ "topup_stream": topup_stream })))
+}
+
+/// Emits the current result of a live query if it differs from the last one emitted.
+fn live_query(result: Value, last_result: &mut Option<Value>) -> Option<Value> {
+    if last_result.as_ref() == Some(&result) {
+        return None;
+    }
+    *last_result = Some(result.clone());
+    Some(result)
+}
+
+fn list_uncompleted_topups_of_wallet(state: &State, permit: &Permit) -> Value {
+    let mut topups: Vec<&Topup> = state
+        .topups
+        .iter()
+        .filter(|(wallet_pubkey_id, t)| {
+            *wallet_pubkey_id == permit.wallet_pubkey_id
+                && UNCOMPLETED_TOPUP_STATUSES.contains(&t.status.as_str())
+        })
+        .map(|(_, t)| t)
+        .collect();
+    topups.sort_by(|a, b| (a.created_at, &a.id).cmp(&(b.created_at, &b.id)));
+    let topup: Vec<Value> = topups.into_iter().map(Topup::to_json).collect();
+    json!({ "topup": topup })
+}
+
+fn list_countries(state: &State) -> Value {
+    let country: Vec<Value> = state
+        .countries
+        .iter()
+        .map(|(code, c)| to_country_json(code, c))
+        .collect();
+    json!({ "country": country })
+}
+
+fn get_country(state: &State, variables: &Value) -> OperationResult {
+    let code = string(variables, "countryCode")?;
+    let country = state
+        .countries
+        .get(&code)
+        .map(|c| to_country_json(&code, c));
+    Ok(json!({ "country_by_pk": country }))
+}
+
+fn to_country_json(code: &str, country: &Country) -> Value {
+    json!({
+        "callingCode": country.calling_code,
+        "countryCode": code,
+        "currencyCode": country.currency_code,
+        "currencyName": country.currency_name,
+        "name": country.name,
+    })
+}
+
+fn register_topup(state: &State, variables: &Value, permit: Permit) -> OperationResult {
+    let order_id = string(variables, "orderId")?;
+    let email = optional_string(variables, "email");
+    let node_pub_key = state
+        .topup_setups
+        .get(&order_id)
+        .map(|s| s.node_pubkey.clone())
+        .or_else(|| {
+            state
+                .topups
+                .iter()
+                .find(|(_, t)| t.id == order_id)
+                .map(|(_, t)| t.node_pub_key.clone())
+        })
+        .ok_or_else(|| OperationError::new(NOT_FOUND_CODE, "Unknown order id"))?;
+    Ok(json!({
+        "register_topup": {
+            "walletPubKeyId": permit.wallet_pubkey_id,
+            "nodePubKey": node_pub_key,
+            "email": email,
+        }
+    }))
+}
+
+fn register_notification_token(
+    state: &mut State,
+    variables: &Value,
+    permit: Permit,
+) -> OperationResult {
+    let language = string(variables, "language")?;
+    let notification_token = string(variables, "notificationToken")?;
+    state
+        .notification_tokens
+        .push((permit.wallet_pubkey_id, notification_token, language));
+    Ok(json!({ "register_notification_token": { "id": random_uuid() } }))
+}
+
+fn hide_topup(state: &mut State, variables: &Value, permit: Permit) -> OperationResult {
+    let id = string(variables, "id")?;
+    let (_, topup) = state
+        .topups
+        .iter_mut()
+        .find(|(wallet_pubkey_id, t)| *wallet_pubkey_id == permit.wallet_pubkey_id && t.id == id)
+        .ok_or_else(|| OperationError::new(NOT_FOUND_CODE, "Unknown topup"))?;
+    topup.status = "REFUND_HIDDEN".to_string();
+    state.notify(Update::Changed);
+    Ok(json!({ "hide_topup": id }))
+}
+
+fn list_uncompleted_topups(state: &State, permit: Permit) -> Value {
+    let topup: Vec<Value> = state
+        .topups
+        .iter()
+        .filter(|(wallet_pubkey_id, t)| {
+            *wallet_pubkey_id == permit.wallet_pubkey_id
+                && UNCOMPLETED_TOPUP_STATUSES.contains(&t.status.as_str())
+        })
+        .map(|(_, t)| t.to_json())
+        .collect();
+    json!({ "topup": topup })
+}
+
+fn list_topups(state: &State, variables: &Value, permit: Permit) -> OperationResult {
+    let filter = &variables["where"];
+    let mut topups = Vec::new();
+    for (wallet_pubkey_id, topup) in &state.topups {
+        if *wallet_pubkey_id == permit.wallet_pubkey_id && topup_matches(topup, filter)? {
+            topups.push(topup);
+        }
+    }
+    let mut order_bys = Vec::new();
+    for order_by in variables["orderBy"].as_array().into_iter().flatten() {
+        let (column, direction) = order_by
+            .as_object()
+            .and_then(|o| o.iter().next())
+            .ok_or_else(|| missing_variable("orderBy"))?;
+        if column != "createdAt" && column != "id" {
+            return Err(unsupported(&format!("ordering by {column}")));
+        }
+        order_bys.push((column.as_str(), direction == "desc"));
+    }
+    topups.sort_by(|a, b| {
+        order_bys
+            .iter()
+            .map(|(column, descending)| {
+                let ordering = match *column {
+                    "createdAt" => a.created_at.cmp(&b.created_at),
+                    _ => a.id.cmp(&b.id),
+                };
+                if *descending {
+                    ordering.reverse()
+                } else {
+                    ordering
+                }
+            })
+            .fold(Ordering::Equal, Ordering::then)
+    });
+    let offset = variables["offset"].as_u64().unwrap_or(0) as usize;
+    let limit = variables["limit"]
+        .as_u64()
+        .map_or(usize::MAX, |l| l as usize);
+    let topup: Vec<Value> = topups
+        .into_iter()
+        .skip(offset)
+        .take(limit)
+        .map(Topup::to_json)
+        .collect();
+    Ok(json!({ "topup": topup }))
+}
+
+fn get_topup(state: &State, variables: &Value, permit: Permit) -> OperationResult {
+    let id = string(variables, "id")?;
+    let topup = state
+        .topups
+        .iter()
+        .find(|(wallet_pubkey_id, t)| *wallet_pubkey_id == permit.wallet_pubkey_id && t.id == id)
+        .map(|(_, t)| t.to_json());
+    Ok(json!({ "topup_by_pk": topup }))
+}
+
+/// Evaluates the subset of `topup_bool_exp` the client uses.
+fn topup_matches(topup: &Topup, filter: &Value) -> Result<bool, OperationError> {
+    let Some(filter) = filter.as_object() else {
+        return Err(missing_variable("where"));
+    };
+    for (column, comparison) in filter {
+        let matches = match (column.as_str(), comparison) {
+            ("status", Value::Object(c)) if c.len() == 1 && c.contains_key("_in") => c["_in"]
+                .as_array()
+                .is_some_and(|s| s.iter().any(|s| *s == topup.status)),
+            ("createdAt", Value::Object(c)) if c.keys().all(|k| k == "_gte" || k == "_lt") => {
+                let from = c
+                    .get("_gte")
+                    .map(|_| timestamp(comparison, "_gte"))
+                    .transpose()?;
+                let until = c
+                    .get("_lt")
+                    .map(|_| timestamp(comparison, "_lt"))
+                    .transpose()?;
+                let after_from = match from {
+                    Some(from) => from <= topup.created_at,
+                    None => true,
+                };
+                let before_until = match until {
+                    Some(until) => topup.created_at < until,
+                    None => true,
+                };
+                after_from && before_until
+            }
+            ("userCurrency", Value::Object(c)) if c.len() == 1 && c.contains_key("_eq") => {
+                c["_eq"].as_str() == Some(topup.user_currency.as_str())
+            }
+            _ => return Err(unsupported(&format!("filtering by {column}: {comparison}"))),
+        };
+        if !matches {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+fn create_backup(state: &mut State, variables: &Value, permit: Permit) -> OperationResult {
+    let encrypted_backup = string(variables, "encryptedBackup")?;
+    let schema_name = string(variables, "schemaName")?;
+    let schema_version = string(variables, "schemaVersion")?;
+    let updated_at = SystemTime::now();
+    state.backups.insert(
+        (permit.wallet_pubkey_id, schema_name),
+        Backup {
+            encrypted_backup,
+            schema_version,
+            updated_at,
+        },
+    );
+    Ok(json!({ "create_backup": { "updatedAt": updated_at.to_rfc3339() } }))
+}
+
+fn recover_backup(state: &State, variables: &Value, permit: Permit) -> OperationResult {
+    let schema_name = string(variables, "schemaName")?;
+    let backup = state
+        .backups
+        .get(&(permit.wallet_pubkey_id, schema_name))
+        .map(|b| {
+            json!({
+                "encryptedBackup": b.encrypted_backup,
+                "schemaVersion": b.schema_version,
+                "updatedAt": b.updated_at.to_rfc3339(),
+            })
+        });
+    Ok(json!({ "recover_backup": backup }))
+}
+
+fn report_payment_telemetry(state: &mut State, variables: &Value) -> Value {
+    state.telemetry_events.push(variables.clone());
+    json!({ "report_payment_telemetry": { "payFailed": null } })
+}
+
+fn assign_lightning_address(state: &mut State, permit: Permit) -> Value {
+    let existing = state
+        .lightning_addresses
+        .iter()
+        .find(|(_, a)| a.wallet_pubkey_id == permit.wallet_pubkey_id)
+        .map(|(address, a)| (address.clone(), a.assigned_at));
+    let (address, assigned_at) = existing.unwrap_or_else(|| {
+        let address = format!("{}@mock.lipa.swiss", &random_token()[..12]);
+        let assigned_at = SystemTime::now();
+        state.lightning_addresses.insert(
+            address.clone(),
+            LightningAddress {
+                wallet_pubkey_id: permit.wallet_pubkey_id,
+                assigned_at,
+                enabled: true,
+            },
+        );
+        (address, assigned_at)
+    });
+    json!({
+        "assign_lightning_address": {
+            "address": address,
+            "assignedAt": assigned_at.to_rfc3339(),
+        }
+    })
+}
+
+fn set_lightning_addresses_enabled(
+    state: &mut State,
+    variables: &Value,
+    permit: Permit,
+    enabled: bool,
+) -> Result<(), OperationError> {
+    let addresses = variables["addresses"]
+        .as_array()
+        .ok_or_else(|| missing_variable("addresses"))?;
+    for address in addresses {
+        let address = state
+            .lightning_addresses
+            .get_mut(address.as_str().unwrap_or_default())
+            .filter(|a| a.wallet_pubkey_id == permit.wallet_pubkey_id)
+            .ok_or_else(|| OperationError::new(NOT_FOUND_CODE, "Unknown lightning address"))?;
+        address.enabled = enabled;
+    }
+    Ok(())
+}
+
+fn request_phone_number_verification(
+    state: &mut State,
+    variables: &Value,
+    permit: Permit,
+) -> OperationResult {
+    let number = string(variables, "number")?;
+    let encrypted_number = string(variables, "encryptedNumber")?;
+    state
+        .pending_phone_numbers
+        .insert(permit.wallet_pubkey_id, (number, encrypted_number));
+    Ok(json!({ "request_phone_number_verification": null }))
+}
+
+fn verify_phone_number(state: &mut State, variables: &Value, permit: Permit) -> OperationResult {
+    let number = string(variables, "number")?;
+    let otp = string(variables, "otp")?;
+    let is_pending = state
+        .pending_phone_numbers
+        .get(&permit.wallet_pubkey_id)
+        .is_some_and(|(pending_number, _)| *pending_number == number);
+    if !is_pending || otp != PHONE_VERIFICATION_OTP {
+        return Err(OperationError::new(
+            VALIDATION_FAILED_CODE,
+            "Invalid phone number or one time password",
+        ));
+    }
+    if let Some((_, encrypted_number)) =
+        state.pending_phone_numbers.remove(&permit.wallet_pubkey_id)
+    {
+        state
+            .verified_phone_numbers
+            .insert(permit.wallet_pubkey_id, encrypted_number);
+    }
+    Ok(json!({ "verify_phone_number": null }))
+}
+
+fn verified_phone_number(state: &State, permit: Permit) -> Value {
+    let number = state
+        .verified_phone_numbers
+        .get(&permit.wallet_pubkey_id)
+        .map(|n| json!({ "encryptedPhoneNumber": n }));
+    json!({ "verified_phone_number": number })
+}
+
+fn start_topup_setup(state: &mut State, variables: &Value, permit: Permit) -> OperationResult {
+    let node_pubkey = string(variables, "node_pubkey")?;
+    let source_iban = string(variables, "source_iban")?;
+    let user_currency = string(variables, "user_currency")?;
+    string(variables, "provider")?;
+
+    let id = random_uuid();
+    let challenge = random_token();
+    state.topup_setups.insert(
+        id.clone(),
+        TopupSetup {
+            wallet_pubkey_id: permit.wallet_pubkey_id,
+            node_pubkey,
+            source_iban,
+            user_currency,
+            challenge: challenge.clone(),
+        },
+    );
+    Ok(json!({ "start_topup_setup": { "id": id, "challenge": challenge } }))
+}
+
+fn complete_topup_setup(state: &State, variables: &Value, permit: Permit) -> OperationResult {
+    let id = string(variables, "id")?;
+    let signed_challenge = string(variables, "signedChallenge")?;
+    let source_iban = string(variables, "sourceIban")?;
+
+    let setup = state
+        .topup_setups
+        .get(&id)
+        .filter(|s| s.wallet_pubkey_id == permit.wallet_pubkey_id)
+        .ok_or_else(|| OperationError::new(NOT_FOUND_CODE, "Unknown topup setup"))?;
+    if setup.source_iban != source_iban {
+        return Err(OperationError::new(
+            VALIDATION_FAILED_CODE,
+            "Source IBAN does not match",
+        ));
+    }
+    ensure_auth(
+        verify_signature(
+            &add_bitcoin_message_prefix(&setup.challenge),
+            &signed_challenge,
+            &setup.node_pubkey,
+        ),
+        "Invalid challenge signature",
+    )?;
+
+    // Swiss francs are paid with a QR-bill (QR-IBAN and QR reference),
+    // euros with a SEPA transfer (IBAN and creditor reference).
+    let (creditor_iban, creditor_reference) = if setup.user_currency.eq_ignore_ascii_case("chf") {
+        ("CH4431999123000889012", "210000000003139471430009017")
+    } else {
+        ("CH5604835012345678009", "RF18539007547034")
+    };
+    Ok(json!({
+        "complete_topup_setup": {
+            "id": id,
+            "debitorIban": setup.source_iban,
+            "creditorReference": creditor_reference,
+            "creditorIban": creditor_iban,
+            "creditorBankName": "Mock Bank AG",
+            "creditorBankStreet": "Bahnhofstrasse 1",
+            "creditorBankPostalCode": "8001",
+            "creditorBankTown": "Zürich",
+            "creditorBankCountry": "CH",
+            "creditorBankBic": "MOCKCHZZXXX",
+            "creditorName": "Mock Exchange AG",
+            "creditorStreet": "Musterstrasse 42",
+            "creditorPostalCode": "8000",
+            "creditorTown": "Zürich",
+            "creditorCountry": "CH",
+            "currency": setup.user_currency.to_uppercase(),
+        }
+    }))
+}
+
+fn ensure_auth(condition: bool, message: &str) -> Result<(), OperationError> {
+    if condition {
+        Ok(())
+    } else {
+        Err(OperationError::new(AUTH_EXCEPTION_CODE, message))
+    }
+}
+
+fn string(variables: &Value, name: &str) -> Result<String, OperationError> {
+    optional_string(variables, name).ok_or_else(|| missing_variable(name))
+}
+
+fn optional_string(variables: &Value, name: &str) -> Option<String> {
+    variables[name].as_str().map(String::from)
+}
+
+fn timestamp(variables: &Value, name: &str) -> Result<SystemTime, OperationError> {
+    parse_from_rfc3339(&string(variables, name)?).map_err(|_| missing_variable(name))
+}
+
+fn unsupported(feature: &str) -> OperationError {
+    OperationError::new(
+        VALIDATION_FAILED_CODE,
+        &format!("The mock backend does not support {feature}"),
+    )
+}
+
+fn missing_variable(name: &str) -> OperationError {
+    OperationError::new(
+        VALIDATION_FAILED_CODE,
+        &format!("Missing or invalid variable: {name}"),
+    )
+}
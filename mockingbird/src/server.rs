@@ -0,0 +1,145 @@
+use crate::operations::{execute, OperationError};
+use crate::state::State;
+use crate::subscriptions::{is_websocket_upgrade, upgrade};
+use crate::Failure;
+use hyper::header::{AUTHORIZATION, CONTENT_TYPE};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use log::debug;
+use serde_json::{json, Value};
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread::JoinHandle;
+use tokio::net::TcpStream;
+use tokio::sync::oneshot;
+
+pub(crate) fn spawn(
+    state: Arc<Mutex<State>>,
+    shutdown: oneshot::Receiver<()>,
+) -> (SocketAddr, JoinHandle<()>) {
+    let (address_sender, address_receiver) = mpsc::channel();
+    let thread = std::thread::spawn(move || {
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(2)
+            .enable_all()
+            .build()
+            .expect("Failed to build a runtime for the mock backend");
+        runtime.block_on(async move {
+            let make_service = make_service_fn(move |_| {
+                let state = Arc::clone(&state);
+                async move {
+                    Ok::<_, Infallible>(service_fn(move |request| {
+                        serve(Arc::clone(&state), request)
+                    }))
+                }
+            });
+            let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_service);
+            address_sender
+                .send(server.local_addr())
+                .expect("Failed to report the mock backend address");
+            tokio::select! {
+                _ = server => {}
+                _ = shutdown => {}
+            }
+        });
+        // Do not wait for idle keep-alive connections of clients.
+        runtime.shutdown_background();
+    });
+    let address = address_receiver
+        .recv()
+        .expect("Failed to start the mock backend");
+    (address, thread)
+}
+
+async fn serve(
+    state: Arc<Mutex<State>>,
+    request: Request<Body>,
+) -> Result<Response<Body>, Infallible> {
+    if request.method() == Method::CONNECT {
+        return Ok(tunnel(state, request));
+    }
+    if is_websocket_upgrade(&request) {
+        return Ok(upgrade(state, request));
+    }
+    let access_token = request
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .map(String::from);
+    let request: Value = match hyper::body::to_bytes(request.into_body())
+        .await
+        .ok()
+        .and_then(|body| serde_json::from_slice(&body).ok())
+    {
+        Some(request) => request,
+        None => return Ok(status_response(StatusCode::BAD_REQUEST)),
+    };
+    let operation = request["operationName"].as_str().unwrap_or_default();
+    let variables = &request["variables"];
+    debug!("Mock backend received {operation}: {variables}");
+
+    let mut state = state.lock().unwrap();
+    *state
+        .request_counts
+        .entry(operation.to_string())
+        .or_default() += 1;
+    let body = match state.take_failure(operation) {
+        Some(Failure::BadGateway) => return Ok(status_response(StatusCode::BAD_GATEWAY)),
+        Some(Failure::ErrorCode(code)) => {
+            error_body(&OperationError::new(&code, "Scripted failure"))
+        }
+        None => match execute(&mut state, operation, variables, access_token.as_deref()) {
+            Ok(data) => json!({ "data": data }),
+            Err(e) => error_body(&e),
+        },
+    };
+
+    Ok(Response::builder()
+        .header(CONTENT_TYPE, "application/json")
+        .body(Body::from(body.to_string()))
+        .unwrap())
+}
+
+/// Acts as an HTTP proxy by tunneling the connection to the requested address.
+fn tunnel(state: Arc<Mutex<State>>, mut request: Request<Body>) -> Response<Body> {
+    let address = match request.uri().authority() {
+        Some(authority) => authority.to_string(),
+        None => return status_response(StatusCode::BAD_REQUEST),
+    };
+    *state
+        .lock()
+        .unwrap()
+        .request_counts
+        .entry(Method::CONNECT.to_string())
+        .or_default() += 1;
+    tokio::spawn(async move {
+        let tunnel = async {
+            let mut upgraded = hyper::upgrade::on(&mut request).await?;
+            let mut target = TcpStream::connect(&address).await?;
+            tokio::io::copy_bidirectional(&mut upgraded, &mut target).await?;
+            Ok::<_, Box<dyn std::error::Error + Send + Sync>>(())
+        };
+        if let Err(e) = tunnel.await {
+            debug!("Mock backend failed to tunnel to {address}: {e}");
+        }
+    });
+    Response::new(Body::empty())
+}
+
+fn error_body(error: &OperationError) -> Value {
+    json!({
+        "errors": [{
+            "message": error.message,
+            "extensions": { "code": error.code },
+        }]
+    })
+}
+
+fn status_response(status: StatusCode) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .body(Body::from(status.to_string()))
+        .unwrap()
+}
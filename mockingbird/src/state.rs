@@ -0,0 +1,249 @@
+use crate::Failure;
+use graphql::ToRfc3339;
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::time::{Duration, SystemTime};
+use tokio::sync::broadcast;
+
+/// A topup as stored in the `topup` table of the backend.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Topup {
+    pub id: String,
+    /// One of `READY`, `FAILED`, `REFUNDED`, `REFUND_HIDDEN` or `SETTLED`.
+    pub status: String,
+    pub additional_info: Option<String>,
+    pub amount_sat: u64,
+    pub amount_user_currency: f64,
+    pub created_at: SystemTime,
+    pub exchange_fee_rate: f64,
+    pub exchange_fee_user_currency: f64,
+    pub exchange_rate: f64,
+    pub expires_at: Option<SystemTime>,
+    pub lightning_fee_user_currency: f64,
+    pub lnurl: Option<String>,
+    pub node_pub_key: String,
+    pub user_currency: String,
+}
+
+impl Topup {
+    /// A topup of 8 EUR with the given id and status.
+    pub fn new(id: &str, status: &str) -> Self {
+        Self {
+            id: id.to_string(),
+            status: status.to_string(),
+            additional_info: None,
+            amount_sat: 42578,
+            amount_user_currency: 8.0,
+            created_at: SystemTime::now(),
+            exchange_fee_rate: 0.015,
+            exchange_fee_user_currency: 0.12,
+            exchange_rate: 18507.0,
+            expires_at: Some(SystemTime::now() + Duration::from_secs(60 * 60 * 24 * 60)),
+            lightning_fee_user_currency: 0.0,
+            lnurl: None,
+            node_pub_key: "0233786a3f5c79d25508ed973e7a37506ddab49d41a07fcb3d341ab638000d69cf"
+                .to_string(),
+            user_currency: "eur".to_string(),
+        }
+    }
+
+    pub(crate) fn to_json(&self) -> Value {
+        json!({
+            "additionalInfo": self.additional_info,
+            "amountSat": self.amount_sat,
+            "amountUserCurrency": self.amount_user_currency,
+            "createdAt": self.created_at.to_rfc3339(),
+            "exchangeFeeRate": self.exchange_fee_rate,
+            "exchangeFeeUserCurrency": self.exchange_fee_user_currency,
+            "exchangeRate": self.exchange_rate,
+            "expiresAt": self.expires_at.map(|e| e.to_rfc3339()),
+            "id": self.id,
+            "lightningFeeUserCurrency": self.lightning_fee_user_currency,
+            "lnurl": self.lnurl,
+            "nodePubKey": self.node_pub_key,
+            "status": self.status,
+            "userCurrency": self.user_currency,
+        })
+    }
+}
+
+pub(crate) struct Currency {
+    pub sats_per_unit: u32,
+    pub updated_at: SystemTime,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Update {
+    /// Data subscriptions may stream changed.
+    Changed,
+    /// Subscriptions have to drop their connection, simulating network loss.
+    Disconnect,
+}
+
+/// What a session permits.
+#[derive(Clone)]
+pub(crate) struct Permit {
+    /// The wallet the session acts on behalf of.
+    pub wallet_pubkey_id: String,
+    /// The wallet which authenticated, differs for employees.
+    pub caller_wallet_pubkey_id: String,
+}
+
+pub(crate) struct AccessToken {
+    pub permit: Permit,
+    pub expires_at: SystemTime,
+}
+
+pub(crate) struct PreparedSession {
+    pub permit: Permit,
+    pub challenge: String,
+}
+
+pub(crate) struct WalletAcl {
+    pub owner_wallet_pubkey_id: String,
+    pub member_wallet_pubkey_id: String,
+    pub access_expires_at: Option<SystemTime>,
+}
+
+pub(crate) struct Backup {
+    pub encrypted_backup: String,
+    pub schema_version: String,
+    pub updated_at: SystemTime,
+}
+
+pub(crate) struct LightningAddress {
+    pub wallet_pubkey_id: String,
+    pub assigned_at: SystemTime,
+    pub enabled: bool,
+}
+
+pub(crate) struct Country {
+    pub calling_code: String,
+    pub currency_code: String,
+    pub currency_name: String,
+    pub name: String,
+}
+
+pub(crate) struct TopupSetup {
+    pub wallet_pubkey_id: String,
+    pub node_pubkey: String,
+    pub source_iban: String,
+    pub user_currency: String,
+    pub challenge: String,
+}
+
+pub(crate) struct State {
+    pub failures: HashMap<String, VecDeque<Failure>>,
+    pub permanent_failure: Option<Failure>,
+    pub request_counts: HashMap<String, usize>,
+    /// Headers of the last websocket upgrade request.
+    pub subscription_headers: Vec<(String, String)>,
+
+    pub access_token_lifetime: Duration,
+    pub challenges: HashSet<String>,
+    /// Wallet public keys by wallet public key id.
+    pub wallets: HashMap<String, String>,
+    pub access_tokens: HashMap<String, AccessToken>,
+    pub refresh_tokens: HashMap<String, Permit>,
+    pub prepared_sessions: HashMap<String, PreparedSession>,
+    pub wallet_acl: Vec<WalletAcl>,
+    /// Accepted version and date by wallet and service.
+    pub accepted_terms: HashMap<(String, String), (i64, SystemTime)>,
+
+    pub currencies: BTreeMap<String, Currency>,
+    pub countries: BTreeMap<String, Country>,
+    pub topups: Vec<(String, Topup)>,
+    pub topup_setups: HashMap<String, TopupSetup>,
+    pub notification_tokens: Vec<(String, String, String)>,
+    pub backups: HashMap<(String, String), Backup>,
+    pub telemetry_events: Vec<Value>,
+    pub lightning_addresses: BTreeMap<String, LightningAddress>,
+    /// Pending phone number verifications by wallet: (number, encrypted number).
+    pub pending_phone_numbers: HashMap<String, (String, String)>,
+    pub verified_phone_numbers: HashMap<String, String>,
+    /// Notifies subscriptions about changes.
+    pub updates: broadcast::Sender<Update>,
+}
+
+impl State {
+    pub fn new() -> Self {
+        let mut state = Self {
+            failures: HashMap::new(),
+            permanent_failure: None,
+            request_counts: HashMap::new(),
+            subscription_headers: Vec::new(),
+            access_token_lifetime: Duration::from_secs(10 * 60),
+            challenges: HashSet::new(),
+            wallets: HashMap::new(),
+            access_tokens: HashMap::new(),
+            refresh_tokens: HashMap::new(),
+            prepared_sessions: HashMap::new(),
+            wallet_acl: Vec::new(),
+            accepted_terms: HashMap::new(),
+            currencies: BTreeMap::new(),
+            countries: BTreeMap::new(),
+            topups: Vec::new(),
+            topup_setups: HashMap::new(),
+            notification_tokens: Vec::new(),
+            backups: HashMap::new(),
+            telemetry_events: Vec::new(),
+            lightning_addresses: BTreeMap::new(),
+            pending_phone_numbers: HashMap::new(),
+            verified_phone_numbers: HashMap::new(),
+            updates: broadcast::channel(16).0,
+        };
+        let now = SystemTime::now();
+        state.set_exchange_rate("CHF", 1_493, now);
+        state.set_exchange_rate("EUR", 1_538, now);
+        state.set_exchange_rate("GBP", 1_312, now);
+        state.set_exchange_rate("USD", 1_652, now);
+        state.add_country("AT", "Austria", "43", "EUR", "Euro");
+        state.add_country("CH", "Switzerland", "41", "CHF", "Swiss Franc");
+        state.add_country("DE", "Germany", "49", "EUR", "Euro");
+        state.add_country("GB", "United Kingdom", "44", "GBP", "Pound Sterling");
+        state.add_country("US", "United States", "1", "USD", "US Dollar");
+        state
+    }
+
+    fn add_country(
+        &mut self,
+        country_code: &str,
+        name: &str,
+        calling_code: &str,
+        currency_code: &str,
+        currency_name: &str,
+    ) {
+        self.countries.insert(
+            country_code.to_string(),
+            Country {
+                calling_code: calling_code.to_string(),
+                currency_code: currency_code.to_string(),
+                currency_name: currency_name.to_string(),
+                name: name.to_string(),
+            },
+        );
+    }
+
+    pub fn set_exchange_rate(&mut self, code: &str, sats_per_unit: u32, updated_at: SystemTime) {
+        self.currencies.insert(
+            code.to_string(),
+            Currency {
+                sats_per_unit,
+                updated_at,
+            },
+        );
+        self.notify(Update::Changed);
+    }
+
+    pub fn notify(&self, update: Update) {
+        // Nobody is subscribed if sending fails.
+        let _ = self.updates.send(update);
+    }
+
+    pub fn take_failure(&mut self, operation: &str) -> Option<Failure> {
+        if let Some(failure) = self.permanent_failure.clone() {
+            return Some(failure);
+        }
+        self.failures.get_mut(operation)?.pop_front()
+    }
+}
@@ -0,0 +1,214 @@
+use crate::operations::{authorize, execute_stream, Cursor, OperationError};
+use crate::state::{State, Update};
+use crate::Failure;
+use futures_util::{SinkExt, StreamExt};
+use hyper::header::{HeaderValue, UPGRADE};
+use hyper::header::{CONNECTION, SEC_WEBSOCKET_ACCEPT, SEC_WEBSOCKET_KEY, SEC_WEBSOCKET_PROTOCOL};
+use hyper::upgrade::Upgraded;
+use hyper::{Body, Request, Response, StatusCode};
+use log::debug;
+use serde_json::{json, Value};
+use std::sync::{Arc, Mutex};
+use tokio::sync::broadcast;
+use tokio_tungstenite::tungstenite::handshake::derive_accept_key;
+use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+use tokio_tungstenite::tungstenite::protocol::{CloseFrame, Role};
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::WebSocketStream;
+
+const PROTOCOL: &str = "graphql-transport-ws";
+const FORBIDDEN_CLOSE_CODE: u16 = 4403;
+const BAD_REQUEST_CLOSE_CODE: u16 = 4400;
+const GOING_AWAY_CLOSE_CODE: u16 = 1001;
+
+type Socket = WebSocketStream<Upgraded>;
+
+pub(crate) fn is_websocket_upgrade(request: &Request<Body>) -> bool {
+    request
+        .headers()
+        .get(UPGRADE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.eq_ignore_ascii_case("websocket"))
+}
+
+/// Accepts the websocket and serves subscriptions over it using the graphql-transport-ws protocol.
+pub(crate) fn upgrade(state: Arc<Mutex<State>>, mut request: Request<Body>) -> Response<Body> {
+    let accept_key = match request.headers().get(SEC_WEBSOCKET_KEY) {
+        Some(key) => derive_accept_key(key.as_bytes()),
+        None => {
+            return Response::builder()
+                .status(StatusCode::BAD_REQUEST)
+                .body(Body::empty())
+                .unwrap()
+        }
+    };
+    state.lock().unwrap().subscription_headers = request
+        .headers()
+        .iter()
+        .filter_map(|(name, value)| Some((name.to_string(), value.to_str().ok()?.to_string())))
+        .collect();
+    tokio::spawn(async move {
+        match hyper::upgrade::on(&mut request).await {
+            Ok(upgraded) => {
+                let socket = WebSocketStream::from_raw_socket(upgraded, Role::Server, None).await;
+                serve(state, socket).await;
+            }
+            Err(e) => debug!("Mock backend failed to upgrade a connection: {e}"),
+        }
+    });
+    Response::builder()
+        .status(StatusCode::SWITCHING_PROTOCOLS)
+        .header(UPGRADE, HeaderValue::from_static("websocket"))
+        .header(CONNECTION, HeaderValue::from_static("Upgrade"))
+        .header(SEC_WEBSOCKET_ACCEPT, accept_key)
+        .header(SEC_WEBSOCKET_PROTOCOL, HeaderValue::from_static(PROTOCOL))
+        .body(Body::empty())
+        .unwrap()
+}
+
+async fn serve(state: Arc<Mutex<State>>, mut socket: Socket) {
+    let init = match receive(&mut socket).await {
+        Some(message) if message["type"] == "connection_init" => message,
+        _ => return close(socket, BAD_REQUEST_CLOSE_CODE, "Expected connection_init").await,
+    };
+    let access_token = init["payload"]["headers"]["Authorization"]
+        .as_str()
+        .and_then(|v| v.strip_prefix("Bearer "));
+    let permit = authorize(&state.lock().unwrap(), access_token);
+    let permit = match permit {
+        Ok(permit) => permit,
+        Err(e) => return close(socket, FORBIDDEN_CLOSE_CODE, &e.message).await,
+    };
+    if send(&mut socket, json!({ "type": "connection_ack" }))
+        .await
+        .is_err()
+    {
+        return;
+    }
+
+    let subscribe = match receive(&mut socket).await {
+        Some(message) if message["type"] == "subscribe" => message,
+        _ => return close(socket, BAD_REQUEST_CLOSE_CODE, "Expected subscribe").await,
+    };
+    let id = subscribe["id"].clone();
+    let operation = subscribe["payload"]["operationName"]
+        .as_str()
+        .unwrap_or_default()
+        .to_string();
+    let variables = &subscribe["payload"]["variables"];
+    debug!("Mock backend received subscription {operation}: {variables}");
+
+    let (failure, mut updates) = {
+        let mut state = state.lock().unwrap();
+        *state.request_counts.entry(operation.clone()).or_default() += 1;
+        (state.take_failure(&operation), state.updates.subscribe())
+    };
+    if let Some(failure) = failure {
+        let code = match failure {
+            Failure::BadGateway => "unexpected".to_string(),
+            Failure::ErrorCode(code) => code,
+        };
+        let error = OperationError::new(&code, "Scripted failure");
+        let _ = send(&mut socket, error_message(&id, &error)).await;
+        return;
+    }
+
+    let mut cursor = Cursor::default();
+    loop {
+        let batch = {
+            let state = state.lock().unwrap();
+            // Checked under the lock, so changes after a disconnect are not streamed anymore.
+            if disconnect_requested(&mut updates) {
+                None
+            } else {
+                Some(execute_stream(
+                    &state,
+                    &operation,
+                    variables,
+                    &permit,
+                    &mut cursor,
+                ))
+            }
+        };
+        let Some(batch) = batch else {
+            return close(socket, GOING_AWAY_CLOSE_CODE, "Disconnected").await;
+        };
+        match batch {
+            Ok(Some(data)) => {
+                let message = json!({ "id": id, "type": "next", "payload": { "data": data } });
+                if send(&mut socket, message).await.is_err() {
+                    return;
+                }
+                // There may be more rows than fit into a batch.
+                continue;
+            }
+            Ok(None) => {}
+            Err(e) => {
+                let _ = send(&mut socket, error_message(&id, &e)).await;
+                return;
+            }
+        }
+        tokio::select! {
+            update = updates.recv() => {
+                if update == Ok(Update::Disconnect) {
+                    return close(socket, GOING_AWAY_CLOSE_CODE, "Disconnected").await;
+                }
+            }
+            message = receive(&mut socket) => match message {
+                Some(message) if message["type"] == "ping" => {
+                    if send(&mut socket, json!({ "type": "pong" })).await.is_err() {
+                        return;
+                    }
+                }
+                Some(message) if message["type"] == "complete" => return,
+                Some(_) => {}
+                None => return,
+            },
+        }
+    }
+}
+
+/// Consumes the pending updates.
+fn disconnect_requested(updates: &mut broadcast::Receiver<Update>) -> bool {
+    let mut disconnect = false;
+    while let Ok(update) = updates.try_recv() {
+        disconnect |= update == Update::Disconnect;
+    }
+    disconnect
+}
+
+async fn receive(socket: &mut Socket) -> Option<Value> {
+    loop {
+        match socket.next().await? {
+            Ok(Message::Text(text)) => return serde_json::from_str(&text).ok(),
+            Ok(Message::Close(_)) | Err(_) => return None,
+            Ok(_) => {}
+        }
+    }
+}
+
+async fn send(socket: &mut Socket, message: Value) -> Result<(), ()> {
+    socket
+        .send(Message::Text(message.to_string()))
+        .await
+        .map_err(|_| ())
+}
+
+async fn close(mut socket: Socket, code: u16, reason: &str) {
+    let frame = CloseFrame {
+        code: CloseCode::from(code),
+        reason: reason.to_string().into(),
+    };
+    let _ = socket.close(Some(frame)).await;
+}
+
+fn error_message(id: &Value, error: &OperationError) -> Value {
+    json!({
+        "id": id,
+        "type": "error",
+        "payload": [{
+            "message": error.message,
+            "extensions": { "code": error.code },
+        }],
+    })
+}
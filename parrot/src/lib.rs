@@ -1,9 +1,10 @@
+use graphql::asynchronous::BackendClient;
 use graphql::schema::report_payment_telemetry::{
     PayFailedInput, PayInitiatedInput, PaySource, PaySucceededInput, PaymentTelemetryEventsInput,
     RequestInitiatedInput, RequestSucceededInput,
 };
 use graphql::schema::{report_payment_telemetry, ReportPaymentTelemetry};
-use graphql::{build_async_client, post, ToRfc3339};
+use graphql::ToRfc3339;
 use honeybadger::asynchronous::Auth;
 use std::sync::Arc;
 use std::time::SystemTime;
@@ -67,17 +68,25 @@ pub enum AnalyticsEvent {
 }
 
 pub struct AnalyticsClient {
-    backend_url: String,
+    client: BackendClient,
     analytics_id: String,
-    auth: Arc<Auth>,
 }
 
 impl AnalyticsClient {
+    #[deprecated(
+        note = "Use AnalyticsClient::with_client, which shares the client with the other crates"
+    )]
     pub fn new(backend_url: String, analytics_id: String, auth: Arc<Auth>) -> Self {
+        Self::with_client(
+            BackendClient::with_default_config(backend_url).with_auth(auth),
+            analytics_id,
+        )
+    }
+
+    pub fn with_client(client: BackendClient, analytics_id: String) -> Self {
         Self {
-            backend_url,
+            client,
             analytics_id,
-            auth,
         }
     }
 
@@ -193,9 +202,9 @@ impl AnalyticsClient {
             },
         };
 
-        let token = self.auth.query_token().await?;
-        let client = build_async_client(Some(&token))?;
-        post::<ReportPaymentTelemetry>(&client, &self.backend_url, variables).await?;
+        self.client
+            .post::<ReportPaymentTelemetry>(variables)
+            .await?;
 
         Ok(())
     }
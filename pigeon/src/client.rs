@@ -0,0 +1,85 @@
+use graphql::asynchronous::BackendClient;
+use graphql::perro::OptionToError;
+use graphql::schema::{
+    assign_lightning_address, disable_lightning_addresses, enable_lightning_addresses,
+    request_phone_number_verification, submit_lnurl_pay_invoice, verified_phone_number,
+    verify_phone_number, AssignLightningAddress, DisableLightningAddresses,
+    EnableLightningAddresses, RequestPhoneNumberVerification, SubmitLnurlPayInvoice,
+    VerifiedPhoneNumber, VerifyPhoneNumber,
+};
+
+pub async fn assign_lightning_address(client: &BackendClient) -> graphql::Result<String> {
+    let data = client
+        .post::<AssignLightningAddress>(assign_lightning_address::Variables {})
+        .await?;
+    let address = data
+        .assign_lightning_address
+        .ok_or_permanent_failure("Unexpected backend response: empty")?
+        .address;
+    Ok(address)
+}
+
+pub async fn submit_lnurl_pay_invoice(
+    client: &BackendClient,
+    id: String,
+    invoice: Option<String>,
+) -> graphql::Result<()> {
+    let _data = client
+        .post::<SubmitLnurlPayInvoice>(submit_lnurl_pay_invoice::Variables { id, invoice })
+        .await?;
+    Ok(())
+}
+
+pub async fn request_phone_number_verification(
+    client: &BackendClient,
+    number: String,
+    encrypted_number: String,
+) -> graphql::Result<()> {
+    let _data = client
+        .post::<RequestPhoneNumberVerification>(request_phone_number_verification::Variables {
+            encrypted_number,
+            number,
+        })
+        .await?;
+    Ok(())
+}
+
+pub async fn verify_phone_number(
+    client: &BackendClient,
+    number: String,
+    otp: String,
+) -> graphql::Result<()> {
+    let _data = client
+        .post::<VerifyPhoneNumber>(verify_phone_number::Variables { number, otp })
+        .await?;
+    Ok(())
+}
+
+pub async fn query_verified_phone_number(
+    client: &BackendClient,
+) -> graphql::Result<Option<String>> {
+    let data = client
+        .post::<VerifiedPhoneNumber>(verified_phone_number::Variables {})
+        .await?;
+    Ok(data.verified_phone_number.map(|n| n.encrypted_phone_number))
+}
+
+pub async fn disable_lightning_addresses(
+    client: &BackendClient,
+    addresses: Vec<String>,
+) -> graphql::Result<()> {
+    client
+        .post::<DisableLightningAddresses>(disable_lightning_addresses::Variables { addresses })
+        .await?;
+    Ok(())
+}
+
+pub async fn enable_lightning_addresses(
+    client: &BackendClient,
+    addresses: Vec<String>,
+) -> graphql::Result<()> {
+    client
+        .post::<EnableLightningAddresses>(enable_lightning_addresses::Variables { addresses })
+        .await?;
+    Ok(())
+}
@@ -1,124 +1,84 @@
-use graphql::perro::OptionToError;
-use graphql::schema::{
-    assign_lightning_address, disable_lightning_addresses, enable_lightning_addresses,
-    request_phone_number_verification, submit_lnurl_pay_invoice, verified_phone_number,
-    verify_phone_number, AssignLightningAddress, DisableLightningAddresses,
-    EnableLightningAddresses, RequestPhoneNumberVerification, SubmitLnurlPayInvoice,
-    VerifiedPhoneNumber, VerifyPhoneNumber,
-};
-use graphql::{build_async_client, post};
+pub mod client;
+
+use graphql::asynchronous::BackendClient;
+use graphql::BackendClientConfig;
 use honeybadger::asynchronous::Auth;
 
+#[deprecated(note = "Use client::assign_lightning_address, which shares the client")]
 pub async fn assign_lightning_address(backend_url: &str, auth: &Auth) -> graphql::Result<String> {
-    let token = auth.query_token().await?;
-    let client = build_async_client(Some(&token))?;
-    let data = post::<AssignLightningAddress>(
-        &client,
-        backend_url,
-        assign_lightning_address::Variables {},
-    )
-    .await?;
-    let address = data
-        .assign_lightning_address
-        .ok_or_permanent_failure("Unexpected backend response: empty")?
-        .address;
-    Ok(address)
+    let client = build_client(backend_url, auth).await?;
+    client::assign_lightning_address(&client).await
 }
 
+#[deprecated(note = "Use client::submit_lnurl_pay_invoice, which shares the client")]
 pub async fn submit_lnurl_pay_invoice(
     backend_url: &str,
     auth: &Auth,
     id: String,
     invoice: Option<String>,
 ) -> graphql::Result<()> {
-    let token = auth.query_token().await?;
-    let client = build_async_client(Some(&token))?;
-    let _data = post::<SubmitLnurlPayInvoice>(
-        &client,
-        backend_url,
-        submit_lnurl_pay_invoice::Variables { id, invoice },
-    )
-    .await?;
-    Ok(())
+    let client = build_client(backend_url, auth).await?;
+    client::submit_lnurl_pay_invoice(&client, id, invoice).await
 }
 
+#[deprecated(note = "Use client::request_phone_number_verification, which shares the client")]
 pub async fn request_phone_number_verification(
     backend_url: &str,
     auth: &Auth,
     number: String,
     encrypted_number: String,
 ) -> graphql::Result<()> {
-    let token = auth.query_token().await?;
-    let client = build_async_client(Some(&token))?;
-    let _data = post::<RequestPhoneNumberVerification>(
-        &client,
-        backend_url,
-        request_phone_number_verification::Variables {
-            encrypted_number,
-            number,
-        },
-    )
-    .await?;
-    Ok(())
+    let client = build_client(backend_url, auth).await?;
+    client::request_phone_number_verification(&client, number, encrypted_number).await
 }
 
+#[deprecated(note = "Use client::verify_phone_number, which shares the client")]
 pub async fn verify_phone_number(
     backend_url: &str,
     auth: &Auth,
     number: String,
     otp: String,
 ) -> graphql::Result<()> {
-    let token = auth.query_token().await?;
-    let client = build_async_client(Some(&token))?;
-    let _data = post::<VerifyPhoneNumber>(
-        &client,
-        backend_url,
-        verify_phone_number::Variables { number, otp },
-    )
-    .await?;
-    Ok(())
+    let client = build_client(backend_url, auth).await?;
+    client::verify_phone_number(&client, number, otp).await
 }
 
+#[deprecated(note = "Use client::query_verified_phone_number, which shares the client")]
 pub async fn query_verified_phone_number(
     backend_url: &str,
     auth: &Auth,
 ) -> graphql::Result<Option<String>> {
-    let token = auth.query_token().await?;
-    let client = build_async_client(Some(&token))?;
-    let data =
-        post::<VerifiedPhoneNumber>(&client, backend_url, verified_phone_number::Variables {})
-            .await?;
-    Ok(data.verified_phone_number.map(|n| n.encrypted_phone_number))
+    let client = build_client(backend_url, auth).await?;
+    client::query_verified_phone_number(&client).await
 }
 
+#[deprecated(note = "Use client::disable_lightning_addresses, which shares the client")]
 pub async fn disable_lightning_addresses(
     backend_url: &str,
     auth: &Auth,
     addresses: Vec<String>,
 ) -> graphql::Result<()> {
-    let token = auth.query_token().await?;
-    let client = build_async_client(Some(&token))?;
-    post::<DisableLightningAddresses>(
-        &client,
-        backend_url,
-        disable_lightning_addresses::Variables { addresses },
-    )
-    .await?;
-    Ok(())
+    let client = build_client(backend_url, auth).await?;
+    client::disable_lightning_addresses(&client, addresses).await
 }
 
+#[deprecated(note = "Use client::enable_lightning_addresses, which shares the client")]
 pub async fn enable_lightning_addresses(
     backend_url: &str,
     auth: &Auth,
     addresses: Vec<String>,
 ) -> graphql::Result<()> {
+    let client = build_client(backend_url, auth).await?;
+    client::enable_lightning_addresses(&client, addresses).await
+}
+
+/// Builds a client sending the current access token of the `Auth`, as the functions
+/// taking a url did before the client could be shared.
+async fn build_client(backend_url: &str, auth: &Auth) -> graphql::Result<BackendClient> {
     let token = auth.query_token().await?;
-    let client = build_async_client(Some(&token))?;
-    post::<EnableLightningAddresses>(
-        &client,
-        backend_url,
-        enable_lightning_addresses::Variables { addresses },
-    )
-    .await?;
-    Ok(())
+    let config = BackendClientConfig {
+        default_headers: vec![("Authorization".to_string(), format!("Bearer {token}"))],
+        ..BackendClientConfig::default()
+    };
+    BackendClient::new(backend_url.to_string(), config)
 }
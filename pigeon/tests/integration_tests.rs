@@ -1,14 +1,15 @@
 use bitcoin::Network;
+use graphql::asynchronous::BackendClient;
+use graphql::BackendClientConfig;
 use honeybadger::asynchronous::Auth;
-use honeybadger::secrets::{derive_keys, generate_keypair, generate_mnemonic};
+use honeybadger::secrets::{derive_keys, generate_keypair, generate_mnemonic, DerivationScheme};
 use honeybadger::AuthLevel;
-use pigeon::{
+use pigeon::client::{
     assign_lightning_address, disable_lightning_addresses, enable_lightning_addresses,
     submit_lnurl_pay_invoice,
 };
 use simplelog::TestLogger;
-use std::env;
-use std::sync::Once;
+use std::sync::{Arc, Once};
 
 static INIT_LOGGER_ONCE: Once = Once::new();
 
@@ -22,57 +23,54 @@ fn init() {
 
 #[tokio::test]
 async fn test_assigning_lightning_address() {
-    let (backend_url, auth) = build_client();
-    let address = assign_lightning_address(&backend_url, &auth).await.unwrap();
+    let client = build_client();
+    let address = assign_lightning_address(&client).await.unwrap();
     println!("Assigned address is: {address}");
     assert!(!address.is_empty());
-    let address_from_another_call = assign_lightning_address(&backend_url, &auth).await.unwrap();
+    let address_from_another_call = assign_lightning_address(&client).await.unwrap();
     assert_eq!(address, address_from_another_call);
 
-    let (backend_url, another_auth) = build_client();
-    let address_for_another_user = assign_lightning_address(&backend_url, &another_auth)
-        .await
-        .unwrap();
+    let another_client = build_client();
+    let address_for_another_user = assign_lightning_address(&another_client).await.unwrap();
     assert_ne!(address, address_for_another_user);
 }
 
 #[tokio::test]
 async fn test_disable_enable_lightning_addresses() {
-    let (backend_url, auth) = build_client();
-    let address = assign_lightning_address(&backend_url, &auth).await.unwrap();
+    let client = build_client();
+    let address = assign_lightning_address(&client).await.unwrap();
     println!("Assigned address is: {address}");
-    disable_lightning_addresses(&backend_url, &auth, vec![address.clone()])
+    disable_lightning_addresses(&client, vec![address.clone()])
         .await
         .unwrap();
     // Disabling again.
-    disable_lightning_addresses(&backend_url, &auth, vec![address.clone()])
+    disable_lightning_addresses(&client, vec![address.clone()])
         .await
         .unwrap();
 
-    enable_lightning_addresses(&backend_url, &auth, vec![address.clone()])
+    enable_lightning_addresses(&client, vec![address.clone()])
         .await
         .unwrap();
     // Enabling again.
-    enable_lightning_addresses(&backend_url, &auth, vec![address.clone()])
+    enable_lightning_addresses(&client, vec![address.clone()])
         .await
         .unwrap();
 
-    let (backend_url, another_auth) = build_client();
+    let another_client = build_client();
     // Disabling/enabling an address of another user.
-    disable_lightning_addresses(&backend_url, &another_auth, vec![address.clone()])
+    disable_lightning_addresses(&another_client, vec![address.clone()])
         .await
         .unwrap_err();
-    enable_lightning_addresses(&backend_url, &another_auth, vec![address.clone()])
+    enable_lightning_addresses(&another_client, vec![address.clone()])
         .await
         .unwrap_err();
 }
 
 #[tokio::test]
 async fn test_submit_lnurl_pay_invoice() {
-    let (backend_url, auth) = build_client();
+    let client = build_client();
     submit_lnurl_pay_invoice(
-        &backend_url,
-        &auth,
+        &client,
         "5fab1a65-3486-4dfd-bba8-dad2c1a1b98e".to_string(),
         Some("invoice".to_string()),
     )
@@ -80,8 +78,7 @@ async fn test_submit_lnurl_pay_invoice() {
     .unwrap();
 
     submit_lnurl_pay_invoice(
-        &backend_url,
-        &auth,
+        &client,
         "44872a5a-8be9-4a27-a80f-2ec66ff1f5b6".to_string(),
         None,
     )
@@ -89,24 +86,50 @@ async fn test_submit_lnurl_pay_invoice() {
     .unwrap();
 }
 
-fn build_client() -> (String, Auth) {
+#[tokio::test]
+#[allow(deprecated)]
+async fn test_deprecated_functions() {
+    let auth = Auth::new(
+        get_backend_url(),
+        AuthLevel::Pseudonymous,
+        generate_keypair().unwrap(),
+        generate_keypair().unwrap(),
+    )
+    .unwrap();
+    let address = pigeon::assign_lightning_address(&get_backend_url(), &auth)
+        .await
+        .unwrap();
+    let address_from_another_call = pigeon::assign_lightning_address(&get_backend_url(), &auth)
+        .await
+        .unwrap();
+    assert_eq!(address, address_from_another_call);
+}
+
+fn build_client() -> BackendClient {
     println!("Generating keys ...");
-    let mnemonic = generate_mnemonic();
+    let mnemonic = generate_mnemonic(24).unwrap();
     println!("mnemonic: {mnemonic:?}");
-    let wallet_keys = derive_keys(Network::Testnet, mnemonic).wallet_keypair;
-    let auth_keys = generate_keypair();
+    let wallet_keys = derive_keys(
+        Network::Testnet,
+        mnemonic,
+        None,
+        &DerivationScheme::LegacyMasterKey,
+    )
+    .unwrap()
+    .wallet_keypair;
+    let auth_keys = generate_keypair().unwrap();
 
-    let auth = Auth::new(
-        get_backend_url(),
+    let client = BackendClient::new(get_backend_url(), BackendClientConfig::default()).unwrap();
+    let auth = Auth::with_client(
+        client.clone(),
         AuthLevel::Pseudonymous,
         wallet_keys,
         auth_keys,
-    )
-    .unwrap();
+    );
 
-    (get_backend_url(), auth)
+    client.with_auth(Arc::new(auth))
 }
 
 fn get_backend_url() -> String {
-    env::var("GRAPHQL_API_URL").expect("GRAPHQL_API_URL environment variable is not set")
+    mockingbird::backend_url()
 }
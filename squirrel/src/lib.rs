@@ -1,7 +1,7 @@
+use graphql::asynchronous::BackendClient;
 use graphql::errors::*;
 use graphql::perro::{MapToError, OptionToError};
 use graphql::schema::*;
-use graphql::{build_async_client, post};
 use honeybadger::asynchronous::Auth;
 use std::sync::Arc;
 
@@ -13,35 +13,37 @@ pub struct Backup {
 }
 
 pub struct RemoteBackupClient {
-    backend_url: String,
-    auth: Arc<Auth>,
+    client: BackendClient,
 }
 
 impl RemoteBackupClient {
+    #[deprecated(
+        note = "Use RemoteBackupClient::with_client, which shares the client with the other crates"
+    )]
     pub fn new(backend_url: String, auth: Arc<Auth>) -> Self {
-        Self { backend_url, auth }
+        Self::with_client(BackendClient::with_default_config(backend_url).with_auth(auth))
+    }
+
+    pub fn with_client(client: BackendClient) -> Self {
+        Self { client }
     }
 
     pub async fn create_backup(&self, backup: &Backup) -> Result<()> {
-        let token = self.auth.query_token().await?;
-        let client = build_async_client(Some(&token))?;
         let variables = create_backup::Variables {
             encrypted_backup: graphql_hex_encode(&backup.encrypted_backup),
             schema_name: backup.schema_name.clone(),
             schema_version: backup.schema_version.clone(),
         };
-        post::<CreateBackup>(&client, &self.backend_url, variables).await?;
+        self.client.post::<CreateBackup>(variables).await?;
 
         Ok(())
     }
 
     pub async fn recover_backup(&self, schema_name: &str) -> Result<Backup> {
-        let token = self.auth.query_token().await?;
-        let client = build_async_client(Some(&token))?;
         let variables = recover_backup::Variables {
             schema_name: schema_name.to_string(),
         };
-        let data = post::<RecoverBackup>(&client, &self.backend_url, variables).await?;
+        let data = self.client.post::<RecoverBackup>(variables).await?;
 
         let d = data.recover_backup.ok_or_runtime_error(
             GraphQlRuntimeErrorCode::ObjectNotFound,
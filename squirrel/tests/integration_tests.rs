@@ -1,13 +1,14 @@
 use bdk::bitcoin::Network;
+use graphql::asynchronous::BackendClient;
 use graphql::perro::Error::RuntimeError;
-use graphql::GraphQlRuntimeErrorCode;
+use graphql::{BackendClientConfig, GraphQlRuntimeErrorCode};
 use honeybadger::asynchronous::Auth;
-use honeybadger::secrets::{derive_keys, generate_keypair, generate_mnemonic};
+use honeybadger::secrets::{derive_keys, generate_keypair, generate_mnemonic, DerivationScheme};
 use honeybadger::AuthLevel;
+use mockingbird::{Failure, MockBackend};
 use rand::random;
 use simplelog::TestLogger;
 use squirrel::{Backup, RemoteBackupClient};
-use std::env;
 use std::sync::{Arc, Once};
 
 static INIT_LOGGER_ONCE: Once = Once::new();
@@ -109,24 +110,59 @@ async fn test_backup_persistence() {
     ));
 }
 
+#[tokio::test]
+async fn test_reauthentication_on_rejected_token() {
+    let backend = MockBackend::start();
+    let client = build_backup_client_for(backend.url());
+    let backup = Backup {
+        encrypted_backup: random::<[u8; 32]>().to_vec(),
+        schema_name: "a".to_string(),
+        schema_version: "1".to_string(),
+    };
+    client.create_backup(&backup).await.unwrap();
+
+    backend.revoke_access_tokens();
+    assert_eq!(client.recover_backup("a").await.unwrap(), backup);
+    assert_eq!(backend.request_count("RefreshSession"), 1);
+    assert_eq!(backend.request_count("RecoverBackup"), 2);
+
+    backend.fail_next(
+        "CreateBackup",
+        Failure::ErrorCode("invalid-jwt".to_string()),
+    );
+    client.create_backup(&backup).await.unwrap();
+    assert_eq!(backend.request_count("RefreshSession"), 2);
+    assert_eq!(backend.request_count("CreateBackup"), 3);
+}
+
 fn build_backup_client() -> RemoteBackupClient {
+    build_backup_client_for(get_backend_url())
+}
+
+fn build_backup_client_for(backend_url: String) -> RemoteBackupClient {
     println!("Generating keys ...");
-    let mnemonic = generate_mnemonic();
+    let mnemonic = generate_mnemonic(24).unwrap();
     println!("mnemonic: {mnemonic:?}");
-    let wallet_keys = derive_keys(Network::Testnet, mnemonic).wallet_keypair;
-    let auth_keys = generate_keypair();
+    let wallet_keys = derive_keys(
+        Network::Testnet,
+        mnemonic,
+        None,
+        &DerivationScheme::LegacyMasterKey,
+    )
+    .unwrap()
+    .wallet_keypair;
+    let auth_keys = generate_keypair().unwrap();
 
-    let auth = Auth::new(
-        get_backend_url(),
+    let client = BackendClient::new(backend_url, BackendClientConfig::default()).unwrap();
+    let auth = Auth::with_client(
+        client.clone(),
         AuthLevel::Pseudonymous,
         wallet_keys,
         auth_keys,
-    )
-    .unwrap();
-
-    RemoteBackupClient::new(get_backend_url(), Arc::new(auth))
+    );
+    RemoteBackupClient::with_client(client.with_auth(Arc::new(auth)))
 }
 
 fn get_backend_url() -> String {
-    env::var("GRAPHQL_API_URL").expect("GRAPHQL_API_URL environment variable is not set")
+    mockingbird::backend_url()
 }